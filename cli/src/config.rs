@@ -2,6 +2,7 @@ use std::{
     env,
     ffi::OsStr,
     fs,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
 };
 
@@ -11,6 +12,10 @@ use regex::Regex;
 use serde::{Deserialize, Serialize};
 
 use crate::constants::CLI_NAME;
+use crate::opt::{
+    EnvoluntaryShellExportArgs, ProfileHashArgs, parse_duration, parse_hash_scope,
+    parse_input_gcroots_dir,
+};
 
 pub fn print_path() -> anyhow::Result<()> {
     println!("{}", get_config_path(None)?.display());
@@ -70,6 +75,8 @@ pub fn print_matching_entries(
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct EnvoluntaryConfig {
     entries: Option<Vec<ConfigEntry>>,
+    rc_rewrites: Option<Vec<RcRewrite>>,
+    options: Option<ExportOptions>,
 }
 
 impl EnvoluntaryConfig {
@@ -97,6 +104,38 @@ impl EnvoluntaryConfig {
         Ok(())
     }
 
+    pub fn rc_rewrites(&self) -> &[RcRewrite] {
+        self.rc_rewrites.as_deref().unwrap_or(&[])
+    }
+
+    /// Fills in the `shell export` options not given on the command line or in env vars from the
+    /// config's `[options]`.
+    pub fn apply_options(&self, args: &mut EnvoluntaryShellExportArgs) -> anyhow::Result<()> {
+        match &self.options {
+            Some(options) => options.apply(args),
+            None => Ok(()),
+        }
+    }
+
+    /// Fills in the options changing a cached profile's hash from the config's `[options]`, for
+    /// commands locating a profile rather than exporting it.
+    pub fn apply_profile_hash_options(&self, args: &mut ProfileHashArgs) -> anyhow::Result<()> {
+        match &self.options {
+            Some(options) => options.apply_profile_hash(args),
+            None => Ok(()),
+        }
+    }
+
+    /// The `impure` setting of the first entry for `flake_reference`, if any.
+    pub fn impure_for(&self, flake_reference: &str) -> Option<bool> {
+        self.entries
+            .as_deref()
+            .unwrap_or(&[])
+            .iter()
+            .find(|entry| entry.config.flake_reference == flake_reference)
+            .and_then(|entry| entry.config.impure)
+    }
+
     pub fn matching_entries(&self, path: &Path) -> anyhow::Result<Vec<ConfigEntry>> {
         let absolute_path = if path.is_absolute() {
             path.to_path_buf()
@@ -197,6 +236,115 @@ pub struct Config {
     pub impure: Option<bool>,
 }
 
+/// Defaults for `shell export` options, so they needn't all be passed on every call.
+///
+/// Options given on the command line or in env vars take precedence, though a flag can only turn
+/// an option on, not off.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExportOptions {
+    cache_ttl: Option<String>,
+    jobs: Option<NonZeroUsize>,
+    max_input_gcroots: Option<usize>,
+    cache_min_bytes: Option<u64>,
+    input_gcroots_dir: Option<String>,
+    hash_scope: Option<String>,
+    exclude_env_vars: Option<Vec<String>>,
+    print_dev_env_args: Option<Vec<String>>,
+    ready_marker: Option<PathBuf>,
+    on_ready: Option<String>,
+    no_input_gcroots: Option<bool>,
+    verify_gcroots: Option<bool>,
+    local_store_fallback: Option<bool>,
+    alias_unchanged_profiles: Option<bool>,
+    compress_cache: Option<bool>,
+    normalize_rc: Option<bool>,
+    fingerprint_env: Option<bool>,
+    progress: Option<bool>,
+}
+
+impl ExportOptions {
+    fn apply(&self, args: &mut EnvoluntaryShellExportArgs) -> anyhow::Result<()> {
+        if args.cache_ttl.is_none()
+            && let Some(cache_ttl) = &self.cache_ttl
+        {
+            args.cache_ttl = Some(
+                parse_duration(cache_ttl)
+                    .map_err(|e| anyhow::format_err!("Invalid `cache_ttl` in config: {e}"))?,
+            );
+        }
+        if args.input_gcroots_dir.is_none()
+            && let Some(input_gcroots_dir) = &self.input_gcroots_dir
+        {
+            args.input_gcroots_dir =
+                Some(parse_input_gcroots_dir(input_gcroots_dir).map_err(|e| {
+                    anyhow::format_err!("Invalid `input_gcroots_dir` in config: {e}")
+                })?);
+        }
+        self.apply_profile_hash(&mut args.profile_hash)?;
+        args.jobs = args.jobs.or(self.jobs);
+        args.max_input_gcroots = args.max_input_gcroots.or(self.max_input_gcroots);
+        args.cache_min_bytes = args.cache_min_bytes.or(self.cache_min_bytes);
+        args.ready_marker = args.ready_marker.take().or(self.ready_marker.clone());
+        args.on_ready = args.on_ready.take().or(self.on_ready.clone());
+        for (arg, option) in [
+            (&mut args.no_input_gcroots, self.no_input_gcroots),
+            (&mut args.verify_gcroots, self.verify_gcroots),
+            (&mut args.local_store_fallback, self.local_store_fallback),
+            (
+                &mut args.alias_unchanged_profiles,
+                self.alias_unchanged_profiles,
+            ),
+            (&mut args.compress_cache, self.compress_cache),
+            (&mut args.fingerprint_env, self.fingerprint_env),
+            (&mut args.progress, self.progress),
+        ] {
+            *arg |= option.unwrap_or_default();
+        }
+        Ok(())
+    }
+
+    fn apply_profile_hash(&self, args: &mut ProfileHashArgs) -> anyhow::Result<()> {
+        if args.hash_scope.is_none()
+            && let Some(hash_scope) = &self.hash_scope
+        {
+            args.hash_scope = Some(
+                parse_hash_scope(hash_scope)
+                    .map_err(|e| anyhow::format_err!("Invalid `hash_scope` in config: {e}"))?,
+            );
+        }
+        if args.exclude_env_vars.is_empty()
+            && let Some(exclude_env_vars) = &self.exclude_env_vars
+        {
+            args.exclude_env_vars = exclude_env_vars.clone();
+        }
+        if args.print_dev_env_args.is_empty()
+            && let Some(print_dev_env_args) = &self.print_dev_env_args
+        {
+            args.print_dev_env_args = print_dev_env_args.iter().map(Into::into).collect();
+        }
+        args.normalize_rc |= self.normalize_rc.unwrap_or_default();
+        Ok(())
+    }
+}
+
+/// A rewrite of a cached profile's rc, applied before it's sourced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RcRewrite {
+    #[serde(with = "serde_regex")]
+    pub pattern: Regex,
+    pub replacement: String,
+}
+
+/// Applies each rewrite in order, replacing every match of its pattern.
+pub fn apply_rc_rewrites(rc: &str, rc_rewrites: &[RcRewrite]) -> String {
+    rc_rewrites.iter().fold(String::from(rc), |rc, rc_rewrite| {
+        rc_rewrite
+            .pattern
+            .replace_all(&rc, rc_rewrite.replacement.as_str())
+            .into_owned()
+    })
+}
+
 pub fn get_config_path(provided_config_path: Option<&Path>) -> anyhow::Result<PathBuf> {
     if let Some(config_path) = provided_config_path {
         return Ok(PathBuf::from(config_path));
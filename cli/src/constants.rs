@@ -1 +1,4 @@
 pub const CLI_NAME: &str = "envoluntary";
+
+/// Salts cached profile hashes, so upgrading invalidates profiles cached by an older version.
+pub const CACHE_SALT: &str = concat!("envoluntary-", env!("CARGO_PKG_VERSION"));
@@ -0,0 +1,90 @@
+use std::{env, fs, path::Path, process};
+
+use duct::cmd;
+use nix_dev_env::{check_flake_metadata, check_nix_version, ensure_writable_dir};
+
+use crate::config::{EnvoluntaryConfig, get_cache_dir, get_config_path};
+use crate::constants::CLI_NAME;
+use crate::opt::EnvoluntaryDoctorArgs;
+
+const TRIVIAL_FLAKE: &str = "{ outputs = _: { }; }\n";
+
+pub fn print_doctor(args: EnvoluntaryDoctorArgs) -> anyhow::Result<()> {
+    let checks = run_checks(args.config_path.as_deref(), args.cache_dir.as_deref());
+
+    let mut failed_checks = 0;
+    for (name, result) in &checks {
+        match result {
+            Ok(()) => println!("ok    {name}"),
+            Err(e) => {
+                failed_checks += 1;
+                println!("FAIL  {name}: {e:#}");
+                println!("      hint: {}", remediation_hint(name));
+            }
+        }
+    }
+
+    if failed_checks > 0 {
+        return Err(anyhow::format_err!(
+            "{failed_checks} of {} checks failed.",
+            checks.len()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Runs every setup check in order, keeping going after failures so all problems are reported.
+pub fn run_checks(
+    config_path: Option<&Path>,
+    cache_dir: Option<&Path>,
+) -> Vec<(&'static str, anyhow::Result<()>)> {
+    vec![
+        ("bash", check_bash()),
+        ("nix version", check_nix_version()),
+        ("config", check_config(config_path)),
+        ("cache dir", check_cache_dir(cache_dir)),
+        ("nix flake metadata", check_trivial_flake()),
+    ]
+}
+
+fn remediation_hint(name: &str) -> &'static str {
+    match name {
+        "bash" => "install bash and make sure it's on $PATH; it's used to source Nix profiles",
+        "nix version" => "install Nix 2.10.0 or newer and make sure `nix` is on $PATH",
+        "config" => "fix the config file, e.g. with `envoluntary config edit`",
+        "cache dir" => "make the cache dir writable or choose another with `--cache-dir`",
+        "nix flake metadata" => {
+            "check that flakes work, e.g. by running `nix flake metadata` in a flake directory"
+        }
+        _ => "",
+    }
+}
+
+fn check_bash() -> anyhow::Result<()> {
+    cmd!("bash", "-c", "true")
+        .run()
+        .map_err(|e| anyhow::format_err!("Couldn't run bash: {e}"))?;
+    Ok(())
+}
+
+fn check_config(config_path: Option<&Path>) -> anyhow::Result<()> {
+    let config_path = get_config_path(config_path)?;
+    EnvoluntaryConfig::load(&config_path)?;
+    Ok(())
+}
+
+fn check_cache_dir(cache_dir: Option<&Path>) -> anyhow::Result<()> {
+    ensure_writable_dir(&get_cache_dir(cache_dir)?)
+}
+
+fn check_trivial_flake() -> anyhow::Result<()> {
+    let flake_dir = env::temp_dir().join(format!("{CLI_NAME}-doctor-flake.{}", process::id()));
+    fs::create_dir_all(&flake_dir)?;
+    fs::write(flake_dir.join("flake.nix"), TRIVIAL_FLAKE)?;
+    let result = check_flake_metadata(&format!("path:{}", flake_dir.display()));
+    // NB: Cleaning up is best-effort, so it doesn't hide the check's result
+    let _ = fs::remove_dir_all(&flake_dir);
+    result?;
+    Ok(())
+}
@@ -0,0 +1,36 @@
+use std::{error::Error, fmt, process::ExitCode};
+
+use nix_dev_env::NixCommandError;
+
+/// Any error without a more specific exit code.
+pub const EXIT_FAILURE: u8 = 1;
+/// Invalid command line arguments, as reported by `clap`.
+pub const EXIT_USAGE: u8 = 2;
+/// A failed preflight check, e.g. `nix` being too old for flakes.
+pub const EXIT_PREFLIGHT: u8 = 3;
+/// A `nix` command that couldn't be run or failed, e.g. evaluating a flake.
+pub const EXIT_NIX_FAILURE: u8 = 4;
+
+/// Marks an error as a failed preflight check, exiting with [`EXIT_PREFLIGHT`].
+#[derive(Debug)]
+pub struct PreflightError(pub anyhow::Error);
+
+impl fmt::Display for PreflightError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl Error for PreflightError {}
+
+/// The exit code for an error, by the most specific kind of error in its chain.
+pub fn exit_code(error: &anyhow::Error) -> ExitCode {
+    let exit_code = if error.chain().any(|e| e.is::<PreflightError>()) {
+        EXIT_PREFLIGHT
+    } else if error.chain().any(|e| e.is::<NixCommandError>()) {
+        EXIT_NIX_FAILURE
+    } else {
+        EXIT_FAILURE
+    };
+    ExitCode::from(exit_code)
+}
@@ -0,0 +1,24 @@
+use nix_dev_env::NixCommandError;
+
+use crate::constants::CLI_NAME;
+use crate::opt::EnvoluntaryGcrootArgs;
+
+pub fn add_gcroot(args: EnvoluntaryGcrootArgs) -> anyhow::Result<()> {
+    if let Err(e) = nix_dev_env::add_gcroot(&args.store_path, &args.symlink) {
+        // NB: The error only says `nix` failed, while its stderr says why
+        if let Some(stderr) = e
+            .downcast_ref::<NixCommandError>()
+            .and_then(NixCommandError::stderr)
+            .filter(|stderr| !stderr.is_empty())
+        {
+            eprint!("{stderr}");
+        }
+        return Err(e);
+    }
+    eprintln!(
+        "{CLI_NAME}: created gcroot {} for {}",
+        args.symlink.display(),
+        args.store_path.display()
+    );
+    Ok(())
+}
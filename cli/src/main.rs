@@ -1,17 +1,47 @@
 mod config;
 mod constants;
+mod doctor;
+mod exit_code;
+mod gcroot;
 mod opt;
 mod shell;
 
+use std::{
+    fs,
+    io::{self, Write},
+    process::ExitCode,
+};
+
 use clap::Parser;
 
+use crate::exit_code::{EXIT_USAGE, PreflightError, exit_code};
 use crate::opt::{
     Envoluntary, EnvoluntaryCommands, EnvoluntaryConfigCommands, EnvoluntaryShellCommands,
 };
 
-fn main() -> anyhow::Result<()> {
-    let opt = Envoluntary::parse();
+fn main() -> ExitCode {
+    let opt = match Envoluntary::try_parse() {
+        Ok(opt) => opt,
+        Err(e) => {
+            let _ = e.print();
+            return if e.use_stderr() {
+                ExitCode::from(EXIT_USAGE)
+            } else {
+                ExitCode::SUCCESS
+            };
+        }
+    };
+
+    match run(opt) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            exit_code(&e)
+        }
+    }
+}
 
+fn run(opt: Envoluntary) -> anyhow::Result<()> {
     match opt.command {
         EnvoluntaryCommands::Config { config } => match config {
             EnvoluntaryConfigCommands::PrintPath => {
@@ -35,19 +65,52 @@ fn main() -> anyhow::Result<()> {
         },
         EnvoluntaryCommands::Shell { shell } => match shell {
             EnvoluntaryShellCommands::CheckNixVersion => {
-                nix_dev_env::check_nix_version()?;
+                nix_dev_env::check_nix_version().map_err(PreflightError)?;
             }
             EnvoluntaryShellCommands::Hook(args) => {
                 shell::print_hook(args.shell)?;
             }
             EnvoluntaryShellCommands::Export(args) => {
-                shell::print_export(args)?;
+                if let Some(output_path) = args.output.clone() {
+                    // NB: Buffered, so a failed export doesn't leave an empty file to be sourced
+                    let mut output = Vec::new();
+                    shell::print_export(*args, &mut output)?;
+                    fs::write(output_path, output)?;
+                } else {
+                    let mut output = output_writer(args.output_fd)?;
+                    shell::print_export(*args, &mut output)?;
+                    output.flush()?;
+                }
             }
             EnvoluntaryShellCommands::PrintCachePath(args) => {
                 shell::print_cache_path(args)?;
             }
+            EnvoluntaryShellCommands::PrintProfilePath(args) => {
+                shell::print_profile_path(args)?;
+            }
+            EnvoluntaryShellCommands::DiffProfiles(args) => {
+                shell::print_profiles_diff(args)?;
+            }
         },
+        EnvoluntaryCommands::Doctor(args) => {
+            doctor::print_doctor(args)?;
+        }
+        EnvoluntaryCommands::Gcroot(args) => {
+            gcroot::add_gcroot(args)?;
+        }
     };
 
     Ok(())
 }
+
+fn output_writer(output_fd: Option<u32>) -> anyhow::Result<Box<dyn Write>> {
+    if let Some(fd) = output_fd {
+        let fd_path = format!("/dev/fd/{fd}");
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(&fd_path)
+            .map_err(|e| anyhow::format_err!("Couldn't open file descriptor {fd}: {e}"))?;
+        return Ok(Box::new(file));
+    }
+    Ok(Box::new(io::stdout()))
+}
@@ -1,6 +1,11 @@
-use std::{ffi::OsString, path::PathBuf};
+use std::{env, ffi::OsString, num::NonZeroUsize, path::PathBuf, time::Duration};
 
-use clap::{Args, Parser, Subcommand, ValueEnum};
+use clap::{
+    Args, Parser, Subcommand, ValueEnum,
+    builder::{OsStringValueParser, TypedValueParser},
+};
+use nix_dev_env::{HashScope, InputGcrootsDir};
+use path_clean::PathClean;
 
 /// A Nix flake-based development environment manager for automatic shell integration.
 ///
@@ -32,6 +37,42 @@ pub enum EnvoluntaryCommands {
         #[command(subcommand)]
         shell: EnvoluntaryShellCommands,
     },
+    /// Check that Envoluntary is set up correctly.
+    ///
+    /// Runs a series of checks (bash, Nix version, configuration, cache directory, and a trial
+    /// flake evaluation) and prints whether each passed, with a hint on how to fix failures.
+    Doctor(EnvoluntaryDoctorArgs),
+    /// Protect a Nix store path from garbage collection.
+    ///
+    /// Creates a garbage collection root the same way cached profiles and flake inputs are
+    /// rooted, with `nix build --out-link`.
+    Gcroot(EnvoluntaryGcrootArgs),
+}
+
+/// Arguments for the `gcroot` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryGcrootArgs {
+    /// The Nix store path to protect, e.g. `/nix/store/<hash>-<name>`.
+    pub store_path: PathBuf,
+
+    /// Where to create the garbage collection root symlink.
+    pub symlink: PathBuf,
+}
+
+/// Arguments for the `doctor` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryDoctorArgs {
+    /// Path to the configuration file to check (overrides default location).
+    ///
+    /// If not provided, uses the default configuration path.
+    #[arg(long, env = "ENVOLUNTARY_CONFIG_PATH")]
+    pub config_path: Option<PathBuf>,
+
+    /// Directory for caching Nix profiles to check (overrides default cache location).
+    ///
+    /// If not provided, uses `$XDG_CACHE_HOME/envoluntary` (or `~/.cache/envoluntary` if not set).
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR", value_parser = cache_dir_parser())]
+    pub cache_dir: Option<PathBuf>,
 }
 
 /// Configuration management subcommands.
@@ -152,13 +193,26 @@ pub enum EnvoluntaryShellCommands {
     ///
     /// Generates shell commands to export environment variables based on matching
     /// configuration entries for the current directory. This is called by the shell hook.
-    Export(EnvoluntaryShellExportArgs),
+    Export(Box<EnvoluntaryShellExportArgs>),
 
     /// Print the cache path for a given Nix flake reference.
     ///
     /// Shows where Envoluntary caches the compiled profiles for a specific flake reference.
     /// Useful for debugging cache-related issues.
     PrintCachePath(EnvoluntaryShellPrintCachePathArgs),
+
+    /// Print the cached profile path for a given Nix flake reference.
+    ///
+    /// Shows the `flake-profile-<hash>` path for a specific flake reference without evaluating
+    /// anything, and reports on stderr whether it currently exists.
+    /// Useful for integrating with other Nix tooling.
+    PrintProfilePath(EnvoluntaryShellPrintProfilePathArgs),
+
+    /// Print how the env vars of two cached profiles of a Nix flake reference differ.
+    ///
+    /// Compares the `flake-profile-<hash>` profiles cached for a flake reference, e.g. to see why
+    /// switching branches produced a different dev env. Secret values are scrubbed.
+    DiffProfiles(EnvoluntaryShellDiffProfilesArgs),
 }
 
 /// Arguments for the `shell hook` command.
@@ -187,22 +241,31 @@ pub struct EnvoluntaryShellExportArgs {
     /// Directory for caching Nix profiles (overrides default cache location).
     ///
     /// If not provided, uses `$XDG_CACHE_HOME/envoluntary` (or `~/.cache/envoluntary` if not set).
-    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR")]
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR", value_parser = cache_dir_parser())]
     pub cache_dir: Option<PathBuf>,
 
+    /// How cached Nix profiles are laid out in the cache directory.
+    #[arg(long, env = "ENVOLUNTARY_CACHE_LAYOUT", value_enum, default_value_t)]
+    pub cache_layout: CacheLayout,
+
     /// Explicit list of Nix flake references to load (overrides config-based matching).
     ///
     /// If provided, these flake references will be used instead of matching against
     /// the configuration file patterns. Useful for testing or temporary overrides.
     /// See: <https://nix.dev/manual/nix/latest/command-ref/new-cli/nix3-flake#flake-references>
-    #[arg(long)]
+    #[arg(long, env = "ENVOLUNTARY_FLAKE")]
     pub flake_references: Option<Vec<String>>,
 
-    /// Override whether to evaluate the flake in impure mode.
+    /// File to read the list of Nix flake references to load from, one per line.
     ///
-    /// If set to `true`, Nix will evaluate the flake with `--impure`, allowing access to environment variables
-    /// and other non-deterministic inputs. If not provided, uses the default evaluation mode.
-    pub impure: Option<bool>,
+    /// Convenient for long flake references or ones containing special characters.
+    /// Only used if `--flake-references` (or `$ENVOLUNTARY_FLAKE`) isn't provided.
+    #[arg(long)]
+    pub flake_references_file: Option<PathBuf>,
+
+    /// Options that change the cached Nix profiles, and so their paths.
+    #[command(flatten)]
+    pub profile_hash: ProfileHashArgs,
 
     /// Force update of cached Nix profiles.
     ///
@@ -211,12 +274,165 @@ pub struct EnvoluntaryShellExportArgs {
     #[arg(long)]
     pub force_update: bool,
 
+    /// Update cached Nix profiles older than this, e.g. `12h` or `1d`.
+    ///
+    /// Useful for flakes referencing unpinned remote state (e.g. `github:owner/repo`), which can
+    /// change without any local file changing. If not provided, profiles don't expire.
+    #[arg(long, env = "ENVOLUNTARY_CACHE_TTL", value_parser = parse_duration)]
+    pub cache_ttl: Option<Duration>,
+
+    /// Skip creating garbage collection roots for flake inputs.
+    ///
+    /// The profile itself is still protected from garbage collection. Useful where Nix garbage
+    /// collection is disabled or managed externally, since flake inputs can number in the dozens.
+    #[arg(long, env = "ENVOLUNTARY_NO_INPUT_GCROOTS")]
+    pub no_input_gcroots: bool,
+
+    /// Create garbage collection roots for at most this many flake inputs, warning about the rest.
+    ///
+    /// A tradeoff for flakes with hundreds of inputs, where rooting them all is slow. If not
+    /// provided, every input is rooted.
+    #[arg(long, env = "ENVOLUNTARY_MAX_INPUT_GCROOTS")]
+    pub max_input_gcroots: Option<usize>,
+
+    /// Directory for the garbage collection roots of flake inputs, or `per-user` for Nix's
+    /// per-user gcroots directory (`/nix/var/nix/gcroots/per-user/$USER`).
+    ///
+    /// Useful when the cache directory is on a volatile filesystem, where roots don't survive a
+    /// reboot. If not provided, roots are kept in the cache directory.
+    #[arg(long, env = "ENVOLUNTARY_INPUT_GCROOTS_DIR", value_parser = parse_input_gcroots_dir)]
+    pub input_gcroots_dir: Option<InputGcrootsDir>,
+
+    /// Check that a cached Nix profile's store path still exists before using it.
+    ///
+    /// Rebuilds the profile if it was garbage collected anyway, e.g. after its garbage
+    /// collection root was removed, at the cost of a `stat` per profile.
+    #[arg(long, env = "ENVOLUNTARY_VERIFY_GCROOTS")]
+    pub verify_gcroots: bool,
+
+    /// Retry evaluating a flake with `--store local` if Nix couldn't connect to its daemon.
+    ///
+    /// Useful where the daemon is flaky or restarting, and the Nix store is writable.
+    #[arg(long, env = "ENVOLUNTARY_LOCAL_STORE_FALLBACK")]
+    pub local_store_fallback: bool,
+
+    /// Reuse the previous Nix profile when a flake's watched files changed but its environment
+    /// didn't, e.g. after a no-op `flake.lock` bump.
+    ///
+    /// Saves rooting the profile and its flake inputs again. The new inputs aren't rooted until
+    /// the environment changes.
+    #[arg(long, env = "ENVOLUNTARY_ALIAS_UNCHANGED_PROFILES")]
+    pub alias_unchanged_profiles: bool,
+
+    /// Compress cached Nix profile rc files with zstd.
+    ///
+    /// Saves disk space for large environments. Profiles already cached uncompressed keep working.
+    #[arg(long, env = "ENVOLUNTARY_COMPRESS_CACHE")]
+    pub compress_cache: bool,
+
+    /// The most Nix processes to run at once, e.g. when creating garbage collection roots.
+    ///
+    /// If not provided, uses the number of CPUs, capped at 4.
+    #[arg(long, env = "ENVOLUNTARY_JOBS")]
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Print progress to stderr while updating cached Nix profiles.
+    ///
+    /// Updating a cold cache can take minutes, so this shows why a profile is being rebuilt and
+    /// which phase is running.
+    #[arg(long, env = "ENVOLUNTARY_PROGRESS")]
+    pub progress: bool,
+
+    /// A file to write the profile hash to whenever a Nix profile is rebuilt.
+    ///
+    /// A lightweight signal for tools that react to new environments, e.g. restarting a
+    /// language server when the file changes.
+    #[arg(long, value_name = "PATH", env = "ENVOLUNTARY_READY_MARKER")]
+    pub ready_marker: Option<PathBuf>,
+
+    /// A shell command to run whenever a Nix profile is rebuilt, with the profile hash as `$1`.
+    ///
+    /// Its output goes to stderr, so it doesn't end up in the exports.
+    #[arg(long, value_name = "COMMAND", env = "ENVOLUNTARY_ON_READY")]
+    pub on_ready: Option<String>,
+
+    /// Print how many env vars each Nix profile adds and changes to stderr.
+    ///
+    /// Useful for seeing how heavy entering an environment is.
+    #[arg(long)]
+    pub stats: bool,
+
+    /// Record a fingerprint of the env vars set for the Nix profiles, and export them again if
+    /// the shell's env no longer matches it.
+    ///
+    /// Otherwise, staying in a directory never exports anything again, even if something (e.g.
+    /// a nested shell's startup files) changed the env vars. `PATH` and `XDG_DATA_DIRS` aren't
+    /// fingerprinted, since other tools commonly add to them.
+    #[arg(long, env = "ENVOLUNTARY_FINGERPRINT_ENV")]
+    pub fingerprint_env: bool,
+
+    /// Build or update the cached Nix profiles without printing any exports.
+    ///
+    /// Useful for warming the cache in the background, e.g. from a git hook, so entering the
+    /// directory later is fast.
+    #[arg(long)]
+    pub prebuild: bool,
+
+    /// Print a line sourcing each cached Nix profile instead of exporting its env vars.
+    ///
+    /// Keeps the output small for huge profiles, but loads everything the profile defines
+    /// (e.g. shell functions), and nothing is unloaded when leaving the directory. Only for bash
+    /// and zsh, and not with `--compress-cache` or `rc_rewrites` in the config.
+    #[arg(long, conflicts_with = "prebuild")]
+    pub emit_source_line: bool,
+
+    /// Write the env vars of the matching Nix profiles to a `.env` file instead of exporting
+    /// them.
+    ///
+    /// For tools that read `.env` files, e.g. docker-compose. Nothing is printed.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["prebuild", "emit_source_line"])]
+    pub emit_dotenv: Option<PathBuf>,
+
+    /// Evaluate the matching Nix profiles fresh without caching them.
+    ///
+    /// For CI or one-off runs where a persistent cache isn't wanted: nothing is written to the
+    /// cache directory and no garbage collection roots are created, so every run evaluates the
+    /// flakes again.
+    #[arg(long, conflicts_with_all = ["prebuild", "emit_source_line", "force_update"])]
+    pub no_cache: bool,
+
+    /// Evaluate local flakes fresh instead of caching them when their watched files (e.g.
+    /// `flake.nix` and `flake.lock`) total fewer bytes than this.
+    ///
+    /// For trivial flakes, where hashing and rooting the profile may cost more than evaluating
+    /// it. Ignored with `--prebuild` and `--emit-source-line`.
+    #[arg(long, value_name = "BYTES", env = "ENVOLUNTARY_CACHE_MIN_BYTES")]
+    pub cache_min_bytes: Option<u64>,
+
+    /// Warn instead of failing when the `nix` version check fails.
+    ///
+    /// For patched or unreleased versions of Nix whose version can't be parsed.
+    #[arg(long, env = "ENVOLUNTARY_IGNORE_VERSION_CHECK")]
+    pub ignore_version_check: bool,
+
     /// The directory path to check for matching configuration entries (for testing).
     ///
     /// If not provided, uses the current working directory.
     /// Useful for debugging what environments would be loaded in a specific directory.
     #[arg(long)]
     pub current_dir: Option<PathBuf>,
+
+    /// Write the export code to this file instead of stdout.
+    ///
+    /// The file is only written once the export succeeds, replacing any previous content.
+    #[arg(long, conflicts_with = "output_fd")]
+    pub output: Option<PathBuf>,
+
+    /// Write the export code to this already open file descriptor instead of stdout.
+    ///
+    /// Useful when embedding Envoluntary in another program, e.g. `--output-fd 3`.
+    #[arg(long)]
+    pub output_fd: Option<u32>,
 }
 
 /// Arguments for the `shell print-cache-path` command.
@@ -231,8 +447,132 @@ pub struct EnvoluntaryShellPrintCachePathArgs {
     /// Directory for caching Nix profiles (overrides default cache location).
     ///
     /// If not provided, uses `$XDG_CACHE_HOME/envoluntary` (or `~/.cache/envoluntary` if not set).
-    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR")]
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR", value_parser = cache_dir_parser())]
     pub cache_dir: Option<PathBuf>,
+
+    /// How cached Nix profiles are laid out in the cache directory.
+    #[arg(long, env = "ENVOLUNTARY_CACHE_LAYOUT", value_enum, default_value_t)]
+    pub cache_layout: CacheLayout,
+}
+
+/// Arguments changing the hash of a cached Nix profile, shared by every command locating one, so
+/// they all agree on its path.
+#[derive(Debug, Clone, Args)]
+pub struct ProfileHashArgs {
+    /// Override whether to evaluate the flake in impure mode.
+    ///
+    /// If set to `true` (or given without a value), Nix will evaluate the flake with `--impure`,
+    /// allowing access to environment variables and other non-deterministic inputs. Impure and
+    /// pure evaluations are cached separately. If not provided, uses the `impure` setting of the
+    /// flake's config entry.
+    #[arg(
+        long,
+        env = "ENVOLUNTARY_IMPURE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub impure: Option<bool>,
+
+    /// Cache Nix profile rc files in a canonical form, with exports sorted by key.
+    ///
+    /// Keeps rc files stable across builds, so diffing two cached profiles shows only what
+    /// changed. Other shell code is kept in place.
+    #[arg(long, env = "ENVOLUNTARY_NORMALIZE_RC")]
+    pub normalize_rc: bool,
+
+    /// What of a local flake's watched files (e.g. `flake.nix`) to hash to tell whether its
+    /// cached Nix profile is up to date: `contents` (the default) or `metadata-only`.
+    ///
+    /// `metadata-only` hashes each file's path, size and mtime without reading it, for huge
+    /// watched files. It's weaker: a change keeping the size and mtime goes unnoticed, and
+    /// touching a file rebuilds the profile.
+    #[arg(long, env = "ENVOLUNTARY_HASH_SCOPE", value_parser = parse_hash_scope)]
+    pub hash_scope: Option<HashScope>,
+
+    /// Env vars to keep Nix dev shells from setting, e.g. `TMPDIR,TERM`.
+    ///
+    /// Their assignments are stripped from the cached profile.
+    #[arg(long, env = "ENVOLUNTARY_EXCLUDE_ENV_VARS", value_delimiter = ',')]
+    pub exclude_env_vars: Vec<String>,
+
+    /// An extra arg for `nix print-dev-env`, passed just before the flake reference. Repeat it
+    /// for each arg, e.g. `--print-dev-env-arg=--override-input --print-dev-env-arg=nixpkgs …`.
+    ///
+    /// An escape hatch for Nix options envoluntary has no flag for. Profiles evaluated with
+    /// different args are cached separately.
+    #[arg(
+        long = "print-dev-env-arg",
+        value_name = "ARG",
+        allow_hyphen_values = true
+    )]
+    pub print_dev_env_args: Vec<OsString>,
+}
+
+/// Arguments for the `shell print-profile-path` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryShellPrintProfilePathArgs {
+    /// The Nix flake reference to get the profile path for.
+    ///
+    /// See: <https://nix.dev/manual/nix/latest/command-ref/new-cli/nix3-flake#flake-references>
+    #[arg(long)]
+    pub flake_reference: String,
+
+    /// Directory for caching Nix profiles (overrides default cache location).
+    ///
+    /// If not provided, uses `$XDG_CACHE_HOME/envoluntary` (or `~/.cache/envoluntary` if not set).
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR", value_parser = cache_dir_parser())]
+    pub cache_dir: Option<PathBuf>,
+
+    /// How cached Nix profiles are laid out in the cache directory.
+    #[arg(long, env = "ENVOLUNTARY_CACHE_LAYOUT", value_enum, default_value_t)]
+    pub cache_layout: CacheLayout,
+
+    /// Path to the configuration file (overrides default location), whose `[options]` and
+    /// entries' `impure` settings change the profile path like they do for `shell export`.
+    #[arg(long, env = "ENVOLUNTARY_CONFIG_PATH")]
+    pub config_path: Option<PathBuf>,
+
+    /// Options that change the cached Nix profiles, and so their paths.
+    #[command(flatten)]
+    pub profile_hash: ProfileHashArgs,
+}
+
+/// Arguments for the `shell diff-profiles` command.
+#[derive(Debug, Clone, Args)]
+pub struct EnvoluntaryShellDiffProfilesArgs {
+    /// The hash of the old profile, as in `flake-profile-<hash>`.
+    pub hash_a: String,
+
+    /// The hash of the new profile, as in `flake-profile-<hash>`.
+    pub hash_b: String,
+
+    /// The Nix flake reference the profiles were cached for.
+    ///
+    /// See: <https://nix.dev/manual/nix/latest/command-ref/new-cli/nix3-flake#flake-references>
+    #[arg(long)]
+    pub flake_reference: String,
+
+    /// Directory for caching Nix profiles (overrides default cache location).
+    ///
+    /// If not provided, uses `$XDG_CACHE_HOME/envoluntary` (or `~/.cache/envoluntary` if not set).
+    #[arg(long, env = "ENVOLUNTARY_CACHE_DIR", value_parser = cache_dir_parser())]
+    pub cache_dir: Option<PathBuf>,
+
+    /// How cached Nix profiles are laid out in the cache directory.
+    #[arg(long, env = "ENVOLUNTARY_CACHE_LAYOUT", value_enum, default_value_t)]
+    pub cache_layout: CacheLayout,
+}
+
+/// Layouts of the per-flake directories in the cache directory.
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum CacheLayout {
+    /// A directory per flake reference hash, directly in the cache directory.
+    #[default]
+    Flat,
+    /// Directories sharded by the first two characters of the flake reference hash, e.g.
+    /// `ab/cdef…`, to avoid huge directories when thousands of profiles are cached.
+    Sharded,
 }
 
 /// Supported shells for hook and export code generation.
@@ -240,6 +580,8 @@ pub struct EnvoluntaryShellPrintCachePathArgs {
 pub enum EnvoluntaryShell {
     /// POSIX shell compatible syntax (sh, bash).
     Bash,
+    /// direnv's `direnv dump` format, for loading with `direnv_load` in an `.envrc`.
+    Direnv,
     /// Fish shell syntax.
     Fish,
     /// JSON output format (useful for machine parsing).
@@ -249,3 +591,104 @@ pub enum EnvoluntaryShell {
     /// Z shell (zsh) syntax.
     Zsh,
 }
+
+/// Normalizes the cache dir, since profile paths are joined onto it, e.g. trailing slashes would
+/// otherwise end up in them.
+fn cache_dir_parser() -> impl TypedValueParser<Value = PathBuf> {
+    // NB: Parsed from an `OsString`, so non-UTF-8 cache dirs still work
+    OsStringValueParser::new().try_map(parse_cache_dir)
+}
+
+fn parse_cache_dir(value: OsString) -> Result<PathBuf, String> {
+    let cache_dir = PathBuf::from(value);
+    if cache_dir.as_os_str().is_empty() {
+        return Err(String::from("expected a directory"));
+    }
+    let cache_dir = match cache_dir.strip_prefix("~") {
+        Ok(relative_to_home) => env::home_dir()
+            .ok_or("couldn't find $HOME to expand `~`")?
+            .join(relative_to_home),
+        Err(_) => cache_dir,
+    };
+    Ok(cache_dir.clean())
+}
+
+pub fn parse_input_gcroots_dir(value: &str) -> Result<InputGcrootsDir, String> {
+    match value {
+        "" => Err(String::from("expected a directory or `per-user`")),
+        "per-user" => Ok(InputGcrootsDir::PerUser),
+        dir => Ok(InputGcrootsDir::Dir(PathBuf::from(dir))),
+    }
+}
+
+pub fn parse_hash_scope(value: &str) -> Result<HashScope, String> {
+    match value {
+        "contents" => Ok(HashScope::Contents),
+        "metadata-only" => Ok(HashScope::MetadataOnly),
+        _ => Err(format!(
+            "expected `contents` or `metadata-only`, got {value:?}"
+        )),
+    }
+}
+
+/// Parses a duration of a whole number of `ms`, `s`, `m`, `h` or `d`, for every time-based
+/// option.
+///
+/// A bare number is read as seconds, which is what these options took before units.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let unit_start = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(unit_start);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("expected a duration like `30s` or `5m`, got {value:?}"))?;
+    let unit_millis: u64 = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => {
+            return Err(format!(
+                "unknown duration unit {unit:?}, expected `ms`, `s`, `m`, `h` or `d`"
+            ));
+        }
+    };
+    number
+        .checked_mul(unit_millis)
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("duration {value:?} is too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::parse_duration;
+
+    #[test]
+    fn test_parse_duration_units() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(5 * 60)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+    }
+
+    #[test]
+    fn test_parse_duration_bare_number_is_seconds() {
+        assert_eq!(parse_duration("86400"), Ok(Duration::from_secs(86400)));
+    }
+
+    #[test]
+    fn test_parse_duration_invalid() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("5 minutes").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("99999999999999999d").is_err());
+    }
+}
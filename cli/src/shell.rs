@@ -1,29 +1,45 @@
-use std::collections::HashSet;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
 use std::env;
+use std::fs;
 use std::path::Path;
-use std::{io::Read, os::unix::ffi::OsStrExt, path::PathBuf};
+use std::{
+    io::{self, Read, Write},
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+};
 
 use base64::{Engine, prelude::BASE64_STANDARD};
 use bstr::B;
 use env_hooks::{
-    BashSource, EnvVars, EnvVarsState, get_env_vars_from_bash, get_env_vars_from_current_process,
-    get_env_vars_reset, get_old_env_vars_to_be_updated, merge_delimited_env_var,
-    remove_ignored_env_vars, shells,
+    BashEnvExtractor, BashSource, DEFAULT_SECRET_PATTERNS, DelimitedEnvVars, EnvBase, EnvDiff,
+    EnvVars, EnvVarsState, get_env_vars_from_current_process, get_env_vars_reset,
+    get_old_env_vars_to_be_updated, remove_ignored_env_vars, remove_invalid_env_var_keys, shells,
     state::{self, GetEnvStateVar, MatchRcs},
 };
-use nix_dev_env::{EvaluationMode, NixProfileCache, check_nix_version};
+use nix_dev_env::{
+    EvaluationMode, NixProfileCache, NixProfileCacheOptions, UpdatePhase, UpdateWarning,
+    check_nix_version, diff_profiles, read_cached_profile_rc,
+};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 use shell_quote::{Bash, Fish, Zsh};
 
-use crate::config::{Config, EnvoluntaryConfig, get_cache_dir, get_config_path};
-use crate::constants::CLI_NAME;
+use crate::config::{
+    Config, EnvoluntaryConfig, RcRewrite, apply_rc_rewrites, get_cache_dir, get_config_path,
+};
+use crate::constants::{CACHE_SALT, CLI_NAME};
+use crate::exit_code::PreflightError;
 use crate::opt::{
-    EnvoluntaryShell, EnvoluntaryShellExportArgs, EnvoluntaryShellPrintCachePathArgs,
+    CacheLayout, EnvoluntaryShell, EnvoluntaryShellDiffProfilesArgs, EnvoluntaryShellExportArgs,
+    EnvoluntaryShellPrintCachePathArgs, EnvoluntaryShellPrintProfilePathArgs, ProfileHashArgs,
 };
 
 const ENVOLUNTARY_ENV_STATE_VAR_KEY: &str = "ENVOLUNTARY_ENV_STATE";
+/// Set to the path of the cached profile rc while it's sourced, e.g. for a dev shell to locate
+/// files next to it. Left unset when a temporary copy is sourced instead.
+const ENVOLUNTARY_RC_PATH_VAR_KEY: &str = "ENVOLUNTARY_RC_PATH";
 
 const ENV_VAR_KEY_PATH: &str = "PATH";
 const ENV_VAR_KEY_XDG_DATA_DIRS: &str = "XDG_DATA_DIRS";
@@ -53,6 +69,11 @@ pub fn print_hook(shell: EnvoluntaryShell) -> anyhow::Result<()> {
                 [&Fish::quote_vec(&current_exe), B("shell export fish")],
             ),
         ),
+        EnvoluntaryShell::Direnv => {
+            return Err(anyhow::anyhow!(
+                "direnv dumps are loaded from an `.envrc`, so there's no hook to use."
+            ));
+        }
         EnvoluntaryShell::Json => {
             return Err(anyhow::anyhow!(
                 "JSON isn't is a shell, so there's no hook to use."
@@ -76,22 +97,82 @@ pub fn print_hook(shell: EnvoluntaryShell) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn print_export(args: EnvoluntaryShellExportArgs) -> anyhow::Result<()> {
+pub fn print_export(
+    mut args: EnvoluntaryShellExportArgs,
+    output: &mut dyn Write,
+) -> anyhow::Result<()> {
+    let output = RefCell::new(output);
+
     let config_path = get_config_path(args.config_path.as_deref())?;
     let envoluntary_config = EnvoluntaryConfig::load(&config_path)?;
+    envoluntary_config.apply_options(&mut args)?;
     let cache_dir = get_cache_dir(args.cache_dir.as_deref())?;
 
-    check_nix_version()?;
+    if let Err(e) = check_nix_version() {
+        if !args.ignore_version_check {
+            return Err(PreflightError(e).into());
+        }
+        eprintln!("{CLI_NAME}: warning: ignoring failed nix version check: {e:#}");
+    }
 
-    let current_dir_state = state::ShellPromptState::get_current_dir(args.current_dir)?;
+    let cache_options = NixProfileCacheOptions {
+        no_input_gcroots: args.no_input_gcroots,
+        max_input_gcroots: args.max_input_gcroots,
+        input_gcroots_dir: args.input_gcroots_dir.unwrap_or_default(),
+        verify_gcroots: args.verify_gcroots,
+        local_store_fallback: args.local_store_fallback,
+        alias_unchanged_profiles: args.alias_unchanged_profiles,
+        jobs: args.jobs,
+        cache_ttl: args.cache_ttl,
+        compress_rc: args.compress_cache,
+        ready_marker: args.ready_marker,
+        ready_command: args.on_ready,
+        on_update_phase: args.progress.then_some(print_update_phase),
+        on_update_warning: Some(print_update_warning),
+        ..profile_hash_cache_options(&args.profile_hash)
+    };
+    let impure = args.profile_hash.impure;
+
+    let flake_references = match args.flake_references {
+        Some(flake_references) => Some(flake_references),
+        None => args
+            .flake_references_file
+            .as_deref()
+            .map(read_flake_references_file)
+            .transpose()?,
+    };
+
+    let get_profile_rc = |config: &Config| -> anyhow::Result<ProfileRc> {
+        let cache_profile = new_cache_profile(
+            &cache_dir,
+            args.cache_layout,
+            &config.flake_reference,
+            impure.or(config.impure),
+            &cache_options,
+        )?;
+        // NB: Prebuilding and sourcing need a cached profile, whatever its size
+        let cache_min_bytes = args
+            .cache_min_bytes
+            .filter(|_| !args.prebuild && !args.emit_source_line);
+        if args.no_cache || below_cache_min_bytes(&cache_profile, cache_min_bytes) {
+            return Ok(ProfileRc::Evaluated(cache_profile.evaluate()?));
+        }
+        update_cache_profile(
+            &cache_profile,
+            &config.flake_reference,
+            args.force_update,
+            args.progress,
+        )?;
+        Ok(ProfileRc::Cached(Box::new(cache_profile)))
+    };
 
-    let match_rcs = current_dir_state.match_rcs(|current_dir| {
-        let config_values = if let Some(ref flake_references) = args.flake_references {
+    let matching_configs = |current_dir: &Path| -> anyhow::Result<Vec<Config>> {
+        let config_values = if let Some(ref flake_references) = flake_references {
             flake_references
                 .iter()
                 .map(|flake_reference| Config {
                     flake_reference: String::from(flake_reference),
-                    impure: args.impure,
+                    impure,
                 })
                 .collect()
         } else {
@@ -102,7 +183,60 @@ pub fn print_export(args: EnvoluntaryShellExportArgs) -> anyhow::Result<()> {
                 .collect()
         };
         Ok(config_values)
-    })?;
+    };
+
+    if args.prebuild || args.emit_source_line || args.emit_dotenv.is_some() {
+        if args.emit_source_line
+            && !matches!(args.shell, EnvoluntaryShell::Bash | EnvoluntaryShell::Zsh)
+        {
+            return Err(anyhow::anyhow!(
+                "--emit-source-line is only supported for bash and zsh, which can source a Nix profile."
+            ));
+        }
+        if args.emit_source_line && !envoluntary_config.rc_rewrites().is_empty() {
+            return Err(anyhow::anyhow!(
+                "--emit-source-line can't be used with `rc_rewrites`, since sourcing the cached profile would skip them."
+            ));
+        }
+        let current_dir = match args.current_dir {
+            Some(current_dir) => current_dir,
+            None => env::current_dir()?,
+        };
+        let mut dotenv_env_vars = EnvVars::new();
+        for config in matching_configs(&current_dir)? {
+            let profile_rc = get_profile_rc(&config)?;
+            // NB: `--no-cache` conflicts with `--emit-source-line`, so the profile rc is cached
+            if args.emit_source_line
+                && let ProfileRc::Cached(cache_profile) = &profile_rc
+            {
+                if cache_profile.profile_rc_is_compressed() {
+                    return Err(anyhow::anyhow!(
+                        "The cached profile for {} is compressed, so it can't be sourced.",
+                        config.flake_reference
+                    ));
+                }
+                let mut output = output.borrow_mut();
+                output.write_all(b"source ")?;
+                output.write_all(&Bash::quote_vec(cache_profile.profile_rc()))?;
+                output.write_all(b"\n")?;
+            }
+            if args.emit_dotenv.is_some() {
+                dotenv_env_vars.extend(
+                    get_new_env_vars(&profile_rc, envoluntary_config.rc_rewrites())?.new_env_vars,
+                );
+            }
+        }
+        if let Some(emit_dotenv) = &args.emit_dotenv {
+            fs::write(emit_dotenv, shells::dotenv::render_dotenv(&dotenv_env_vars)).map_err(
+                |e| anyhow::format_err!("Couldn't write {}: {e}", emit_dotenv.display()),
+            )?;
+        }
+        return Ok(());
+    }
+
+    let current_dir_state = state::ShellPromptState::get_current_dir(args.current_dir)?;
+
+    let match_rcs = current_dir_state.match_rcs(matching_configs)?;
 
     match match_rcs {
         MatchRcs::NoRcs(no_rcs_state) => {
@@ -111,7 +245,7 @@ pub fn print_export(args: EnvoluntaryShellExportArgs) -> anyhow::Result<()> {
             {
                 ready_for_full_reset_state.reset_env_vars(|env_state_var_value| {
                     let env_state = EnvoluntaryEnvState::decode(env_state_var_value.as_bytes())?;
-                    print_shell_export(args.shell, env_state.env_vars_reset);
+                    print_shell_export(*output.borrow_mut(), args.shell, env_state.env_vars_reset)?;
                     Ok(())
                 })?;
             }
@@ -124,21 +258,19 @@ pub fn print_export(args: EnvoluntaryShellExportArgs) -> anyhow::Result<()> {
                         let env_vars_state = rcs.into_iter().try_fold(
                             EnvVarsState::new(),
                             |mut acc, config| -> anyhow::Result<EnvVarsState> {
-                                let cache_profile = get_cache_profile(
-                                    &cache_dir,
-                                    &config.flake_reference,
-                                    args.force_update,
-                                    args.impure.or(config.impure),
-                                )?;
+                                let profile_rc = get_profile_rc(&config)?;
                                 acc.extend(get_export_env_vars_state(
                                     config.flake_reference,
-                                    &cache_profile,
+                                    &profile_rc,
+                                    envoluntary_config.rc_rewrites(),
+                                    args.stats,
+                                    args.fingerprint_env,
                                 )?);
                                 Ok(acc)
                             },
                         )?;
 
-                        print_shell_export(args.shell, env_vars_state);
+                        print_shell_export(*output.borrow_mut(), args.shell, env_vars_state)?;
 
                         Ok(())
                     })?;
@@ -149,47 +281,45 @@ pub fn print_export(args: EnvoluntaryShellExportArgs) -> anyhow::Result<()> {
                             let env_state =
                                 EnvoluntaryEnvState::decode(env_state_var_value.as_bytes())?;
 
-                            if rcs
+                            let already_applied = rcs
                                 .iter()
                                 .map(|config| String::from(&config.flake_reference))
                                 .collect::<Vec<_>>()
                                 == env_state.flake_references
-                            {
-                                return Ok((rcs, env_state.flake_references));
+                                && env_state.is_applied();
+                            if already_applied {
+                                return Ok((rcs, already_applied));
                             }
 
-                            print_shell_export(args.shell, env_state.env_vars_reset);
+                            print_shell_export(
+                                *output.borrow_mut(),
+                                args.shell,
+                                env_state.env_vars_reset,
+                            )?;
 
-                            Ok((rcs, env_state.flake_references))
+                            Ok((rcs, already_applied))
                         },
-                        |(rcs, env_state_flake_references)| {
-                            if rcs
-                                .iter()
-                                .map(|config| String::from(&config.flake_reference))
-                                .collect::<Vec<_>>()
-                                == env_state_flake_references
-                            {
+                        |(rcs, already_applied)| {
+                            if already_applied {
                                 return Ok(());
                             }
 
                             let env_vars_state = rcs.into_iter().try_fold(
                                 EnvVarsState::new(),
                                 |mut acc, config| -> anyhow::Result<EnvVarsState> {
-                                    let cache_profile = get_cache_profile(
-                                        &cache_dir,
-                                        &config.flake_reference,
-                                        args.force_update,
-                                        args.impure.or(config.impure),
-                                    )?;
+                                    let profile_rc = get_profile_rc(&config)?;
                                     acc.extend(get_export_env_vars_state(
                                         config.flake_reference,
-                                        &cache_profile,
+                                        &profile_rc,
+                                        envoluntary_config.rc_rewrites(),
+                                        args.stats,
+                                        args.fingerprint_env,
                                     )?);
                                     Ok(acc)
                                 },
                             )?;
 
-                            print_shell_export(args.shell, env_vars_state);
+                            print_shell_export(*output.borrow_mut(), args.shell, env_vars_state)?;
 
                             Ok(())
                         },
@@ -204,48 +334,219 @@ pub fn print_export(args: EnvoluntaryShellExportArgs) -> anyhow::Result<()> {
 
 pub fn print_cache_path(args: EnvoluntaryShellPrintCachePathArgs) -> anyhow::Result<()> {
     let cache_dir = get_cache_dir(args.cache_dir.as_deref())?;
-    println!(
-        "{}",
-        get_cache_sub_dir(&cache_dir, &args.flake_reference).display()
-    );
+    // NB: Write the raw bytes so non-UTF-8 cache dirs are printed as-is rather than lossily
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(
+        get_cache_sub_dir(&cache_dir, args.cache_layout, &args.flake_reference)
+            .as_os_str()
+            .as_bytes(),
+    )?;
+    stdout.write_all(b"\n")?;
     Ok(())
 }
 
-fn get_cache_profile(
-    cache_dir: &Path,
+pub fn print_profile_path(mut args: EnvoluntaryShellPrintProfilePathArgs) -> anyhow::Result<()> {
+    let config_path = get_config_path(args.config_path.as_deref())?;
+    let envoluntary_config = EnvoluntaryConfig::load(&config_path)?;
+    envoluntary_config.apply_profile_hash_options(&mut args.profile_hash)?;
+    let cache_dir = get_cache_dir(args.cache_dir.as_deref())?;
+    let cache_profile = new_cache_profile(
+        &cache_dir,
+        args.cache_layout,
+        &args.flake_reference,
+        args.profile_hash
+            .impure
+            .or(envoluntary_config.impure_for(&args.flake_reference)),
+        &profile_hash_cache_options(&args.profile_hash),
+    )?;
+    let profile_path = cache_profile.profile_path();
+
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(profile_path.as_os_str().as_bytes())?;
+    stdout.write_all(b"\n")?;
+
+    if profile_path.is_symlink() {
+        eprintln!("{CLI_NAME}: profile exists");
+    } else {
+        eprintln!("{CLI_NAME}: profile doesn't exist yet");
+    }
+
+    Ok(())
+}
+
+pub fn print_profiles_diff(args: EnvoluntaryShellDiffProfilesArgs) -> anyhow::Result<()> {
+    let cache_dir = get_cache_dir(args.cache_dir.as_deref())?;
+    let cache_sub_dir = get_cache_sub_dir(&cache_dir, args.cache_layout, &args.flake_reference);
+    let read_profile_rc = |hash: &str| -> anyhow::Result<String> {
+        Ok(String::from_utf8(read_cached_profile_rc(
+            &cache_sub_dir,
+            hash,
+        )?)?)
+    };
+    let env_diff = diff_profiles(
+        &read_profile_rc(&args.hash_a)?,
+        &read_profile_rc(&args.hash_b)?,
+    )
+    .scrub_secrets(&DEFAULT_SECRET_PATTERNS);
+
+    let mut stdout = io::stdout().lock();
+    for (key, value) in env_diff.added.iter() {
+        writeln!(stdout, "+ {key}={value}")?;
+    }
+    for (key, (old_value, new_value)) in env_diff.changed.iter() {
+        writeln!(stdout, "~ {key}={old_value} -> {new_value}")?;
+    }
+    for (key, value) in env_diff.removed.iter() {
+        writeln!(stdout, "- {key}={value}")?;
+    }
+    if env_diff.is_empty() {
+        eprintln!("{CLI_NAME}: the profiles set the same env vars");
+    }
+
+    Ok(())
+}
+
+fn print_update_phase(update_phase: &UpdatePhase) {
+    eprintln!("{CLI_NAME}: {update_phase}");
+}
+
+fn print_update_warning(update_warning: &UpdateWarning) {
+    eprintln!("{CLI_NAME}: warning: {update_warning}");
+}
+
+fn read_flake_references_file(path: &Path) -> anyhow::Result<Vec<String>> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        anyhow::format_err!(
+            "Couldn't read flake references from {}: {e}",
+            path.display()
+        )
+    })?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+fn update_cache_profile(
+    cache_profile: &NixProfileCache,
     flake_reference: &str,
     force_update: bool,
+    progress: bool,
+) -> anyhow::Result<()> {
+    let rebuild_reason = if force_update {
+        Some(String::from("--force-update was given"))
+    } else {
+        cache_profile
+            .rebuild_reason()?
+            .map(|rebuild_reason| rebuild_reason.to_string())
+    };
+    if let Some(rebuild_reason) = rebuild_reason {
+        if progress {
+            eprintln!("{CLI_NAME}: rebuilding {flake_reference}: {rebuild_reason}");
+        }
+        let update_summary = cache_profile.update()?;
+        if progress {
+            eprintln!("{CLI_NAME}: {update_summary}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a local flake's watched files are too small to be worth caching, see
+/// `--cache-min-bytes`.
+fn below_cache_min_bytes(cache_profile: &NixProfileCache, cache_min_bytes: Option<u64>) -> bool {
+    let Some(cache_min_bytes) = cache_min_bytes else {
+        return false;
+    };
+    let watched_files = cache_profile.watched_files();
+    !watched_files.is_empty()
+        && watched_files
+            .iter()
+            .filter_map(|file| fs::metadata(file).ok())
+            .map(|meta| meta.len())
+            .sum::<u64>()
+            < cache_min_bytes
+}
+
+/// The cache options that change a profile's hash, so every command locating a cached profile
+/// agrees on its path.
+fn profile_hash_cache_options(profile_hash: &ProfileHashArgs) -> NixProfileCacheOptions {
+    NixProfileCacheOptions {
+        cache_salt: Some(CACHE_SALT.to_string()),
+        excluded_env_vars: profile_hash.exclude_env_vars.clone(),
+        print_dev_env_args: profile_hash.print_dev_env_args.clone(),
+        normalize_rc: profile_hash.normalize_rc,
+        hash_scope: profile_hash.hash_scope.unwrap_or_default(),
+        ..NixProfileCacheOptions::default()
+    }
+}
+
+fn evaluation_mode(impure: Option<bool>) -> EvaluationMode {
+    if impure == Some(true) {
+        EvaluationMode::Impure
+    } else {
+        EvaluationMode::Pure
+    }
+}
+
+fn new_cache_profile(
+    cache_dir: &Path,
+    cache_layout: CacheLayout,
+    flake_reference: &str,
     impure: Option<bool>,
+    cache_options: &NixProfileCacheOptions,
 ) -> anyhow::Result<NixProfileCache> {
-    let cach_sub_dir = get_cache_sub_dir(cache_dir, flake_reference);
-    let cache_profile = NixProfileCache::new(
+    let cach_sub_dir = get_cache_sub_dir(cache_dir, cache_layout, flake_reference);
+    NixProfileCache::with_options(
         cach_sub_dir,
         flake_reference,
-        if impure == Some(true) {
-            EvaluationMode::Impure
-        } else {
-            EvaluationMode::Pure
-        },
-    )?;
-
-    if force_update || cache_profile.needs_update()? {
-        cache_profile.update()?;
-    }
-
-    Ok(cache_profile)
+        evaluation_mode(impure),
+        cache_options.clone(),
+    )
 }
 
-fn get_cache_sub_dir(cache_dir: &Path, flake_reference: &str) -> PathBuf {
-    cache_dir.join(format!("{:x}", Sha1::digest(flake_reference)))
+fn get_cache_sub_dir(
+    cache_dir: &Path,
+    cache_layout: CacheLayout,
+    flake_reference: &str,
+) -> PathBuf {
+    let hash = format!("{:x}", Sha1::digest(flake_reference));
+    match cache_layout {
+        CacheLayout::Flat => cache_dir.join(hash),
+        CacheLayout::Sharded => {
+            let (shard, rest) = hash.split_at(2);
+            cache_dir.join(shard).join(rest)
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct EnvoluntaryEnvState {
     flake_references: Vec<String>,
     env_vars_reset: EnvVarsState,
+    /// See `--fingerprint-env`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<String>,
 }
 
 impl EnvoluntaryEnvState {
+    /// Whether the current env still has the env vars this state set, which is assumed without
+    /// a fingerprint.
+    fn is_applied(&self) -> bool {
+        let Some(fingerprint) = &self.fingerprint else {
+            return true;
+        };
+        let current_env_vars = get_env_vars_from_current_process();
+        let env_vars = self
+            .env_vars_reset
+            .keys()
+            .map(|key| (key.as_str(), current_env_vars.get(key).map(String::as_str)));
+        *fingerprint == env_fingerprint(env_vars)
+    }
+
     fn decode(base64_value: impl AsRef<[u8]>) -> anyhow::Result<Self> {
         let zstd_value = BASE64_STANDARD.decode(base64_value)?;
         let mut zstd_value_slice = zstd_value.as_slice();
@@ -268,20 +569,35 @@ impl EnvoluntaryEnvState {
 
 fn get_export_env_vars_state(
     flake_reference: String,
-    cache_profile: &NixProfileCache,
+    profile_rc: &ProfileRc,
+    rc_rewrites: &[RcRewrite],
+    stats: bool,
+    fingerprint: bool,
 ) -> anyhow::Result<EnvVarsState> {
     let EnvVarUpdates {
         mut new_env_vars,
         old_env_vars_to_be_updated,
-    } = get_new_env_vars(cache_profile)?;
+    } = get_new_env_vars(profile_rc, rc_rewrites)?;
+    if stats {
+        let env_diff = EnvDiff::new(&old_env_vars_to_be_updated, &new_env_vars);
+        eprintln!("{CLI_NAME}: {flake_reference}: {}", env_diff.stats());
+    }
     let env_vars_reset = get_env_vars_reset(
         old_env_vars_to_be_updated,
         new_env_vars.keys().cloned().collect(),
         String::from(ENVOLUNTARY_ENV_STATE_VAR_KEY),
     );
+    let fingerprint = fingerprint.then(|| {
+        env_fingerprint(
+            new_env_vars
+                .iter()
+                .map(|(key, value)| (key.as_str(), Some(value.as_str()))),
+        )
+    });
     let env_state = EnvoluntaryEnvState {
         flake_references: vec![flake_reference],
         env_vars_reset,
+        fingerprint,
     };
     new_env_vars.insert(
         String::from(ENVOLUNTARY_ENV_STATE_VAR_KEY),
@@ -290,12 +606,68 @@ fn get_export_env_vars_state(
     Ok(EnvVarsState::from(new_env_vars))
 }
 
+/// A hash of the given env vars' values, sorted by key, skipping `PATH` and `XDG_DATA_DIRS` since
+/// other tools commonly add to them.
+fn env_fingerprint<'a>(env_vars: impl Iterator<Item = (&'a str, Option<&'a str>)>) -> String {
+    let env_vars = env_vars
+        .filter(|(key, _)| {
+            ![
+                ENVOLUNTARY_ENV_STATE_VAR_KEY,
+                ENV_VAR_KEY_PATH,
+                ENV_VAR_KEY_XDG_DATA_DIRS,
+            ]
+            .contains(key)
+        })
+        .collect::<BTreeMap<_, _>>();
+    let mut hasher = Sha1::new();
+    for (key, value) in env_vars {
+        hasher.update(key);
+        match value {
+            Some(value) => {
+                hasher.update(b"=");
+                hasher.update(value);
+            }
+            None => hasher.update(b"!"),
+        }
+        hasher.update(b"\0");
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// A Nix profile rc, either cached or, with `--no-cache`, evaluated without caching.
+enum ProfileRc {
+    Cached(Box<NixProfileCache>),
+    Evaluated(String),
+}
+
+impl ProfileRc {
+    fn read(&self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Self::Cached(cache_profile) => cache_profile.read_profile_rc(),
+            Self::Evaluated(profile_rc) => Ok(profile_rc.clone().into_bytes()),
+        }
+    }
+
+    /// The profile rc as a file bash can source as-is, if there is one.
+    fn sourceable_file(&self) -> Option<&Path> {
+        match self {
+            Self::Cached(cache_profile) if !cache_profile.profile_rc_is_compressed() => {
+                Some(cache_profile.profile_rc())
+            }
+            _ => None,
+        }
+    }
+}
+
 struct EnvVarUpdates {
     new_env_vars: EnvVars,
     old_env_vars_to_be_updated: EnvVars,
 }
 
-fn get_new_env_vars(cache_profile: &NixProfileCache) -> anyhow::Result<EnvVarUpdates> {
+fn get_new_env_vars(
+    profile_rc: &ProfileRc,
+    rc_rewrites: &[RcRewrite],
+) -> anyhow::Result<EnvVarUpdates> {
     let mut bash_env_vars = EnvVars::new();
 
     let old_path = env::var_os(ENV_VAR_KEY_PATH).map(|p| String::from(p.to_string_lossy()));
@@ -307,11 +679,37 @@ fn get_new_env_vars(cache_profile: &NixProfileCache) -> anyhow::Result<EnvVarUpd
     // https://github.com/numtide/devshell/blob/7c9e793ebe66bcba8292989a68c0419b737a22a0/modules/devshell.nix#L400
     bash_env_vars.insert(String::from("DIRENV_IN_ENVRC"), String::from("1"));
 
-    let mut new_env_vars = get_env_vars_from_bash(
-        BashSource::File(PathBuf::from(cache_profile.profile_rc())),
-        Some(bash_env_vars),
-    )?;
+    // NB: bash can't source a compressed or uncached rc, so it's written to a temporary file,
+    // which (unlike a script) isn't limited by the maximum argument length. Rewrites are applied
+    // here rather than when caching, so they apply the same whether or not the profile was just
+    // updated. The temporary file is deleted on return, so its path isn't exposed.
+    let mut tmp_profile_rc = None;
+    let (profile_rc_file, rc_path_var) = match profile_rc.sourceable_file() {
+        Some(profile_rc_file) if rc_rewrites.is_empty() => (
+            PathBuf::from(profile_rc_file),
+            Some(ENVOLUNTARY_RC_PATH_VAR_KEY),
+        ),
+        _ => {
+            let mut profile_rc_content = profile_rc.read()?;
+            if !rc_rewrites.is_empty() {
+                profile_rc_content =
+                    apply_rc_rewrites(&String::from_utf8(profile_rc_content)?, rc_rewrites)
+                        .into_bytes();
+            }
+            let tmp_profile_rc = tmp_profile_rc.insert(tempfile::NamedTempFile::new()?);
+            tmp_profile_rc.write_all(&profile_rc_content)?;
+            (PathBuf::from(tmp_profile_rc.path()), None)
+        }
+    };
+
+    let mut new_env_vars = BashEnvExtractor::new()
+        .env_base(EnvBase::Vars(bash_env_vars))
+        .rc_path_var(rc_path_var)
+        .run(BashSource::File(profile_rc_file))?;
     remove_ignored_env_vars(&mut new_env_vars);
+    for invalid_env_var_key in remove_invalid_env_var_keys(&mut new_env_vars) {
+        eprintln!("{CLI_NAME}: skipping env var with an invalid name: {invalid_env_var_key:?}");
+    }
     if new_env_vars.get(ENV_VAR_KEY_PATH) == old_path.as_ref() {
         new_env_vars.shift_remove(ENV_VAR_KEY_PATH);
     }
@@ -322,24 +720,8 @@ fn get_new_env_vars(cache_profile: &NixProfileCache) -> anyhow::Result<EnvVarUpd
         get_old_env_vars_to_be_updated(old_env_vars, &new_env_vars)
     };
 
-    if new_env_vars.contains_key(ENV_VAR_KEY_PATH) {
-        merge_delimited_env_var(
-            ENV_VAR_KEY_PATH,
-            ':',
-            ':',
-            &old_env_vars_to_be_updated,
-            &mut new_env_vars,
-        );
-    }
-    if new_env_vars.contains_key(ENV_VAR_KEY_XDG_DATA_DIRS) {
-        merge_delimited_env_var(
-            ENV_VAR_KEY_XDG_DATA_DIRS,
-            ':',
-            ':',
-            &old_env_vars_to_be_updated,
-            &mut new_env_vars,
-        );
-    }
+    // NB: The default registry covers `PATH` and `XDG_DATA_DIRS` along with the other search paths
+    DelimitedEnvVars::default().merge(&old_env_vars_to_be_updated, &mut new_env_vars);
 
     Ok(EnvVarUpdates {
         new_env_vars,
@@ -347,11 +729,18 @@ fn get_new_env_vars(cache_profile: &NixProfileCache) -> anyhow::Result<EnvVarUpd
     })
 }
 
-fn print_shell_export(shell: EnvoluntaryShell, env_vars_state: EnvVarsState) {
+fn print_shell_export(
+    output: &mut dyn Write,
+    shell: EnvoluntaryShell,
+    env_vars_state: EnvVarsState,
+) -> anyhow::Result<()> {
     let export = match shell {
         EnvoluntaryShell::Bash => {
             shells::bash::export(env_vars_state, Some(&SEMICOLON_DELIMITED_ENV_VARS))
         }
+        EnvoluntaryShell::Direnv => {
+            shells::direnv::export(env_vars_state, get_env_vars_from_current_process())
+        }
         EnvoluntaryShell::Fish => {
             shells::fish::export(env_vars_state, Some(&SEMICOLON_DELIMITED_ENV_VARS))
         }
@@ -363,5 +752,6 @@ fn print_shell_export(shell: EnvoluntaryShell, env_vars_state: EnvVarsState) {
             shells::zsh::export(env_vars_state, Some(&SEMICOLON_DELIMITED_ENV_VARS))
         }
     };
-    println!("{}", export);
+    writeln!(output, "{}", export)?;
+    Ok(())
 }
@@ -0,0 +1,82 @@
+use std::{env, fs, os::unix::fs::PermissionsExt, path::Path};
+
+use assert_cmd::{Command, cargo};
+use predicates::prelude::*;
+
+fn write_fake_nix(bin_dir: &Path, flake_metadata_exit_code: i32) {
+    let bash_path = env::var("NIX_BIN_BASH").unwrap_or_else(|_| String::from("/bin/bash"));
+    let nix_file_content = format!(
+        r#"#! {bash_path}
+
+if [[ "$@" == "--extra-experimental-features nix-command flakes --version" ]]; then
+  echo "nix (Nix) 2.30.0"
+  exit 0
+fi
+
+if [[ "$3" == "flake" && "$4" == "metadata" ]]; then
+  exit {flake_metadata_exit_code}
+fi
+
+exit 1
+"#
+    );
+    let nix_file = bin_dir.join("nix");
+    fs::write(&nix_file, nix_file_content).unwrap();
+    fs::set_permissions(&nix_file, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+fn doctor_cmd(work_dir: &Path) -> Command {
+    let bin_dir = work_dir.join("bin");
+    let original_path = env::var("PATH").unwrap_or_default();
+    let new_path = format!("{}:{}", bin_dir.display(), original_path);
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args([
+        "doctor",
+        "--config-path",
+        &work_dir.join("config.toml").to_string_lossy(),
+        "--cache-dir",
+        &work_dir.join("cache").to_string_lossy(),
+    ])
+    .env("PATH", new_path);
+    cmd
+}
+
+#[test]
+fn doctor_passes_when_setup_is_healthy() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    fs::create_dir(&bin_dir).unwrap();
+    write_fake_nix(&bin_dir, 0);
+
+    doctor_cmd(work_dir.path()).assert().success().stdout(
+        predicate::str::contains("ok    bash\n")
+            .and(predicate::str::contains("ok    nix version\n"))
+            .and(predicate::str::contains("ok    config\n"))
+            .and(predicate::str::contains("ok    cache dir\n"))
+            .and(predicate::str::contains("ok    nix flake metadata\n")),
+    );
+    assert!(work_dir.path().join("cache").is_dir());
+}
+
+#[test]
+fn doctor_reports_failed_checks_with_hints() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    fs::create_dir(&bin_dir).unwrap();
+    write_fake_nix(&bin_dir, 1);
+    fs::write(work_dir.path().join("config.toml"), "entries = 1").unwrap();
+
+    doctor_cmd(work_dir.path())
+        .assert()
+        .failure()
+        .stdout(
+            predicate::str::contains("ok    nix version\n")
+                .and(predicate::str::contains("FAIL  config: "))
+                .and(predicate::str::contains("FAIL  nix flake metadata: "))
+                .and(predicate::str::contains(
+                    "      hint: check that flakes work",
+                )),
+        )
+        .stderr(predicate::str::contains("2 of 5 checks failed."));
+}
@@ -0,0 +1,97 @@
+use std::{env, fs, os::unix::fs::PermissionsExt, path::Path};
+
+use assert_cmd::{Command, cargo};
+use predicates::prelude::*;
+
+fn write_fake_nix(bin_dir: &Path, nix_version: &str, print_dev_env_exit_code: i32) {
+    let bash_path = env::var("NIX_BIN_BASH").unwrap_or_else(|_| String::from("/bin/bash"));
+    let nix_file_content = format!(
+        r#"#! {bash_path}
+
+if [[ "$@" == "--extra-experimental-features nix-command flakes --version" ]]; then
+  echo "nix (Nix) {nix_version}"
+  exit 0
+fi
+
+if [[ "$3" == "print-dev-env" ]]; then
+  exit {print_dev_env_exit_code}
+fi
+
+exit 1
+"#
+    );
+    fs::create_dir_all(bin_dir).unwrap();
+    let nix_file = bin_dir.join("nix");
+    fs::write(&nix_file, nix_file_content).unwrap();
+    fs::set_permissions(&nix_file, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+fn export_cmd(work_dir: &Path) -> Command {
+    let bin_dir = work_dir.join("bin");
+    let original_path = env::var("PATH").unwrap_or_default();
+    let new_path = format!("{}:{}", bin_dir.display(), original_path);
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args(["shell", "export", "bash", "--config-path"])
+        .arg(work_dir.join("config.toml"))
+        .arg("--cache-dir")
+        .arg(work_dir.join("cache"))
+        .args(["--flake-references", "github:owner/repo"])
+        .env("PATH", new_path)
+        .env_remove("ENVOLUNTARY_IGNORE_VERSION_CHECK");
+    cmd
+}
+
+#[test]
+fn usage_errors_exit_with_2() {
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args(["shell", "export", "not-a-shell"]);
+    cmd.assert().code(2);
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.arg("--help");
+    cmd.assert().code(0);
+}
+
+#[test]
+fn preflight_failures_exit_with_3() {
+    let work_dir = tempfile::tempdir().unwrap();
+    fs::write(work_dir.path().join("config.toml"), "").unwrap();
+    write_fake_nix(&work_dir.path().join("bin"), "2.3.0", 0);
+
+    export_cmd(work_dir.path())
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains(
+            "`nix` version too old for flakes.",
+        ));
+
+    let original_path = env::var("PATH").unwrap_or_default();
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args(["shell", "check-nix-version"]).env(
+        "PATH",
+        format!("{}:{original_path}", work_dir.path().join("bin").display()),
+    );
+    cmd.assert().code(3);
+}
+
+#[test]
+fn nix_failures_exit_with_4() {
+    let work_dir = tempfile::tempdir().unwrap();
+    fs::write(work_dir.path().join("config.toml"), "").unwrap();
+    write_fake_nix(&work_dir.path().join("bin"), "2.30.0", 1);
+
+    export_cmd(work_dir.path())
+        .assert()
+        .code(4)
+        .stderr(predicate::str::contains("print-dev-env"));
+}
+
+#[test]
+fn other_failures_exit_with_1() {
+    let work_dir = tempfile::tempdir().unwrap();
+    fs::write(work_dir.path().join("config.toml"), "not valid toml [").unwrap();
+    write_fake_nix(&work_dir.path().join("bin"), "2.30.0", 0);
+
+    export_cmd(work_dir.path()).assert().code(1);
+}
@@ -0,0 +1,80 @@
+use std::{env, fs, os::unix::fs::PermissionsExt, path::Path};
+
+use assert_cmd::{Command, cargo};
+use predicates::prelude::*;
+
+fn write_fake_nix(bin_dir: &Path, args_file: &Path) {
+    let bash_path = env::var("NIX_BIN_BASH").unwrap_or_else(|_| String::from("/bin/bash"));
+    let nix_file_content = format!(
+        r#"#! {bash_path}
+
+echo "$@" > {args_file}
+if [[ "$3 $4" == "build --out-link" && "$6" == /nix/store/* ]]; then
+  ln -sf "$6" "$5"
+  exit 0
+fi
+
+echo "error: path '$6' is not in the Nix store" >&2
+exit 1
+"#,
+        args_file = args_file.display()
+    );
+    let nix_file = bin_dir.join("nix");
+    fs::write(&nix_file, nix_file_content).unwrap();
+    fs::set_permissions(&nix_file, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+fn gcroot_cmd(work_dir: &Path, store_path: &str, symlink: &Path) -> Command {
+    let bin_dir = work_dir.join("bin");
+    let original_path = env::var("PATH").unwrap_or_default();
+    let new_path = format!("{}:{}", bin_dir.display(), original_path);
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.arg("gcroot")
+        .arg(store_path)
+        .arg(symlink)
+        .env("PATH", new_path);
+    cmd
+}
+
+#[test]
+fn gcroot_runs_nix_build_out_link() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    fs::create_dir(&bin_dir).unwrap();
+    let args_file = work_dir.path().join("nix-args");
+    write_fake_nix(&bin_dir, &args_file);
+    let symlink = work_dir.path().join("my-root");
+    let store_path = "/nix/store/yfzmnk75f009yb7b542kf4r7qaqq9kid-source";
+
+    gcroot_cmd(work_dir.path(), store_path, &symlink)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("created gcroot"));
+
+    assert_eq!(
+        fs::read_to_string(&args_file).unwrap(),
+        format!(
+            "--extra-experimental-features nix-command flakes build --out-link {} {store_path}\n",
+            symlink.display()
+        )
+    );
+    assert_eq!(fs::read_link(&symlink).unwrap(), Path::new(store_path));
+}
+
+#[test]
+fn gcroot_reports_nix_stderr_on_failure() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    fs::create_dir(&bin_dir).unwrap();
+    write_fake_nix(&bin_dir, &work_dir.path().join("nix-args"));
+    let symlink = work_dir.path().join("my-root");
+
+    gcroot_cmd(work_dir.path(), "/tmp/not-in-store", &symlink)
+        .assert()
+        .code(4)
+        .stderr(predicate::str::contains(
+            "error: path '/tmp/not-in-store' is not in the Nix store",
+        ));
+    assert!(!symlink.is_symlink());
+}
@@ -1,10 +1,125 @@
-use std::{env, fs, os::unix::fs::PermissionsExt, process};
+use std::{
+    env,
+    ffi::OsStr,
+    fs,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::{PermissionsExt, symlink},
+    },
+    path::Path,
+    process,
+};
 
 use assert_cmd::{Command, cargo};
 use env_hooks::{BashSource, EnvVars, get_env_vars_from_bash};
 use predicates::prelude::*;
 use sha1::{Digest, Sha1};
 
+/// The hash of a (non-path) flake's cached profile, salted with the CLI version.
+fn profile_hash(flake_reference: &str) -> String {
+    format!(
+        "{:x}",
+        Sha1::digest(format!(
+            "envoluntary-{}{flake_reference}",
+            env!("CARGO_PKG_VERSION")
+        ))
+    )
+}
+
+/// How the fake `nix` written by [`write_fake_nix`] behaves.
+struct FakeNix<'a> {
+    /// Printed by `nix --version`.
+    version: &'a str,
+    /// Bash printing the profile rc, with the flake reference in `$installable` and the
+    /// `--profile` path (if any) in `$profile_path`.
+    print_dev_env: &'a str,
+    /// Bash run by `nix build` before it creates the gcroot.
+    on_build: &'a str,
+    /// Printed by `nix flake archive --json`.
+    flake_archive_json: &'a str,
+    /// A file each invocation's args (after `--extra-experimental-features`) are appended to.
+    log_file: Option<&'a Path>,
+}
+
+impl Default for FakeNix<'_> {
+    fn default() -> Self {
+        Self {
+            version: "nix (Nix) 2.30.0",
+            print_dev_env: r#"echo "export FAKE_VAR=true;""#,
+            on_build: "",
+            flake_archive_json: r#"{ "inputs": {} }"#,
+            log_file: None,
+        }
+    }
+}
+
+/// Writes a fake `nix` to `bin_dir` and returns a `PATH` that finds it first.
+///
+/// Args like `--profile` and `--out-link` are found by name, and the fake fails rather than
+/// write anywhere outside the parent of `bin_dir`.
+fn write_fake_nix(bin_dir: &Path, fake_nix: FakeNix) -> String {
+    let FakeNix {
+        version,
+        print_dev_env,
+        on_build,
+        flake_archive_json,
+        log_file,
+    } = fake_nix;
+    let log = log_file
+        .map(|log_file| format!(r#"echo "${{*:3}}" >> "{}""#, log_file.display()))
+        .unwrap_or_default();
+
+    let bash_path = env::var("NIX_BIN_BASH").unwrap_or_else(|_| String::from("/bin/bash"));
+    let nix_file_content = format!(
+        r#"#! {bash_path}
+
+{log}
+work_dir="$(dirname "$(dirname "$0")")"
+
+arg_value() {{
+    local args=("${{@:2}}")
+    for ((i=0; i+1<${{#args[@]}}; i++)); do
+        if [[ "${{args[$i]}}" == "$1" ]]; then
+            case "${{args[$((i+1))]}}" in
+                "$work_dir"/*) echo "${{args[$((i+1))]}}"; return 0 ;;
+                *) return 1 ;;
+            esac
+        fi
+    done
+}}
+
+installable="${{@: -1}}"
+if [[ "$3" == "--version" ]]; then
+    echo "{version}"
+elif [[ "$3" == "print-dev-env" ]]; then
+    profile_path="$(arg_value --profile "$@")" || exit 1
+    rc="$({print_dev_env})"
+    if [[ -n "$profile_path" ]]; then
+        printf '%s\n' "$rc" > "$profile_path"
+    fi
+    printf '%s\n' "$rc"
+elif [[ "$3" == "build" ]]; then
+    link_path="$(arg_value --out-link "$@")" || exit 1
+    [[ -n "$link_path" ]] || exit 1
+    {on_build}
+    mkdir -p "$(dirname "$link_path")"
+    ln -sf "/nix/store/$(basename "$installable")" "$link_path"
+elif [[ "$3" == "flake" && "$4" == "archive" ]]; then
+    echo '{flake_archive_json}'
+fi
+
+exit 0
+"#
+    );
+    fs::create_dir_all(bin_dir).unwrap();
+    let nix_file = bin_dir.join("nix");
+    fs::write(&nix_file, nix_file_content).unwrap();
+    fs::set_permissions(&nix_file, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let original_path = env::var("PATH").unwrap_or_default();
+    format!("{}:{}", bin_dir.display(), original_path)
+}
+
 #[test]
 fn shell_hook_bash_produces_evaluable_shell_syntax() {
     let mut cmd = Command::new(cargo::cargo_bin!());
@@ -50,34 +165,1009 @@ fn shell_print_cache_path_outputs_valid_path() {
     ));
 }
 
+#[test]
+fn shell_print_cache_path_normalizes_cache_dir() {
+    let home_dir = tempfile::tempdir().unwrap();
+
+    let flake_reference = "github:owner/repo";
+    let flake_reference_hash = format!("{:x}", Sha1::digest(flake_reference));
+    let print_cache_path = |cache_dir: &str| {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args([
+            "shell",
+            "print-cache-path",
+            "--flake-reference",
+            flake_reference,
+            "--cache-dir",
+            cache_dir,
+        ])
+        .env("HOME", home_dir.path());
+        cmd.assert()
+    };
+
+    print_cache_path("/tmp/envoluntary-cache//")
+        .success()
+        .stdout(predicate::eq(format!(
+            "/tmp/envoluntary-cache/{flake_reference_hash}\n"
+        )));
+    print_cache_path("~/.cache/envoluntary/")
+        .success()
+        .stdout(predicate::eq(format!(
+            "{}/.cache/envoluntary/{flake_reference_hash}\n",
+            home_dir.path().display()
+        )));
+    print_cache_path("")
+        .code(2)
+        .stderr(predicate::str::contains("expected a directory"));
+}
+
+#[test]
+fn shell_print_cache_path_is_distinct_per_flake_reference() {
+    let cache_dir = tempfile::tempdir().unwrap();
+
+    let print_cache_path = |flake_reference: &str| {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args([
+            "shell",
+            "print-cache-path",
+            "--flake-reference",
+            flake_reference,
+            "--cache-dir",
+            &cache_dir.path().to_string_lossy(),
+        ]);
+        let output = cmd.assert().success().get_output().stdout.clone();
+        String::from_utf8(output).unwrap()
+    };
+
+    let first_cache_path = print_cache_path("github:owner/first");
+    let second_cache_path = print_cache_path("github:owner/second");
+    assert_ne!(first_cache_path, second_cache_path);
+    for cache_path in [&first_cache_path, &second_cache_path] {
+        assert_eq!(
+            Path::new(cache_path.trim_end()).parent(),
+            Some(cache_dir.path())
+        );
+    }
+}
+
+#[test]
+fn shell_diff_profiles_prints_env_var_changes() {
+    let cache_dir = tempfile::tempdir().unwrap();
+
+    let flake_reference = "github:owner/repo";
+    let cache_sub_dir = cache_dir
+        .path()
+        .join(format!("{:x}", Sha1::digest(flake_reference)));
+    fs::create_dir_all(&cache_sub_dir).unwrap();
+    fs::write(
+        cache_sub_dir.join("flake-profile-aaa.rc"),
+        "export SHARED='same'\nexport VERSION='1.0'\nexport OLD_ONLY='gone'\n",
+    )
+    .unwrap();
+    fs::write(
+        cache_sub_dir.join("flake-profile-bbb.rc"),
+        "export SHARED='same'\nexport VERSION='2.0'\nexport API_TOKEN='hunter2'\n",
+    )
+    .unwrap();
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args([
+        "shell",
+        "diff-profiles",
+        "--flake-reference",
+        flake_reference,
+        "--cache-dir",
+        &cache_dir.path().to_string_lossy(),
+        "aaa",
+        "bbb",
+    ]);
+    cmd.assert()
+        .success()
+        .stdout("+ API_TOKEN=***\n~ VERSION=1.0 -> 2.0\n- OLD_ONLY=gone\n");
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args([
+        "shell",
+        "diff-profiles",
+        "--flake-reference",
+        flake_reference,
+        "--cache-dir",
+        &cache_dir.path().to_string_lossy(),
+        "aaa",
+        "missing",
+    ]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("No cached profile rc for missing"));
+}
+
+#[test]
+fn shell_diff_profiles_works_for_a_removed_path_flake() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let cache_dir = work_dir.path().join("cache");
+
+    let flake_reference = format!("path:{}", work_dir.path().join("removed-flake").display());
+    let cache_sub_dir = cache_dir.join(format!("{:x}", Sha1::digest(&flake_reference)));
+    fs::create_dir_all(&cache_sub_dir).unwrap();
+    fs::write(
+        cache_sub_dir.join("flake-profile-aaa.rc"),
+        "export VERSION='1.0'\n",
+    )
+    .unwrap();
+    fs::write(
+        cache_sub_dir.join("flake-profile-bbb.rc"),
+        "export VERSION='2.0'\n",
+    )
+    .unwrap();
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args([
+        "shell",
+        "diff-profiles",
+        "--flake-reference",
+        &flake_reference,
+    ])
+    .arg("--cache-dir")
+    .arg(&cache_dir)
+    .args(["aaa", "bbb"]);
+    cmd.assert().success().stdout("~ VERSION=1.0 -> 2.0\n");
+}
+
+#[test]
+fn shell_print_paths_with_sharded_cache_layout() {
+    let cache_dir = tempfile::tempdir().unwrap();
+
+    let flake_reference = "github:owner/repo";
+    let flake_reference_hash = format!("{:x}", Sha1::digest(flake_reference));
+    let (shard, rest) = flake_reference_hash.split_at(2);
+    let cache_sub_dir = cache_dir.path().join(shard).join(rest);
+
+    for (subcommand, expected_path) in [
+        ("print-cache-path", cache_sub_dir.clone()),
+        (
+            "print-profile-path",
+            cache_sub_dir.join(format!("flake-profile-{}", profile_hash(flake_reference))),
+        ),
+    ] {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args([
+            "shell",
+            subcommand,
+            "--flake-reference",
+            flake_reference,
+            "--cache-layout",
+            "sharded",
+            "--cache-dir",
+            &cache_dir.path().to_string_lossy(),
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::eq(format!("{}\n", expected_path.display())));
+    }
+}
+
+#[test]
+fn shell_print_profile_path_outputs_path_regardless_of_cache_state() {
+    let cache_dir = tempfile::tempdir().unwrap();
+
+    let flake_reference = "github:owner/repo";
+    let profile_path = cache_dir
+        .path()
+        .join(format!("{:x}", Sha1::digest(flake_reference)))
+        .join(format!("flake-profile-{}", profile_hash(flake_reference)));
+
+    let print_profile_path = || {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args([
+            "shell",
+            "print-profile-path",
+            "--flake-reference",
+            flake_reference,
+            "--cache-dir",
+            &cache_dir.path().to_string_lossy(),
+        ])
+        .arg("--config-path")
+        .arg(cache_dir.path().join("no-config.toml"));
+        cmd.assert()
+            .success()
+            .stdout(predicate::eq(format!("{}\n", profile_path.display())))
+    };
+
+    print_profile_path().stderr(predicate::str::contains("profile doesn't exist yet"));
+
+    fs::create_dir_all(profile_path.parent().unwrap()).unwrap();
+    symlink("/nix/store/profile", &profile_path).unwrap();
+    print_profile_path().stderr(predicate::str::contains("profile exists"));
+}
+
 #[test]
 fn shell_export_with_empty_config_and_no_flake_references() {
     let work_dir = tempfile::tempdir().unwrap();
     let bin_dir = work_dir.path().join("bin");
-    fs::create_dir(&bin_dir).unwrap();
-    let nix_file = bin_dir.join("nix");
+
+    let new_path = write_fake_nix(&bin_dir, FakeNix::default());
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args(["shell", "export", "bash"]).env("PATH", new_path);
+
+    cmd.assert().success().stdout(predicate::eq(""));
+}
+
+#[test]
+fn shell_export_writes_to_output_file() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+    let output_file = work_dir.path().join("export.sh");
+
+    let new_path = write_fake_nix(&bin_dir, FakeNix::default());
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args([
+        "shell",
+        "export",
+        "bash",
+        "--config-path",
+        &config_file.to_string_lossy(),
+        "--cache-dir",
+        &work_dir.path().join("cache").to_string_lossy(),
+        "--current-dir",
+        "/",
+        "--output",
+        &output_file.to_string_lossy(),
+    ])
+    .env("PATH", new_path)
+    .env(
+        "ENVOLUNTARY_ENV_STATE",
+        "KLUv/QQ4dQMArAYAeyJmbGFrZV9yZWZlcmVuY2VzIjpbImdpdGh1Yjpvd25lci9yZXBvIl0sImVudl92YXJzX3Jlc2V0Ijp7IkZBS0VfVkFSIjpudWxsLCJFTlZPTFVOVEFSWV9FTlZfU1RBVEUiOm51bGx9fQCbvTM7",
+    );
+
+    cmd.assert().success().stdout(predicate::eq(""));
+
+    assert_eq!(
+        fs::read_to_string(&output_file).unwrap(),
+        "unset FAKE_VAR;\nunset ENVOLUNTARY_ENV_STATE;\n"
+    );
+}
+
+#[test]
+fn shell_export_leaves_output_file_alone_on_failure() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+    let output_file = work_dir.path().join("export.sh");
+    fs::write(&output_file, "export PREVIOUS_VAR=1;\n").unwrap();
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            version: "nix (Nix) pre-release",
+            ..FakeNix::default()
+        },
+    );
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args(["shell", "export", "bash", "--config-path"])
+        .arg(&config_file)
+        .arg("--cache-dir")
+        .arg(work_dir.path().join("cache"))
+        .args(["--flake-references", "github:owner/repo"])
+        .arg("--output")
+        .arg(&output_file)
+        .env("PATH", new_path)
+        .env_remove("ENVOLUNTARY_IGNORE_VERSION_CHECK");
+
+    cmd.assert().failure();
+    assert_eq!(
+        fs::read_to_string(&output_file).unwrap(),
+        "export PREVIOUS_VAR=1;\n"
+    );
+}
+
+#[test]
+fn shell_export_with_non_utf8_cache_dir() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let cache_dir = work_dir.path().join(OsStr::from_bytes(b"cache-\xff"));
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+
+    let new_path = write_fake_nix(&bin_dir, FakeNix::default());
+
+    let flake_reference = "github:owner/repo";
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args(["shell", "export", "bash", "--config-path"])
+        .arg(&config_file)
+        .arg("--cache-dir")
+        .arg(&cache_dir)
+        .args(["--flake-references", flake_reference])
+        .env("PATH", new_path);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("export FAKE_VAR=true;"));
+
+    let cache_sub_dir = cache_dir.join(format!("{:x}", Sha1::digest(flake_reference)));
+    let profile_symlink =
+        cache_sub_dir.join(format!("flake-profile-{}", profile_hash(flake_reference)));
+    assert!(profile_symlink.is_symlink());
+    assert!(profile_symlink.with_extension("rc").is_file());
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args([
+        "shell",
+        "print-cache-path",
+        "--flake-reference",
+        flake_reference,
+    ])
+    .arg("--cache-dir")
+    .arg(&cache_dir);
+
+    let mut expected_output = cache_sub_dir.into_os_string().into_vec();
+    expected_output.push(b'\n');
+    cmd.assert()
+        .success()
+        .stdout(predicate::eq(expected_output));
+}
+
+#[test]
+fn shell_export_with_compress_cache_sources_compressed_rc() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let cache_dir = work_dir.path().join("cache");
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+
+    let new_path = write_fake_nix(&bin_dir, FakeNix::default());
+
+    let flake_reference = "github:owner/repo";
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args([
+        "shell",
+        "export",
+        "bash",
+        "--compress-cache",
+        "--config-path",
+    ])
+    .arg(&config_file)
+    .arg("--cache-dir")
+    .arg(&cache_dir)
+    .args(["--flake-references", flake_reference])
+    .env("PATH", new_path);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::starts_with("export FAKE_VAR=true;\n"));
+
+    let profile_symlink = cache_dir
+        .join(format!("{:x}", Sha1::digest(flake_reference)))
+        .join(format!("flake-profile-{}", profile_hash(flake_reference)));
+    assert!(profile_symlink.with_extension("rc.zst").is_file());
+    assert!(!profile_symlink.with_extension("rc").exists());
+}
+
+#[test]
+fn shell_export_sets_rc_path_only_when_sourcing_the_cached_rc() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let cache_dir = work_dir.path().join("cache");
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            print_dev_env: r#"echo 'export SEEN_RC_PATH="$ENVOLUNTARY_RC_PATH";'"#,
+            ..FakeNix::default()
+        },
+    );
+
+    let flake_reference = "github:owner/repo";
+    let export = |cache_dir: &Path, extra_args: &[&str]| {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args(["shell", "export", "json", "--config-path"])
+            .arg(&config_file)
+            .arg("--cache-dir")
+            .arg(cache_dir)
+            .args(["--flake-references", flake_reference])
+            .args(extra_args)
+            .env("PATH", &new_path)
+            .env_remove("ENVOLUNTARY_ENV_STATE");
+        let output = cmd.assert().success().get_output().stdout.clone();
+        serde_json::from_slice::<serde_json::Value>(&output).unwrap()["SEEN_RC_PATH"].clone()
+    };
+
+    let profile_rc = cache_dir
+        .join(format!("{:x}", Sha1::digest(flake_reference)))
+        .join(format!(
+            "flake-profile-{}.rc",
+            profile_hash(flake_reference)
+        ));
+    assert_eq!(
+        export(&cache_dir, &[]),
+        profile_rc.to_string_lossy().as_ref()
+    );
+    // NB: A compressed rc is sourced from a temporary copy, whose path would be gone on return
+    assert_eq!(
+        export(
+            &work_dir.path().join("compressed-cache"),
+            &["--compress-cache"]
+        ),
+        ""
+    );
+}
+
+#[test]
+fn shell_export_with_prebuild_writes_rc_without_printing() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let cache_dir = work_dir.path().join("cache");
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+
+    let new_path = write_fake_nix(&bin_dir, FakeNix::default());
+
+    let flake_reference = "github:owner/repo";
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args(["shell", "export", "bash", "--prebuild", "--config-path"])
+        .arg(&config_file)
+        .arg("--cache-dir")
+        .arg(&cache_dir)
+        .args(["--flake-references", flake_reference])
+        .env("PATH", new_path);
+
+    cmd.assert().success().stdout(predicate::str::is_empty());
+
+    let profile_rc = cache_dir
+        .join(format!("{:x}", Sha1::digest(flake_reference)))
+        .join(format!(
+            "flake-profile-{}.rc",
+            profile_hash(flake_reference)
+        ));
+    assert_eq!(
+        fs::read_to_string(profile_rc).unwrap(),
+        "export FAKE_VAR=true;\n"
+    );
+}
+
+#[test]
+fn shell_export_with_emit_source_line_prints_quoted_source_of_rc() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let cache_dir = work_dir.path().join("cache dir's");
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
 
     let bash_path = env::var("NIX_BIN_BASH").unwrap_or_else(|_| String::from("/bin/bash"));
-    let nix_file_content = format!(
-        r#"#! {bash_path}
+    let new_path = write_fake_nix(&bin_dir, FakeNix::default());
 
-if [[ "$@" == "--extra-experimental-features nix-command flakes --version" ]]; then
-  echo "nix (Nix) 2.30.0"
-fi
+    let flake_reference = "github:owner/repo";
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args([
+        "shell",
+        "export",
+        "bash",
+        "--emit-source-line",
+        "--config-path",
+    ])
+    .arg(&config_file)
+    .arg("--cache-dir")
+    .arg(&cache_dir)
+    .args(["--flake-references", flake_reference])
+    .env("PATH", new_path);
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let source_line = String::from_utf8(output).unwrap();
+
+    let profile_rc = cache_dir
+        .join(format!("{:x}", Sha1::digest(flake_reference)))
+        .join(format!(
+            "flake-profile-{}.rc",
+            profile_hash(flake_reference)
+        ));
+    assert!(source_line.starts_with("source "));
+    assert_eq!(source_line.lines().count(), 1);
+
+    let sourced = process::Command::new(&bash_path)
+        .args([
+            "-c",
+            &format!(
+                r#"source() {{ echo "$1"; builtin source "$1"; }}; {source_line} echo "$FAKE_VAR""#
+            ),
+        ])
+        .output()
+        .unwrap();
+    assert!(sourced.status.success());
+    assert_eq!(
+        String::from_utf8(sourced.stdout).unwrap(),
+        format!("{}\ntrue\n", profile_rc.display())
+    );
+}
 
-exit 0
-"#
+#[test]
+fn shell_export_with_emit_source_line_rejects_rc_rewrites() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(
+        &config_file,
+        "[[rc_rewrites]]\npattern = \"/opt/tools\"\nreplacement = \"/usr/local/tools\"\n",
+    )
+    .unwrap();
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args([
+        "shell",
+        "export",
+        "bash",
+        "--emit-source-line",
+        "--ignore-version-check",
+        "--config-path",
+    ])
+    .arg(&config_file)
+    .arg("--cache-dir")
+    .arg(work_dir.path().join("cache"))
+    .args(["--flake-references", "github:owner/repo"])
+    .env("PATH", work_dir.path().join("bin"));
+
+    cmd.assert()
+        .failure()
+        .stdout(predicate::eq(""))
+        .stderr(predicate::str::contains(
+            "--emit-source-line can't be used with `rc_rewrites`",
+        ));
+    assert!(!work_dir.path().join("cache").exists());
+}
+
+#[test]
+fn shell_export_with_stats_reports_env_diff_size() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            print_dev_env: r#"echo "export FAKE_VAR=true CHANGED_VAR=new;""#,
+            ..FakeNix::default()
+        },
     );
-    fs::write(&nix_file, nix_file_content).unwrap();
-    fs::set_permissions(&nix_file, fs::Permissions::from_mode(0o755)).unwrap();
 
-    let original_path = env::var("PATH").unwrap_or_default();
-    let new_path = format!("{}:{}", bin_dir.display(), original_path);
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args(["shell", "export", "bash", "--stats", "--config-path"])
+        .arg(&config_file)
+        .arg("--cache-dir")
+        .arg(work_dir.path().join("cache"))
+        .args(["--flake-references", "github:owner/repo"])
+        .env("PATH", new_path)
+        .env("CHANGED_VAR", "old");
+
+    cmd.assert().success().stderr(predicate::eq(
+        "envoluntary: github:owner/repo: 1 added (4 bytes), 1 changed, 0 removed\n",
+    ));
+}
+
+#[test]
+fn shell_export_applies_rc_rewrites_on_cache_miss_and_hit() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let cache_dir = work_dir.path().join("cache");
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(
+        &config_file,
+        toml::to_string_pretty(&toml::toml! {
+            [[rc_rewrites]]
+            pattern = "FAKE_VAR=true"
+            replacement = "FAKE_VAR=rewritten"
+        })
+        .unwrap(),
+    )
+    .unwrap();
+
+    let new_path = write_fake_nix(&bin_dir, FakeNix::default());
 
+    let flake_reference = "github:owner/repo";
+    let export = || {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args(["shell", "export", "bash", "--progress", "--config-path"])
+            .arg(&config_file)
+            .arg("--cache-dir")
+            .arg(&cache_dir)
+            .args(["--flake-references", flake_reference])
+            .env("PATH", &new_path);
+        cmd.assert().success()
+    };
+
+    let cache_miss = export().stderr(predicate::str::contains("rebuilding"));
+    let cache_hit = export().stderr(predicate::str::contains("rebuilding").not());
+
+    let cache_miss_stdout = String::from_utf8(cache_miss.get_output().stdout.clone()).unwrap();
+    let cache_hit_stdout = String::from_utf8(cache_hit.get_output().stdout.clone()).unwrap();
+    assert!(cache_miss_stdout.starts_with("export FAKE_VAR=rewritten;\n"));
+    assert_eq!(cache_miss_stdout, cache_hit_stdout);
+}
+
+#[test]
+fn shell_export_with_ignore_version_check_warns_on_unparseable_version() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            version: "nix (Nix) pre-release",
+            ..FakeNix::default()
+        },
+    );
+
+    let export = |ignore_version_check: bool| {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args(["shell", "export", "bash", "--config-path"])
+            .arg(&config_file)
+            .arg("--cache-dir")
+            .arg(work_dir.path().join("cache"))
+            .args(["--flake-references", "github:owner/repo"])
+            .env("PATH", &new_path)
+            .env_remove("ENVOLUNTARY_IGNORE_VERSION_CHECK");
+        if ignore_version_check {
+            cmd.arg("--ignore-version-check");
+        }
+        cmd.assert()
+    };
+
+    export(false).failure().stderr(predicate::str::contains(
+        "SemVer from `nix --version` could not be found.",
+    ));
+    export(true)
+        .success()
+        .stdout(predicate::str::starts_with("export FAKE_VAR=true;\n"))
+        .stderr(predicate::str::contains(
+            "envoluntary: warning: ignoring failed nix version check: SemVer from `nix --version` \
+             could not be found.",
+        ));
+}
+
+#[test]
+fn shell_export_errors_when_nix_build_creates_no_gcroot() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let cache_dir = work_dir.path().join("cache");
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            on_build: "exit 0",
+            ..FakeNix::default()
+        },
+    );
+
+    let flake_reference = "github:owner/repo";
     let mut cmd = Command::new(cargo::cargo_bin!());
-    cmd.args(["shell", "export", "bash"]).env("PATH", new_path);
+    cmd.args(["shell", "export", "bash", "--config-path"])
+        .arg(&config_file)
+        .arg("--cache-dir")
+        .arg(&cache_dir)
+        .args(["--flake-references", flake_reference])
+        .env("PATH", new_path);
+
+    let profile_symlink = cache_dir
+        .join(format!("{:x}", Sha1::digest(flake_reference)))
+        .join(format!("flake-profile-{}", profile_hash(flake_reference)));
+    cmd.assert()
+        .failure()
+        .stdout(predicate::eq(""))
+        .stderr(predicate::str::contains(format!(
+            "`nix build` didn't create a gcroot at {}",
+            profile_symlink.display()
+        )));
+}
 
-    cmd.assert().success().stdout(predicate::eq(""));
+#[test]
+fn shell_export_flake_references_from_cli_env_and_file() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let cache_dir = work_dir.path().join("cache");
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+    let flake_references_file = work_dir.path().join("flake-references");
+    fs::write(&flake_references_file, "\ngithub:owner/from_file\n\n").unwrap();
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            print_dev_env: r#"echo "export LOADED_FLAKE=$installable;""#,
+            ..FakeNix::default()
+        },
+    );
+
+    let export = |flake_references: Option<&str>, env_flake: Option<&str>| {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args(["shell", "export", "bash", "--config-path"])
+            .arg(&config_file)
+            .arg("--cache-dir")
+            .arg(&cache_dir)
+            .arg("--flake-references-file")
+            .arg(&flake_references_file)
+            .env("PATH", &new_path)
+            .env_remove("ENVOLUNTARY_FLAKE");
+        if let Some(flake_reference) = flake_references {
+            cmd.args(["--flake-references", flake_reference]);
+        }
+        if let Some(flake_reference) = env_flake {
+            cmd.env("ENVOLUNTARY_FLAKE", flake_reference);
+        }
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    assert!(export(None, None).contains("export LOADED_FLAKE=$'github:owner/from_file';"));
+    assert!(
+        export(None, Some("github:owner/from_env"))
+            .contains("export LOADED_FLAKE=$'github:owner/from_env';")
+    );
+    assert!(
+        export(Some("github:owner/from_cli"), Some("github:owner/from_env"))
+            .contains("export LOADED_FLAKE=$'github:owner/from_cli';")
+    );
+}
+
+#[test]
+fn shell_export_with_no_input_gcroots_skips_flake_inputs() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    let log_file = work_dir.path().join("nix_commands.log");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+    let flake_dir = work_dir.path().join("flake");
+    fs::create_dir(&flake_dir).unwrap();
+    fs::write(flake_dir.join("flake.nix"), "{}").unwrap();
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            flake_archive_json: r#"{ "inputs": { "nixpkgs": { "inputs": {}, "path": "/nix/store/yfzmnk75f009yb7b542kf4r7qaqq9kid-source" } } }"#,
+            log_file: Some(&log_file),
+            ..FakeNix::default()
+        },
+    );
+
+    let flake_reference = format!("path:{}", flake_dir.display());
+    let export = |cache_dir: &str, no_input_gcroots: bool| {
+        fs::write(&log_file, "").unwrap();
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args(["shell", "export", "bash", "--config-path"])
+            .arg(&config_file)
+            .arg("--cache-dir")
+            .arg(work_dir.path().join(cache_dir))
+            .args(["--flake-references", &flake_reference])
+            .env("PATH", &new_path);
+        if no_input_gcroots {
+            cmd.arg("--no-input-gcroots");
+        }
+        cmd.assert().success();
+        fs::read_to_string(&log_file)
+            .unwrap()
+            .lines()
+            .map(|nix_command| nix_command.split(' ').next().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        export("cache", false),
+        [
+            "--version",
+            "--version",
+            "print-dev-env",
+            "build",
+            "flake",
+            "build"
+        ]
+    );
+    assert_eq!(
+        export("cache-no-input-gcroots", true),
+        ["--version", "--version", "print-dev-env", "build"]
+    );
+}
+
+#[test]
+fn shell_export_creates_all_flake_input_gcroots() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let cache_dir = work_dir.path().join("cache");
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+    let flake_dir = work_dir.path().join("flake");
+    fs::create_dir(&flake_dir).unwrap();
+    fs::write(flake_dir.join("flake.nix"), "{}").unwrap();
+
+    let input_names = (0..6)
+        .map(|i| format!("{i}{}-source", "a".repeat(31)))
+        .collect::<Vec<_>>();
+    let flake_archive_json = serde_json::json!({
+        "inputs": input_names
+            .iter()
+            .map(|input_name| {
+                (
+                    input_name.clone(),
+                    serde_json::json!({ "inputs": {}, "path": format!("/nix/store/{input_name}") }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>()
+    });
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            on_build: "sleep 0.1",
+            flake_archive_json: &flake_archive_json.to_string(),
+            ..FakeNix::default()
+        },
+    );
+
+    let flake_reference = format!("path:{}", flake_dir.display());
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args(["shell", "export", "bash", "--config-path"])
+        .arg(&config_file)
+        .arg("--cache-dir")
+        .arg(&cache_dir)
+        .args(["--flake-references", &flake_reference])
+        .env("PATH", new_path);
+    cmd.assert().success();
+
+    let flake_inputs_dir = cache_dir
+        .join(format!("{:x}", Sha1::digest(&flake_reference)))
+        .join("flake-inputs");
+    let mut gcroots = fs::read_dir(&flake_inputs_dir)
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            (
+                entry.file_name().into_string().unwrap(),
+                fs::read_link(entry.path()).unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    gcroots.sort();
+    assert_eq!(
+        gcroots,
+        input_names
+            .iter()
+            .map(|input_name| (input_name.clone(), Path::new("/nix/store").join(input_name)))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn shell_export_never_runs_more_nix_builds_than_jobs() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    let running_dir = work_dir.path().join("running");
+    fs::create_dir(&running_dir).unwrap();
+    let concurrency_log_file = work_dir.path().join("concurrency.log");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+    let flake_dir = work_dir.path().join("flake");
+    fs::create_dir(&flake_dir).unwrap();
+    fs::write(flake_dir.join("flake.nix"), "{}").unwrap();
+
+    let flake_archive_json = serde_json::json!({
+        "inputs": (0..8)
+            .map(|i| {
+                (
+                    format!("input{i}"),
+                    serde_json::json!({ "inputs": {}, "path": format!("/nix/store/{i}-source") }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>()
+    });
+    let on_build = format!(
+        r#"touch "{running_dir}/$$"
+    ls "{running_dir}" | wc -l >> "{concurrency_log_file}"
+    sleep 0.1
+    rm "{running_dir}/$$""#,
+        running_dir = running_dir.display(),
+        concurrency_log_file = concurrency_log_file.display(),
+    );
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            on_build: &on_build,
+            flake_archive_json: &flake_archive_json.to_string(),
+            ..FakeNix::default()
+        },
+    );
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args(["shell", "export", "bash", "--config-path"])
+        .arg(&config_file)
+        .arg("--cache-dir")
+        .arg(work_dir.path().join("cache"))
+        .args([
+            "--flake-references",
+            &format!("path:{}", flake_dir.display()),
+        ])
+        .args(["--jobs", "2"])
+        .env("PATH", new_path);
+    cmd.assert().success();
+
+    let concurrency_log = fs::read_to_string(&concurrency_log_file).unwrap();
+    let concurrency_counts = concurrency_log
+        .split_whitespace()
+        .map(|count| count.parse::<usize>().unwrap())
+        .collect::<Vec<_>>();
+    // NB: One build for the profile and one per input
+    assert_eq!(concurrency_counts.len(), 9);
+    assert!(concurrency_counts.iter().all(|count| *count <= 2));
+}
+
+#[test]
+fn shell_export_with_progress_reports_update_phases_to_stderr() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+    let flake_dir = work_dir.path().join("flake");
+    fs::create_dir(&flake_dir).unwrap();
+    fs::write(flake_dir.join("flake.nix"), "{}").unwrap();
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            flake_archive_json: r#"{ "inputs": { "a": { "inputs": {}, "path": "/nix/store/a-source" }, "b": { "inputs": {}, "path": "/nix/store/b-source" } } }"#,
+            ..FakeNix::default()
+        },
+    );
+
+    let export = |cache_dir: &str, progress: bool| {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args(["shell", "export", "bash", "--config-path"])
+            .arg(&config_file)
+            .arg("--cache-dir")
+            .arg(work_dir.path().join(cache_dir))
+            .args([
+                "--flake-references",
+                &format!("path:{}", flake_dir.display()),
+            ])
+            .env("PATH", &new_path)
+            .env_remove("ENVOLUNTARY_PROGRESS");
+        if progress {
+            cmd.arg("--progress");
+        }
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).starts_with("export FAKE_VAR=true;\n"));
+        String::from_utf8(output.stderr).unwrap()
+    };
+
+    let stderr = export("cache", true);
+    let (stderr, summary) = stderr.trim_end().rsplit_once('\n').unwrap();
+    assert_eq!(
+        stderr,
+        [
+            &format!(
+                "envoluntary: rebuilding path:{}: the profile hasn't been built",
+                flake_dir.display()
+            ),
+            "envoluntary: evaluating flake…",
+            "envoluntary: creating profile gcroot…",
+            "envoluntary: archiving flake inputs…",
+            "envoluntary: creating flake input gcroots 0/2…",
+            "envoluntary: creating flake input gcroots 1/2…",
+            "envoluntary: creating flake input gcroots 2/2…",
+        ]
+        .join("\n")
+    );
+    assert!(summary.starts_with("envoluntary: built profile "));
+    assert!(summary.contains(", rooted 2 flake inputs in "));
+    assert!(summary.ends_with('s'));
+    assert_eq!(export("cache-without-progress", false), "");
 }
 
 #[test]
@@ -110,48 +1200,14 @@ fn test_shell_export_state_init_update_and_reset() {
     )
     .unwrap();
     let bin_dir = work_dir.path().join("bin");
-    fs::create_dir(&bin_dir).unwrap();
-    let nix_file = bin_dir.join("nix");
 
-    let bash_path = env::var("NIX_BIN_BASH").unwrap_or_else(|_| String::from("/bin/bash"));
-    let profile_rc_content = "export FAKE_VAR=true;";
-    let nix_file_content = format!(
-        r#"#! {bash_path}
-
-if [[ "$@" == "--extra-experimental-features nix-command flakes --version" ]]; then
-    echo "nix (Nix) 2.30.0"
-elif [[ "$@" == "--extra-experimental-features nix-command flakes print-dev-env --no-write-lock-file --profile "* ]]; then
-rc="{profile_rc_content}"
-for ((i=0; i<$#; i++)); do
-    if [[ "${{@:$i:1}}" == "--profile" ]]; then
-        profile_path="${{@:$((i+1)):1}}"
-        echo "$rc" > "$profile_path"
-        break
-    fi
-done
-echo "$rc"
-elif [[ "$@" == "--extra-experimental-features nix-command flakes build --out-link "* ]]; then
-for ((i=0; i<$#; i++)); do
-    if [[ "${{@:$i:1}}" == "--out-link" ]]; then
-        link_path="${{@:$((i+1)):1}}"
-        installable="${{@:$((i+2)):1}}"
-        mkdir -p "$(dirname "$link_path")"
-        ln -sf "$installable" "$link_path"
-        break
-    fi
-done
-elif [[ "$@" == "--extra-experimental-features nix-command flakes flake archive --json --no-write-lock-file "* ]]; then
-echo '{{ "inputs": {{ "nixpkgs": {{ "inputs": {{}}, "path": "/nix/store/yfzmnk75f009yb7b542kf4r7qaqq9kid-source" }} }} }}'
-fi
-
-exit 0
-"#
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            flake_archive_json: r#"{ "inputs": { "nixpkgs": { "inputs": {}, "path": "/nix/store/yfzmnk75f009yb7b542kf4r7qaqq9kid-source" } } }"#,
+            ..FakeNix::default()
+        },
     );
-    fs::write(&nix_file, nix_file_content).unwrap();
-    fs::set_permissions(&nix_file, fs::Permissions::from_mode(0o755)).unwrap();
-
-    let original_path = env::var("PATH").unwrap_or_default();
-    let new_path = format!("{}:{}", bin_dir.display(), original_path);
 
     {
         let mut cmd = Command::new(cargo::cargo_bin!());
@@ -403,3 +1459,348 @@ exit 0
         );
     }
 }
+
+#[test]
+fn shell_export_with_no_cache_evaluates_without_writing() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let cache_dir = work_dir.path().join("cache");
+    let bin_dir = work_dir.path().join("bin");
+    let log_file = work_dir.path().join("nix_commands.log");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            log_file: Some(&log_file),
+            ..FakeNix::default()
+        },
+    );
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args(["shell", "export", "bash", "--no-cache", "--config-path"])
+        .arg(&config_file)
+        .arg("--cache-dir")
+        .arg(&cache_dir)
+        .args(["--flake-references", "github:owner/repo"])
+        .env("PATH", new_path)
+        .env_remove("ENVOLUNTARY_ENV_STATE");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("export FAKE_VAR=true;"));
+
+    assert!(!cache_dir.exists());
+    assert_eq!(
+        fs::read_to_string(&log_file)
+            .unwrap()
+            .lines()
+            .filter(|nix_command| *nix_command != "--version")
+            .collect::<Vec<_>>(),
+        ["print-dev-env --no-write-lock-file github:owner/repo"]
+    );
+}
+
+#[test]
+fn shell_export_with_cache_min_bytes_evaluates_small_flakes_fresh() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let cache_dir = work_dir.path().join("cache");
+    let flake_dir = work_dir.path().join("flake");
+    fs::create_dir(&flake_dir).unwrap();
+    fs::write(flake_dir.join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            print_dev_env: r#"if [[ -n "$profile_path" ]]; then
+        echo "export FAKE_VAR=cached;"
+    else
+        echo "export FAKE_VAR=fresh;"
+    fi"#,
+            ..FakeNix::default()
+        },
+    );
+
+    let export = |cache_min_bytes: &str| {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args([
+            "shell",
+            "export",
+            "bash",
+            "--cache-min-bytes",
+            cache_min_bytes,
+        ])
+        .arg("--config-path")
+        .arg(&config_file)
+        .arg("--cache-dir")
+        .arg(&cache_dir)
+        .arg("--flake-references")
+        .arg(&flake_dir)
+        .args(["--no-input-gcroots"])
+        .env("PATH", &new_path)
+        .env_remove("ENVOLUNTARY_ENV_STATE");
+        cmd.assert()
+    };
+
+    export("1024")
+        .success()
+        .stdout(predicate::str::contains("export FAKE_VAR=fresh;"));
+    assert!(!cache_dir.exists());
+
+    export("1")
+        .success()
+        .stdout(predicate::str::contains("export FAKE_VAR=cached;"));
+    assert!(cache_dir.exists());
+}
+
+#[test]
+fn shell_export_with_emit_dotenv_writes_profile_env_vars() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+    let dotenv_file = work_dir.path().join(".env");
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            print_dev_env: r#"echo "export FAKE_VAR=true GREETING='hello world';""#,
+            ..FakeNix::default()
+        },
+    );
+
+    let mut cmd = Command::new(cargo::cargo_bin!());
+    cmd.args(["shell", "export", "bash", "--emit-dotenv"])
+        .arg(&dotenv_file)
+        .arg("--config-path")
+        .arg(&config_file)
+        .arg("--cache-dir")
+        .arg(work_dir.path().join("cache"))
+        .args(["--flake-references", "github:owner/repo"])
+        .env("PATH", new_path);
+    cmd.assert().success().stdout("");
+
+    let dotenv_content = fs::read_to_string(&dotenv_file).unwrap();
+    assert!(dotenv_content.contains("FAKE_VAR=true\n"));
+    assert!(dotenv_content.contains("GREETING='hello world'\n"));
+}
+
+#[test]
+fn shell_export_applies_config_options_unless_overridden() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(
+        &config_file,
+        toml::to_string_pretty(&toml::toml! {
+            [options]
+            exclude_env_vars = ["CONFIG_EXCLUDED"]
+            progress = true
+        })
+        .unwrap(),
+    )
+    .unwrap();
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            print_dev_env: r"printf 'export CONFIG_EXCLUDED=1;\nexport CLI_EXCLUDED=1;\n'",
+            ..FakeNix::default()
+        },
+    );
+
+    let export = |extra_args: &[&str]| {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args(["shell", "export", "json", "--config-path"])
+            .arg(&config_file)
+            .arg("--cache-dir")
+            .arg(work_dir.path().join("cache"))
+            .args(["--flake-references", "github:owner/repo"])
+            .args(extra_args)
+            .env("PATH", &new_path)
+            .env_remove("ENVOLUNTARY_EXCLUDE_ENV_VARS");
+        let output = cmd.assert().success().get_output().clone();
+        (
+            serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap(),
+        )
+    };
+
+    let (exported, stderr) = export(&[]);
+    assert_eq!(exported.get("CONFIG_EXCLUDED"), None);
+    assert_eq!(exported["CLI_EXCLUDED"], "1");
+    assert!(stderr.contains("envoluntary: rebuilding github:owner/repo"));
+
+    let (exported, _) = export(&["--exclude-env-vars", "CLI_EXCLUDED"]);
+    assert_eq!(exported["CONFIG_EXCLUDED"], "1");
+    assert_eq!(exported.get("CLI_EXCLUDED"), None);
+
+    fs::write(&config_file, "[options]\ncache_ttl = \"soon\"\n").unwrap();
+    Command::new(cargo::cargo_bin!())
+        .args(["shell", "export", "json", "--config-path"])
+        .arg(&config_file)
+        .arg("--cache-dir")
+        .arg(work_dir.path().join("cache"))
+        .args(["--flake-references", "github:owner/repo"])
+        .env("PATH", &new_path)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid `cache_ttl` in config"));
+}
+
+#[test]
+fn shell_export_with_fingerprint_env_prints_nothing_until_env_drifts() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+
+    let new_path = write_fake_nix(&bin_dir, FakeNix::default());
+
+    let export = |env_vars: &EnvVars| {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args([
+            "shell",
+            "export",
+            "bash",
+            "--fingerprint-env",
+            "--config-path",
+        ])
+        .arg(&config_file)
+        .arg("--cache-dir")
+        .arg(work_dir.path().join("cache"))
+        .args(["--flake-references", "github:owner/repo"])
+        .env_remove("ENVOLUNTARY_ENV_STATE")
+        .env_remove("FAKE_VAR")
+        .envs(env_vars.iter())
+        .env("PATH", &new_path);
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let apply = |shell_export: String| {
+        get_env_vars_from_bash(
+            BashSource::Script(shell_export.into()),
+            Some(EnvVars::from_iter([("PATH".to_string(), new_path.clone())])),
+        )
+        .unwrap()
+    };
+
+    let initial_shell_export = export(&EnvVars::new());
+    assert!(initial_shell_export.contains("export FAKE_VAR=true;"));
+    let mut env_vars = apply(initial_shell_export);
+
+    assert_eq!(export(&env_vars), "");
+
+    env_vars.insert(String::from("FAKE_VAR"), String::from("clobbered"));
+    let drifted_shell_export = export(&env_vars);
+    assert!(drifted_shell_export.contains("unset FAKE_VAR;"));
+    assert!(drifted_shell_export.contains("export FAKE_VAR=true;"));
+}
+
+#[test]
+fn shell_export_with_impure_forwards_it_and_caches_separately() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let cache_dir = work_dir.path().join("cache");
+    let bin_dir = work_dir.path().join("bin");
+    let log_file = work_dir.path().join("nix_commands.log");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "").unwrap();
+
+    let new_path = write_fake_nix(
+        &bin_dir,
+        FakeNix {
+            log_file: Some(&log_file),
+            ..FakeNix::default()
+        },
+    );
+
+    let flake_reference = "github:owner/repo";
+    let cache_sub_dir = cache_dir.join(format!("{:x}", Sha1::digest(flake_reference)));
+    let impure_profile_hash = format!(
+        "{:x}",
+        Sha1::digest(format!(
+            "envoluntary-{}\0impure{flake_reference}",
+            env!("CARGO_PKG_VERSION")
+        ))
+    );
+    for (impure_args, hash) in [
+        (&["--impure"][..], impure_profile_hash),
+        (&[], profile_hash(flake_reference)),
+    ] {
+        let mut cmd = Command::new(cargo::cargo_bin!());
+        cmd.args(["shell", "export", "bash", "--config-path"])
+            .arg(&config_file)
+            .arg("--cache-dir")
+            .arg(&cache_dir)
+            .args(["--flake-references", flake_reference])
+            .args(impure_args)
+            .env("PATH", &new_path)
+            .env_remove("ENVOLUNTARY_ENV_STATE");
+        cmd.assert().success();
+        assert!(
+            cache_sub_dir
+                .join(format!("flake-profile-{hash}"))
+                .is_symlink()
+        );
+    }
+
+    // NB: The pure profile didn't reuse the impure one, so it was evaluated again
+    assert_eq!(
+        fs::read_to_string(&log_file)
+            .unwrap()
+            .lines()
+            .filter_map(|nix_command| nix_command.strip_prefix("print-dev-env "))
+            .map(|args| args.split(' ').next().unwrap())
+            .collect::<Vec<_>>(),
+        ["--impure", "--no-write-lock-file"]
+    );
+}
+
+#[test]
+fn shell_print_profile_path_matches_export_with_profile_hash_options() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let cache_dir = work_dir.path().join("cache");
+    let bin_dir = work_dir.path().join("bin");
+    let config_file = work_dir.path().join("config.toml");
+    fs::write(&config_file, "[options]\nnormalize_rc = true\n").unwrap();
+
+    let new_path = write_fake_nix(&bin_dir, FakeNix::default());
+
+    let flake_reference = "github:owner/repo";
+    let profile_hash_args = ["--impure", "--print-dev-env-arg=--refresh"];
+
+    let mut export_cmd = Command::new(cargo::cargo_bin!());
+    export_cmd
+        .args(["shell", "export", "bash", "--prebuild", "--config-path"])
+        .arg(&config_file)
+        .arg("--cache-dir")
+        .arg(&cache_dir)
+        .args(["--flake-references", flake_reference])
+        .args(profile_hash_args)
+        .env("PATH", &new_path);
+    export_cmd.assert().success();
+
+    let mut print_profile_path_cmd = Command::new(cargo::cargo_bin!());
+    print_profile_path_cmd
+        .args(["shell", "print-profile-path", "--config-path"])
+        .arg(&config_file)
+        .arg("--cache-dir")
+        .arg(&cache_dir)
+        .args(["--flake-reference", flake_reference])
+        .args(profile_hash_args);
+    let output = print_profile_path_cmd.output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "envoluntary: profile exists\n"
+    );
+    let profile_path = String::from_utf8(output.stdout).unwrap();
+    assert!(Path::new(profile_path.trim_end()).is_symlink());
+    assert_ne!(
+        Path::new(profile_path.trim_end()).file_name().unwrap(),
+        OsStr::new(&format!("flake-profile-{}", profile_hash(flake_reference)))
+    );
+}
@@ -8,9 +8,9 @@ use std::{
 use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
 use bstr::B;
 use env_hooks::{
-    BashSource, EnvVars, EnvVarsState, get_env_vars_from_bash, get_env_vars_from_current_process,
-    get_env_vars_reset, get_old_env_vars_to_be_updated, merge_delimited_env_var,
-    remove_ignored_env_vars, shells,
+    BashEnvExtractor, BashSource, EnvBase, EnvVars, EnvVarsState,
+    get_env_vars_from_current_process, get_env_vars_reset, get_old_env_vars_to_be_updated,
+    merge_delimited_env_var, remove_ignored_env_vars, shells,
     state::{self, GetEnvStateVar, MatchRcs},
 };
 use flate2::{Compression, read::ZlibDecoder, write::ZlibEncoder};
@@ -220,7 +220,11 @@ fn get_new_env_vars(envrc: PathBuf) -> anyhow::Result<EnvVarUpdates> {
     bash_env_vars.insert(String::from("DIRENV_IN_ENVRC"), String::from("1"));
 
     let direnv_file = String::from(envrc.to_string_lossy());
-    let mut new_env_vars = get_env_vars_from_bash(BashSource::File(envrc), Some(bash_env_vars))?;
+    let mut extractor = BashEnvExtractor::new().env_base(EnvBase::Vars(bash_env_vars));
+    if let Some(envrc_dir) = envrc.parent() {
+        extractor = extractor.current_dir(envrc_dir);
+    }
+    let mut new_env_vars = extractor.run(BashSource::File(envrc))?;
     new_env_vars.insert(String::from(DIRENV_FILE_VAR_KEY), direnv_file);
     remove_ignored_env_vars(&mut new_env_vars);
     if new_env_vars.get(ENV_VAR_KEY_PATH) == old_path.as_ref() {
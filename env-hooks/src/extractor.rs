@@ -0,0 +1,464 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use bstr::{B, ByteSlice};
+use duct::cmd;
+use shell_quote::Bash;
+
+use crate::{
+    BashSource, EnvVars, EnvVarsInner, LineEndings, SimplifiedExitOk,
+    get_env_vars_from_current_process, normalize_line_endings, remove_env_vars_with_prefix,
+    strip_dangerous_env_vars,
+};
+
+/// The environment that the bash process sourcing a [`BashSource`] starts from.
+#[derive(Debug, Clone, Default)]
+pub enum EnvBase {
+    /// Start from an empty environment.
+    #[default]
+    Empty,
+    /// Start from the environment of the current process, without the env vars with
+    /// [`BashEnvExtractor::excluded_base_prefix`].
+    Inherit,
+    /// Start from [`minimal_base`], leaking as little of the host environment as possible.
+    ///
+    /// The interpreter is then looked up on the minimal `PATH`, so may need to be set with
+    /// [`BashEnvExtractor::interpreter`], e.g. on NixOS.
+    Minimal,
+    /// Start from exactly these env vars.
+    Vars(EnvVars),
+}
+
+impl EnvBase {
+    fn env_vars(&self) -> EnvVars {
+        match self {
+            Self::Empty => EnvVars::new(),
+            Self::Inherit => get_env_vars_from_current_process(),
+            Self::Minimal => minimal_base(),
+            Self::Vars(env_vars) => env_vars.clone(),
+        }
+    }
+}
+
+/// The env vars of the current process that are kept by [`minimal_base`].
+const MINIMAL_BASE_KEYS: &[&str] = &["HOME", "USER", "LOGNAME", "TERM", "LANG", "TZ"];
+
+/// A curated, minimal environment for reproducible sourcing: the current process's user, locale
+/// and terminal env vars, and `PATH=/usr/bin:/bin`.
+pub fn minimal_base() -> EnvVars {
+    let mut env_vars = get_env_vars_from_current_process();
+    env_vars.retain(|key, _| MINIMAL_BASE_KEYS.contains(&key.as_str()));
+    env_vars.insert(String::from("PATH"), String::from("/usr/bin:/bin"));
+    env_vars
+}
+
+/// Sources a [`BashSource`] in bash and captures the resulting env vars.
+///
+/// ```no_run
+/// use env_hooks::{BashEnvExtractor, BashSource, EnvBase, LineEndings};
+///
+/// let env_vars = BashEnvExtractor::new()
+///     .env_base(EnvBase::Inherit)
+///     .line_endings(LineEndings::StripTrailingCr)
+///     .run(BashSource::File(".envrc".into()))?;
+/// # anyhow::Ok(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct BashEnvExtractor {
+    interpreter: OsString,
+    env_base: EnvBase,
+    line_endings: LineEndings,
+    current_dir: Option<PathBuf>,
+    allow_failure: bool,
+    xtrace: bool,
+    strict: bool,
+    rc_path_var: Option<String>,
+    excluded_base_prefix: Option<String>,
+    capture_umask: bool,
+    errexit: bool,
+}
+
+/// The env vars captured by [`BashEnvExtractor::run_with_output`], along with how sourcing exited.
+#[derive(Debug, Clone)]
+pub struct BashEnvOutput {
+    pub env_vars: EnvVars,
+    /// The exit code of the sourcing process, `None` if it was terminated by a signal.
+    pub exit_code: Option<i32>,
+    /// The `set -x` trace of sourcing, only captured when [`BashEnvExtractor::xtrace`] is enabled.
+    pub xtrace: Option<String>,
+    /// The dangerous env vars reverted in strict mode, see [`BashEnvExtractor::strict`].
+    pub stripped_env_vars: Vec<String>,
+    /// The umask the source left, only captured when [`BashEnvExtractor::capture_umask`] is
+    /// enabled.
+    pub umask: Option<u32>,
+}
+
+/// Reported by [`BashEnvExtractor::run_sources`] around sourcing each [`BashSource`].
+pub enum SourcingEvent<'a> {
+    /// The source is about to be sourced.
+    Started(&'a BashSource),
+    /// The source was sourced, with the env vars it left or the error sourcing it.
+    Finished(&'a BashSource, &'a anyhow::Result<EnvVars>),
+}
+
+impl Default for BashEnvExtractor {
+    fn default() -> Self {
+        Self {
+            interpreter: OsString::from("bash"),
+            env_base: EnvBase::default(),
+            line_endings: LineEndings::default(),
+            current_dir: None,
+            allow_failure: false,
+            xtrace: false,
+            strict: false,
+            rc_path_var: None,
+            excluded_base_prefix: None,
+            capture_umask: false,
+            errexit: false,
+        }
+    }
+}
+
+impl BashEnvExtractor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The bash-compatible program used to source, `bash` (looked up on `$PATH`) by default.
+    pub fn interpreter(mut self, interpreter: impl AsRef<OsStr>) -> Self {
+        self.interpreter = interpreter.as_ref().to_os_string();
+        self
+    }
+
+    pub fn env_base(mut self, env_base: EnvBase) -> Self {
+        self.env_base = env_base;
+        self
+    }
+
+    pub fn line_endings(mut self, line_endings: LineEndings) -> Self {
+        self.line_endings = line_endings;
+        self
+    }
+
+    /// The working directory to source from, the current working directory by default.
+    ///
+    /// Useful for rc files that assume they're sourced from their own directory.
+    pub fn current_dir(mut self, current_dir: impl AsRef<Path>) -> Self {
+        self.current_dir = Some(current_dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Whether sourcing may exit non-zero, `false` by default.
+    ///
+    /// When allowed, whatever env vars were set before the failure are still captured and the
+    /// exit code is reported through [`BashEnvOutput`], leaving the caller to decide whether to
+    /// use them.
+    pub fn allow_failure(mut self, allow_failure: bool) -> Self {
+        self.allow_failure = allow_failure;
+        self
+    }
+
+    /// Whether to trace sourcing with `set -x`, `false` by default.
+    ///
+    /// The trace is captured separately from stderr and returned in [`BashEnvOutput::xtrace`],
+    /// which helps when debugging an rc file that does something unexpected.
+    pub fn xtrace(mut self, xtrace: bool) -> Self {
+        self.xtrace = xtrace;
+        self
+    }
+
+    /// Whether to strip the [`DANGEROUS_ENV_VAR_KEYS`](crate::DANGEROUS_ENV_VAR_KEYS) that the
+    /// source set or changed, e.g. `LD_PRELOAD`, `false` by default.
+    ///
+    /// For capturing an untrusted environment. The stripped keys are reported through
+    /// [`BashEnvOutput::stripped_env_vars`], so callers can warn about them.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// The env var set to the path of a sourced [`BashSource::File`] while it's sourced, e.g. to
+    /// locate sibling files, or `None` (the default) to not set one.
+    ///
+    /// The env var isn't captured, unless the source changed it, and isn't set for paths that
+    /// aren't UTF-8.
+    pub fn rc_path_var(mut self, rc_path_var: Option<&str>) -> Self {
+        self.rc_path_var = rc_path_var.map(String::from);
+        self
+    }
+
+    /// The prefix of env vars left out of [`EnvBase::Inherit`], or `None` (the default) to
+    /// inherit everything.
+    ///
+    /// Keeps the current process's own control env vars (e.g. `MYTOOL_`) from leaking into the
+    /// sourcing process and back into the captured env vars.
+    pub fn excluded_base_prefix(mut self, excluded_base_prefix: Option<&str>) -> Self {
+        self.excluded_base_prefix = excluded_base_prefix.map(String::from);
+        self
+    }
+
+    /// Whether to also capture the umask the source left, `false` by default.
+    ///
+    /// A umask isn't an env var, but some dev environments set one, so it's returned in
+    /// [`BashEnvOutput::umask`] for callers to re-apply.
+    pub fn capture_umask(mut self, capture_umask: bool) -> Self {
+        self.capture_umask = capture_umask;
+        self
+    }
+
+    /// Whether to source under `set -euo pipefail`, `false` by default.
+    ///
+    /// A command failing midway through the source then fails sourcing, rather than capturing a
+    /// half-set environment. Off by default since some rc files rely on bash being lenient.
+    pub fn errexit(mut self, errexit: bool) -> Self {
+        self.errexit = errexit;
+        self
+    }
+
+    pub fn run(&self, source: impl AsRef<BashSource>) -> anyhow::Result<EnvVars> {
+        Ok(self.run_with_output(source)?.env_vars)
+    }
+
+    /// Sources each of `sources` in turn, each starting from the env vars left by the one before,
+    /// stopping at the first that fails.
+    ///
+    /// `on_sourcing_event` is called before and after each source, e.g. to show progress, and
+    /// errors say which source failed.
+    pub fn run_sources<S: AsRef<BashSource>>(
+        &self,
+        sources: impl IntoIterator<Item = S>,
+        mut on_sourcing_event: impl FnMut(SourcingEvent<'_>),
+    ) -> anyhow::Result<EnvVars> {
+        let mut env_vars = self.base_env_vars();
+        for source in sources {
+            let source = source.as_ref();
+            on_sourcing_event(SourcingEvent::Started(source));
+            let result = self
+                .clone()
+                .env_base(EnvBase::Vars(env_vars))
+                .run(source)
+                .map_err(|e| e.context(format!("error sourcing {source}")));
+            on_sourcing_event(SourcingEvent::Finished(source, &result));
+            env_vars = result?;
+        }
+        Ok(env_vars)
+    }
+
+    pub fn run_with_output(&self, source: impl AsRef<BashSource>) -> anyhow::Result<BashEnvOutput> {
+        let bash_env_vars_file = tempfile::NamedTempFile::new()?;
+
+        let umask_file = self
+            .capture_umask
+            .then(tempfile::NamedTempFile::new)
+            .transpose()?;
+        let mut dump_env_vars = bstr::join(
+            " ",
+            [B("env -0 >"), &Bash::quote_vec(bash_env_vars_file.path())],
+        );
+        if let Some(umask_file) = &umask_file {
+            dump_env_vars = bstr::join(
+                " ",
+                [
+                    &dump_env_vars,
+                    B("&& umask >"),
+                    &Bash::quote_vec(umask_file.path()),
+                ],
+            );
+        }
+        let mut command_string = if self.allow_failure {
+            // Dump from an EXIT trap so the env is captured even if the source calls `exit`.
+            bstr::join(
+                " ",
+                [
+                    B("trap"),
+                    &Bash::quote_vec(&dump_env_vars),
+                    B("EXIT;"),
+                    &source.as_ref().to_command_string(),
+                ],
+            )
+        } else {
+            // NB: Bash ignores `set -e` on the left of `&&`, so the dump has to follow separately
+            bstr::join(
+                " ",
+                [
+                    source.as_ref().to_command_string().as_bytes(),
+                    if self.errexit { B(";") } else { B("&&") },
+                    dump_env_vars.as_bytes(),
+                ],
+            )
+        };
+        if self.errexit {
+            command_string = bstr::join(" ", [B("set -euo pipefail;"), &command_string]);
+        }
+        let xtrace_file = self.xtrace.then(tempfile::NamedTempFile::new).transpose()?;
+        if let Some(xtrace_file) = &xtrace_file {
+            // Bash allocates a fresh fd for the trace, keeping it out of the script's stderr.
+            command_string = bstr::join(
+                " ",
+                [
+                    B("exec {BASH_XTRACEFD}>"),
+                    &Bash::quote_vec(xtrace_file.path()),
+                    B("; set -x;"),
+                    &command_string,
+                ],
+            );
+        }
+        let base_env_vars = self.base_env_vars();
+        // NB: Captured env vars must be UTF-8, so other paths aren't passed on
+        let rc_path_env_var = match (&self.rc_path_var, source.as_ref()) {
+            (Some(rc_path_var), BashSource::File(path)) => {
+                path.to_str().map(|path| (rc_path_var, path))
+            }
+            _ => None,
+        };
+        let mut expression = cmd!(&self.interpreter, "-c", command_string.to_os_str()?)
+            .full_env(
+                base_env_vars
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.as_str()))
+                    .chain(rc_path_env_var.map(|(key, path)| (key.as_str(), path))),
+            )
+            .stdout_to_stderr()
+            .unchecked();
+        if let Some(current_dir) = &self.current_dir {
+            expression = expression.dir(current_dir);
+        }
+        let handle = expression.start().map_err(|e| {
+            if e.kind() == io::ErrorKind::NotFound {
+                anyhow::format_err!(
+                    "{} not found on PATH; cannot source {}",
+                    self.interpreter.display(),
+                    source.as_ref()
+                )
+            } else {
+                e.into()
+            }
+        })?;
+        let output = handle.wait()?;
+        if !self.allow_failure {
+            output.status.simplified_exit_ok().map_err(|e| {
+                if self.errexit {
+                    anyhow::format_err!(
+                        "Bash command to retrieve env vars failed under `set -euo pipefail`:\n{e}"
+                    )
+                } else {
+                    anyhow::format_err!("Bash command to retrieve env vars failed:\n{e}")
+                }
+            })?;
+        }
+
+        let mut bash_env_vars = EnvVars(
+            NulDelimitedEnvVars::new(BufReader::new(fs::File::open(bash_env_vars_file.path())?))
+                .collect::<io::Result<EnvVarsInner>>()?,
+        );
+        normalize_line_endings(&mut bash_env_vars, self.line_endings);
+        if let Some((rc_path_var, path)) = rc_path_env_var
+            && bash_env_vars.get(rc_path_var).map(String::as_str) == Some(path)
+        {
+            match base_env_vars.get(rc_path_var) {
+                Some(base_value) => {
+                    bash_env_vars.insert(rc_path_var.clone(), base_value.clone());
+                }
+                None => {
+                    bash_env_vars.shift_remove(rc_path_var);
+                }
+            }
+        }
+        let stripped_env_vars = if self.strict {
+            strip_dangerous_env_vars(&mut bash_env_vars, &base_env_vars)
+        } else {
+            Vec::new()
+        };
+
+        Ok(BashEnvOutput {
+            env_vars: bash_env_vars,
+            exit_code: output.status.code(),
+            xtrace: xtrace_file
+                .map(|xtrace_file| fs::read_to_string(xtrace_file.path()))
+                .transpose()?,
+            stripped_env_vars,
+            umask: umask_file
+                .map(|umask_file| -> anyhow::Result<u32> {
+                    let umask = fs::read_to_string(umask_file.path())?;
+                    u32::from_str_radix(umask.trim(), 8)
+                        .map_err(|e| anyhow::format_err!("Couldn't parse umask {umask:?}: {e}"))
+                })
+                .transpose()?,
+        })
+    }
+
+    fn base_env_vars(&self) -> EnvVars {
+        let mut env_vars = self.env_base.env_vars();
+        if let (EnvBase::Inherit, Some(excluded_base_prefix)) =
+            (&self.env_base, &self.excluded_base_prefix)
+        {
+            remove_env_vars_with_prefix(&mut env_vars, excluded_base_prefix);
+        }
+        env_vars
+    }
+}
+
+/// Lazily parses the NUL-delimited `KEY=value` entries printed by `env -0`, so large environments
+/// aren't read into memory all at once.
+///
+/// Entries without a `=` are skipped.
+#[derive(Debug)]
+pub struct NulDelimitedEnvVars<R> {
+    reader: R,
+    entry: Vec<u8>,
+}
+
+impl<R: BufRead> NulDelimitedEnvVars<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            entry: vec![],
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for NulDelimitedEnvVars<R> {
+    type Item = io::Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.entry.clear();
+            match self.reader.read_until(b'\0', &mut self.entry) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            if self.entry.last() == Some(&b'\0') {
+                self.entry.pop();
+            }
+            let entry = match str::from_utf8(&self.entry) {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(io::Error::new(io::ErrorKind::InvalidData, e))),
+            };
+            if let Some((key, value)) = entry.split_once('=') {
+                return Some(Ok((String::from(key), String::from(value))));
+            }
+        }
+    }
+}
+
+/// Parses NUL-delimited `KEY=value` entries, as printed by `env -0` or found in
+/// `/proc/<pid>/environ`, see [`NulDelimitedEnvVars`].
+///
+/// Errors if an entry isn't UTF-8; see [`parse_env0_str`] for input that's already a string.
+pub fn parse_env0(bytes: &[u8]) -> io::Result<EnvVars> {
+    NulDelimitedEnvVars::new(bytes).collect()
+}
+
+/// Parses NUL-delimited `KEY=value` entries from a string, see [`parse_env0`].
+pub fn parse_env0_str(content: &str) -> EnvVars {
+    content
+        .split('\0')
+        .filter_map(|entry| entry.split_once('='))
+        .map(|(key, value)| (String::from(key), String::from(value)))
+        .collect()
+}
@@ -1,18 +1,25 @@
+mod extractor;
+#[cfg(feature = "powershell")]
+mod powershell;
 pub mod shells;
 pub mod state;
 
+pub use extractor::*;
+#[cfg(feature = "powershell")]
+pub use powershell::*;
+
 use std::{
-    collections::HashSet,
-    env, fs, num,
+    collections::{BTreeSet, HashMap, HashSet},
+    env, fmt, num,
     ops::{Deref, DerefMut},
     path::PathBuf,
     process::ExitStatus,
 };
 
-use bstr::{B, BString, ByteSlice};
-use duct::cmd;
+use bstr::{B, BString};
 use indexmap::{IndexMap, IndexSet, map::IntoIter};
 use once_cell::sync::Lazy;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use shell_quote::Bash;
 
@@ -151,10 +158,38 @@ pub fn get_env_vars_reset(
     env_vars_state
 }
 
+/// Like [`get_env_vars_reset`], but only unsets the env vars that were added, leaving the ones
+/// that were updated with their new values.
+pub fn get_env_vars_soft_reset(
+    old_env_vars_that_were_updated: &EnvVars,
+    new_env_vars: HashSet<String>,
+    env_state_var_key: String,
+) -> EnvVarsState {
+    let mut env_vars_state = new_env_vars
+        .into_iter()
+        .filter(|key| !old_env_vars_that_were_updated.contains_key(key))
+        .map(|key| (key, None))
+        .collect::<EnvVarsState>();
+    env_vars_state.insert(env_state_var_key, None);
+    env_vars_state
+}
+
 pub fn get_env_vars_from_current_process() -> EnvVars {
     EnvVars(env::vars().collect::<EnvVarsInner>())
 }
 
+/// Reads the env vars of a running process from `/proc/<pid>/environ`, without spawning anything,
+/// e.g. to inspect the parent shell.
+///
+/// This is the environment the process started with; changes it made since aren't visible.
+#[cfg(target_os = "linux")]
+pub fn get_env_vars_from_pid(pid: u32) -> anyhow::Result<EnvVars> {
+    let environ_file = format!("/proc/{pid}/environ");
+    let environ = std::fs::read(&environ_file)
+        .map_err(|e| anyhow::format_err!("Couldn't read {environ_file}: {e}"))?;
+    Ok(parse_env0(&environ)?)
+}
+
 pub enum BashSource {
     File(PathBuf),
     Script(BString),
@@ -166,6 +201,15 @@ impl AsRef<BashSource> for BashSource {
     }
 }
 
+impl fmt::Display for BashSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Script(_) => write!(f, "script"),
+        }
+    }
+}
+
 impl BashSource {
     fn to_command_string(&self) -> BString {
         match &self {
@@ -197,37 +241,64 @@ pub fn get_env_vars_from_bash(
     source: impl AsRef<BashSource>,
     env_vars: Option<EnvVars>,
 ) -> anyhow::Result<EnvVars> {
-    let bash_env_vars_file = tempfile::NamedTempFile::new()?;
-
-    let command_string = bstr::join(
-        " ",
-        [
-            &source.as_ref().to_command_string(),
-            B("&& env -0 >"),
-            &Bash::quote_vec(bash_env_vars_file.path()),
-        ],
-    );
-    let handle = cmd!("bash", "-c", command_string.to_os_str()?)
-        .full_env(env_vars.unwrap_or_default())
-        .stdout_to_stderr()
-        .start()?;
-    let output = handle.wait()?;
-    output
-        .status
-        .simplified_exit_ok()
-        .map_err(|e| anyhow::format_err!("Bash command to retrieve env vars failed:\n{e}"))?;
-
-    let bash_env_vars_string = fs::read_to_string(bash_env_vars_file.path())?;
-
-    let bash_env_vars = EnvVars(
-        bash_env_vars_string
-            .split('\0')
-            .filter_map(|env_var| env_var.split_once('='))
-            .map(|(key, value)| (String::from(key), String::from(value)))
-            .collect::<EnvVarsInner>(),
-    );
-
-    Ok(bash_env_vars)
+    BashEnvExtractor::new()
+        .env_base(env_vars.map(EnvBase::Vars).unwrap_or_default())
+        .run(source)
+}
+
+/// The keys of the env vars `source` sets, without their (possibly huge or sensitive) values,
+/// besides the [ignored](ignored_env_var_key) ones bash sets itself, e.g. for completions.
+pub fn capture_env_keys(source: impl AsRef<BashSource>) -> anyhow::Result<BTreeSet<String>> {
+    let mut env_vars = get_env_vars_from_bash(source, None)?;
+    remove_ignored_env_vars(&mut env_vars);
+    Ok(env_vars.0.into_keys().collect())
+}
+
+/// Sources `source` in bash on top of `base`, or the current process's env vars if not provided,
+/// returning how it changes them, besides the [ignored](ignored_env_var_key) ones bash sets itself.
+pub fn bash_env_diff(
+    source: impl AsRef<BashSource>,
+    base: Option<EnvVars>,
+) -> anyhow::Result<EnvDiff> {
+    let mut base = base.unwrap_or_else(get_env_vars_from_current_process);
+    let mut env_vars = get_env_vars_from_bash(source, Some(base.clone()))?;
+    remove_ignored_env_vars(&mut base);
+    remove_ignored_env_vars(&mut env_vars);
+    Ok(EnvDiff::new(&base, &env_vars))
+}
+
+/// How carriage returns in captured environment variable values are handled.
+///
+/// Values exported by tools that originated on Windows can end in `\r\n`, which breaks the
+/// generated shell exports. Nothing is changed by default, since a `\r` can be legitimate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineEndings {
+    /// Keep values exactly as they were captured.
+    #[default]
+    Keep,
+    /// Strip a single trailing `\r` from each value.
+    StripTrailingCr,
+    /// Convert every `\r\n` in each value to `\n` and strip a trailing `\r`.
+    NormalizeCrlf,
+}
+
+/// Rewrites the values of `env_vars` in place according to `line_endings`.
+///
+/// [`LineEndings::Keep`] leaves every value untouched. [`LineEndings::StripTrailingCr`] removes a
+/// single `\r` from the end of each value, leaving any other `\r` alone.
+/// [`LineEndings::NormalizeCrlf`] additionally turns every `\r\n` inside a value into `\n`.
+pub fn normalize_line_endings(env_vars: &mut EnvVars, line_endings: LineEndings) {
+    if line_endings == LineEndings::Keep {
+        return;
+    }
+    env_vars.values_mut().for_each(|value| {
+        if line_endings == LineEndings::NormalizeCrlf && value.contains("\r\n") {
+            *value = value.replace("\r\n", "\n");
+        }
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    });
 }
 
 pub fn merge_delimited_env_var(
@@ -259,6 +330,81 @@ pub fn merge_delimited_values(
         .join(&join_delimiter.to_string())
 }
 
+/// A registry of env vars holding delimited lists, e.g. `PATH`, and their delimiters, for merging
+/// them all between old and new env vars in one call.
+///
+/// The default registry covers the common `PATH`-like vars.
+#[derive(Debug, Clone)]
+pub struct DelimitedEnvVars(IndexMap<String, char>);
+
+impl Default for DelimitedEnvVars {
+    fn default() -> Self {
+        let cmake_delimiter = if cfg!(windows) { ';' } else { ':' };
+        Self(IndexMap::from_iter(
+            [
+                ("PATH", ':'),
+                ("MANPATH", ':'),
+                ("INFOPATH", ':'),
+                ("PKG_CONFIG_PATH", ':'),
+                ("XDG_DATA_DIRS", ':'),
+                ("XDG_CONFIG_DIRS", ':'),
+                ("CMAKE_PREFIX_PATH", cmake_delimiter),
+            ]
+            .map(|(key, delimiter)| (String::from(key), delimiter)),
+        ))
+    }
+}
+
+impl DelimitedEnvVars {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers an env var, replacing its delimiter if it's already registered.
+    pub fn var(mut self, key: impl Into<String>, delimiter: char) -> Self {
+        self.0.insert(key.into(), delimiter);
+        self
+    }
+
+    pub fn delimiter(&self, env_var_key: &str) -> Option<char> {
+        self.0.get(env_var_key).copied()
+    }
+
+    /// Merges each registered env var set in both `old_env_vars` and `new_env_vars` with
+    /// [`merge_delimited_env_var`], the new entries first.
+    pub fn merge(&self, old_env_vars: &EnvVars, new_env_vars: &mut EnvVars) {
+        for (env_var, delimiter) in &self.0 {
+            merge_delimited_env_var(env_var, *delimiter, *delimiter, old_env_vars, new_env_vars);
+        }
+    }
+}
+
+/// How [`merge_env_maps`] combines a key's values from several env maps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// The last map's value wins.
+    #[default]
+    Replace,
+    /// The values are merged with [`merge_delimited_values`], later maps' entries first.
+    Delimited { split: char, join: char },
+}
+
+/// Layers `maps` in order, combining each key's values by its strategy in `strategies`, or
+/// [`MergeStrategy::Replace`] if it has none.
+pub fn merge_env_maps(maps: &[EnvVars], strategies: &HashMap<String, MergeStrategy>) -> EnvVars {
+    let mut merged_env_vars = EnvVars::new();
+    for (key, value) in maps.iter().flat_map(|env_vars| env_vars.iter()) {
+        let merged_value = match (strategies.get(key), merged_env_vars.get(key)) {
+            (Some(MergeStrategy::Delimited { split, join }), Some(old_value)) => {
+                merge_delimited_values(*split, *join, old_value, value)
+            }
+            _ => value.clone(),
+        };
+        merged_env_vars.insert(key.clone(), merged_value);
+    }
+    merged_env_vars
+}
+
 const IGNORED_ENV_VAR_PREFIXES: &[&str] = &["__fish", "BASH_FUNC_"];
 
 static IGNORED_ENV_VAR_KEYS: Lazy<HashSet<&str>> = Lazy::new(|| {
@@ -270,31 +416,339 @@ static IGNORED_ENV_VAR_KEYS: Lazy<HashSet<&str>> = Lazy::new(|| {
         "DIRENV_IN_ENVRC",
         "COMP_WORDBREAKS", // Avoids segfaults in bash
         "PS1",             // PS1 should not be exported, fixes problem in bash
-        // variables that should change freely
-        "OLDPWD",
-        "PWD",
-        "SHELL",
-        "SHELLOPTS",
-        "SHLVL",
-        "_",
         "__CF_USER_TEXT_ENCODING",
     ])
 });
 
-pub fn ignored_env_var_key(env_var_key: &str) -> bool {
-    for ignored_env_var_prefix in IGNORED_ENV_VAR_PREFIXES {
-        if env_var_key.starts_with(ignored_env_var_prefix) {
-            return true;
+/// Env vars that should change freely as a shell runs, ignored by default, but individually kept
+/// with [`IgnoreRules::keep_volatile`], e.g. to see the `PWD` a script `cd`'d to.
+pub const VOLATILE_ENV_VAR_KEYS: &[&str] = &["OLDPWD", "PWD", "SHELL", "SHELLOPTS", "SHLVL", "_"];
+
+/// Which env var keys to ignore, by exact key, prefix or regex pattern.
+///
+/// The default rules ignore the keys that shells and direnv set for themselves, e.g. `PWD`.
+#[derive(Debug, Clone)]
+pub struct IgnoreRules {
+    keys: HashSet<String>,
+    volatile_keys: HashSet<String>,
+    prefixes: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+impl Default for IgnoreRules {
+    fn default() -> Self {
+        Self {
+            keys: IGNORED_ENV_VAR_KEYS
+                .iter()
+                .map(|key| String::from(*key))
+                .collect(),
+            volatile_keys: VOLATILE_ENV_VAR_KEYS
+                .iter()
+                .map(|key| String::from(*key))
+                .collect(),
+            prefixes: IGNORED_ENV_VAR_PREFIXES
+                .iter()
+                .map(|prefix| String::from(*prefix))
+                .collect(),
+            patterns: Vec::new(),
         }
     }
-    IGNORED_ENV_VAR_KEYS.contains(env_var_key)
+}
+
+impl IgnoreRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        self.keys.insert(key.into());
+        self
+    }
+
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefixes.push(prefix.into());
+        self
+    }
+
+    /// Ignores keys the pattern matches in full, for families of keys without a common prefix,
+    /// e.g. `FOO_[0-9]+_BAR`.
+    pub fn pattern(mut self, pattern: Regex) -> Self {
+        self.patterns.push(
+            Regex::new(&format!("^(?:{})$", pattern.as_str()))
+                .expect("anchoring a valid regex keeps it valid"),
+        );
+        self
+    }
+
+    /// Keeps one of the [`VOLATILE_ENV_VAR_KEYS`] rather than ignoring it.
+    pub fn keep_volatile(mut self, key: &str) -> Self {
+        self.volatile_keys.remove(key);
+        self
+    }
+
+    pub fn is_ignored(&self, env_var_key: &str) -> bool {
+        self.keys.contains(env_var_key)
+            || self.volatile_keys.contains(env_var_key)
+            || self
+                .prefixes
+                .iter()
+                .any(|prefix| env_var_key.starts_with(prefix.as_str()))
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| pattern.is_match(env_var_key))
+    }
+
+    /// Removes the ignored env vars, along with any exported shell functions, whatever their key.
+    pub fn remove_ignored_env_vars(&self, env_vars: &mut EnvVars) {
+        env_vars.retain(|env_var_key, env_var_value| {
+            !self.is_ignored(env_var_key) && !exported_function_value(env_var_value)
+        });
+    }
+}
+
+/// Whether an env var's value is a shell function exported by bash, e.g. `() {  echo hi\n}`.
+///
+/// Bash exports functions as `BASH_FUNC_name%%`, but other shells and older versions of bash use
+/// other keys, and re-exporting a function's body as a plain value is never intended.
+pub fn exported_function_value(env_var_value: &str) -> bool {
+    env_var_value
+        .trim_start()
+        .strip_prefix("()")
+        .is_some_and(|rest| rest.trim_start().starts_with('{'))
+}
+
+static DEFAULT_IGNORE_RULES: Lazy<IgnoreRules> = Lazy::new(IgnoreRules::default);
+
+pub fn ignored_env_var_key(env_var_key: &str) -> bool {
+    DEFAULT_IGNORE_RULES.is_ignored(env_var_key)
 }
 
 pub fn remove_ignored_env_vars(env_vars: &mut EnvVars) {
-    let env_var_keys = env_vars.keys().cloned().collect::<Vec<_>>();
-    env_var_keys.into_iter().for_each(|env_var_key| {
-        if ignored_env_var_key(&env_var_key) {
-            env_vars.shift_remove(&env_var_key);
+    DEFAULT_IGNORE_RULES.remove_ignored_env_vars(env_vars);
+}
+
+/// Env vars that change how programs are loaded or how the shell parses input, so are risky to
+/// take from an untrusted environment. Stripped by [`strip_dangerous_env_vars`].
+pub const DANGEROUS_ENV_VAR_KEYS: &[&str] = &[
+    "LD_PRELOAD",
+    "LD_LIBRARY_PATH",
+    "LD_AUDIT",
+    "DYLD_INSERT_LIBRARIES",
+    "DYLD_LIBRARY_PATH",
+    "IFS",
+    "BASH_ENV",
+    "ENV",
+    "PROMPT_COMMAND",
+];
+
+/// Reverts the [`DANGEROUS_ENV_VAR_KEYS`] in `env_vars` that were set or changed from
+/// `base_env_vars`, returning their keys.
+///
+/// Dangerous env vars already in the base are kept as they were, since they didn't come from
+/// the untrusted environment.
+pub fn strip_dangerous_env_vars(env_vars: &mut EnvVars, base_env_vars: &EnvVars) -> Vec<String> {
+    let mut stripped_keys = Vec::new();
+    for key in DANGEROUS_ENV_VAR_KEYS {
+        let base_value = base_env_vars.get(*key);
+        if env_vars.get(*key) == base_value {
+            continue;
+        }
+        match base_value {
+            Some(base_value) => {
+                env_vars.insert(String::from(*key), base_value.clone());
+            }
+            None => {
+                env_vars.shift_remove(*key);
+            }
+        }
+        stripped_keys.push(String::from(*key));
+    }
+    stripped_keys
+}
+
+/// Folds env var keys to upper case, for env vars from Windows where keys are case-insensitive,
+/// e.g. from `get_env_vars_from_powershell`.
+///
+/// When keys collide (e.g. `PATH` and `Path`), the value of the key already in upper case wins,
+/// otherwise the last one does. The merged key keeps the position of the first.
+pub fn normalize_keys_ci(env_vars: EnvVars) -> EnvVars {
+    let mut normalized_env_vars = EnvVars::new();
+    let mut upper_case_keys = HashSet::new();
+    for (key, value) in env_vars {
+        let normalized_key = key.to_uppercase();
+        if key == normalized_key {
+            upper_case_keys.insert(normalized_key.clone());
+        } else if upper_case_keys.contains(&normalized_key) {
+            continue;
+        }
+        normalized_env_vars.insert(normalized_key, value);
+    }
+    normalized_env_vars
+}
+
+/// What [`scrub_secrets`] replaces secret values with.
+pub const SCRUBBED_VALUE: &str = "***";
+
+/// Env var keys that likely hold secrets, e.g. `GITHUB_TOKEN` or `DB_PASSWORD`.
+pub static DEFAULT_SECRET_PATTERNS: Lazy<Vec<Regex>> = Lazy::new(|| {
+    [
+        r"(?i)_TOKEN$",
+        r"(?i)_SECRET$",
+        r"(?i)_KEY$",
+        r"(?i)PASSWORD",
+    ]
+    .into_iter()
+    .map(|pattern| Regex::new(pattern).unwrap())
+    .collect()
+});
+
+/// How env vars differ from one env map to another, see [`EnvDiff::new`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvDiff {
+    /// Env vars only in the new env map, with their values.
+    pub added: EnvVars,
+    /// Env vars in both env maps with different values, as `(old, new)`.
+    pub changed: IndexMap<String, (String, String)>,
+    /// Env vars only in the old env map, with their values.
+    pub removed: EnvVars,
+}
+
+impl EnvDiff {
+    /// Diffs `old` against `new`, keeping the order of the env map each env var came from.
+    pub fn new(old: &EnvVars, new: &EnvVars) -> Self {
+        let mut env_diff = Self::default();
+        for (key, new_value) in new.iter() {
+            match old.get(key) {
+                None => {
+                    env_diff.added.insert(key.clone(), new_value.clone());
+                }
+                Some(old_value) if old_value != new_value => {
+                    env_diff
+                        .changed
+                        .insert(key.clone(), (old_value.clone(), new_value.clone()));
+                }
+                Some(_) => {}
+            }
+        }
+        for (key, old_value) in old.iter() {
+            if !new.contains_key(key) {
+                env_diff.removed.insert(key.clone(), old_value.clone());
+            }
+        }
+        env_diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    /// Applies the diff to an env map, adding and changing env vars and removing the removed
+    /// ones, e.g. for consumers keeping their own env map rather than exporting to a shell.
+    ///
+    /// Applied to the diff's `old` env map, this gives its `new` one.
+    pub fn apply_to(&self, env: &mut EnvVars) {
+        env.extend(self.added.clone());
+        for (key, (_, new_value)) in self.changed.iter() {
+            env.insert(key.clone(), new_value.clone());
+        }
+        for key in self.removed.keys() {
+            env.shift_remove(key);
+        }
+    }
+
+    /// The diff with secret values replaced, see [`scrub_secrets`].
+    pub fn scrub_secrets(&self, patterns: &[Regex]) -> Self {
+        let is_secret = |key: &str| patterns.iter().any(|pattern| pattern.is_match(key));
+        Self {
+            added: scrub_secrets(&self.added, patterns),
+            changed: self
+                .changed
+                .iter()
+                .map(|(key, values)| {
+                    let values = if is_secret(key) {
+                        (String::from(SCRUBBED_VALUE), String::from(SCRUBBED_VALUE))
+                    } else {
+                        values.clone()
+                    };
+                    (key.clone(), values)
+                })
+                .collect(),
+            removed: scrub_secrets(&self.removed, patterns),
+        }
+    }
+
+    /// How big the diff is, e.g. to see how heavy entering an environment is.
+    pub fn stats(&self) -> EnvDiffStats {
+        EnvDiffStats {
+            added: self.added.len(),
+            changed: self.changed.len(),
+            removed: self.removed.len(),
+            added_bytes: self.added.values().map(String::len).sum(),
         }
+    }
+}
+
+/// Counts of the env vars in an [`EnvDiff`], see [`EnvDiff::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EnvDiffStats {
+    pub added: usize,
+    pub changed: usize,
+    pub removed: usize,
+    /// The total size of the added env vars' values.
+    pub added_bytes: usize,
+}
+
+impl fmt::Display for EnvDiffStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} added ({} bytes), {} changed, {} removed",
+            self.added, self.added_bytes, self.changed, self.removed
+        )
+    }
+}
+
+/// Replaces the values of env vars whose keys match any of `patterns` with [`SCRUBBED_VALUE`].
+///
+/// Only for displaying or logging env vars, never for setting them.
+pub fn scrub_secrets(env_vars: &EnvVars, patterns: &[Regex]) -> EnvVars {
+    env_vars
+        .iter()
+        .map(|(key, value)| {
+            let value = if patterns.iter().any(|pattern| pattern.is_match(key)) {
+                String::from(SCRUBBED_VALUE)
+            } else {
+                value.clone()
+            };
+            (key.clone(), value)
+        })
+        .collect()
+}
+
+/// Whether `env_var_key` is a valid shell variable name, i.e. matches `[A-Za-z_][A-Za-z0-9_]*`.
+pub fn valid_env_var_key(env_var_key: &str) -> bool {
+    let mut chars = env_var_key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Removes the env vars whose keys start with `prefix`, e.g. a tool's own control env vars.
+pub fn remove_env_vars_with_prefix(env_vars: &mut EnvVars, prefix: &str) {
+    env_vars.retain(|env_var_key, _| !env_var_key.starts_with(prefix));
+}
+
+/// Removes env vars whose keys can't be exported by a shell, returning the removed keys.
+pub fn remove_invalid_env_var_keys(env_vars: &mut EnvVars) -> Vec<String> {
+    let invalid_env_var_keys = env_vars
+        .keys()
+        .filter(|env_var_key| !valid_env_var_key(env_var_key))
+        .cloned()
+        .collect::<Vec<_>>();
+    invalid_env_var_keys.iter().for_each(|env_var_key| {
+        env_vars.shift_remove(env_var_key);
     });
+    invalid_env_var_keys
 }
@@ -0,0 +1,54 @@
+use std::{fs, io};
+
+use duct::cmd;
+
+use crate::{EnvVars, SimplifiedExitOk, get_env_vars_from_current_process};
+
+/// Runs a PowerShell script with `pwsh`, returning the env vars it leaves set.
+///
+/// The script starts from `env_vars`, or the current process's env vars if not provided, since
+/// PowerShell (on Windows especially) misbehaves without variables like `SystemRoot`.
+pub fn get_env_vars_from_powershell(
+    script: &str,
+    env_vars: Option<EnvVars>,
+) -> anyhow::Result<EnvVars> {
+    let env_vars_file = tempfile::NamedTempFile::new()?;
+
+    let command_string = format!(
+        "$ErrorActionPreference = 'Stop'; \
+         . {{ {script} }} | Out-Null; \
+         $envVars = [ordered]@{{}}; \
+         Get-ChildItem Env: | ForEach-Object {{ $envVars[$_.Name] = $_.Value }}; \
+         [IO.File]::WriteAllText({}, (ConvertTo-Json -Compress -InputObject $envVars))",
+        powershell_quote(&env_vars_file.path().to_string_lossy())
+    );
+    let output = cmd!(
+        "pwsh",
+        "-NoProfile",
+        "-NonInteractive",
+        "-Command",
+        command_string
+    )
+    .full_env(env_vars.unwrap_or_else(get_env_vars_from_current_process))
+    .stdout_to_stderr()
+    .unchecked()
+    .run()
+    .map_err(|e| {
+        if e.kind() == io::ErrorKind::NotFound {
+            anyhow::format_err!("pwsh not found on PATH; cannot run PowerShell script")
+        } else {
+            e.into()
+        }
+    })?;
+    output
+        .status
+        .simplified_exit_ok()
+        .map_err(|e| anyhow::format_err!("PowerShell command to retrieve env vars failed:\n{e}"))?;
+
+    Ok(serde_json::from_slice(&fs::read(env_vars_file.path())?)?)
+}
+
+/// Quotes a string as a PowerShell single-quoted literal, where only `'` needs escaping.
+fn powershell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
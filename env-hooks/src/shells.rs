@@ -1,5 +1,28 @@
 pub mod bash;
+pub mod direnv;
+pub mod dotenv;
 pub mod fish;
 pub mod json;
 pub mod nushell;
 pub mod zsh;
+
+use shell_quote::{Bash, Fish, Quote, Sh, Zsh};
+
+/// Shells with a known quoting strategy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Fish,
+    Sh,
+    Zsh,
+}
+
+/// Quotes an arbitrary string so that `shell` reads it back as a single literal word.
+pub fn quote(value: &str, shell: Shell) -> String {
+    match shell {
+        Shell::Bash => Bash::quote(value),
+        Shell::Fish => Fish::quote(value),
+        Shell::Sh => String::from_utf8_lossy(&Sh::quote_vec(value)).into_owned(),
+        Shell::Zsh => Zsh::quote(value),
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
+
+use base64::{Engine, prelude::BASE64_URL_SAFE};
+use bstr::BString;
+use flate2::{Compression, read::ZlibDecoder, write::ZlibEncoder};
+use serde::Deserialize;
+
+use crate::{EnvVars, EnvVarsState};
+
+/// Renders `env_vars` with `env_vars_state` applied in the gzenv format of `direnv dump`.
+///
+/// A dump is the full resulting environment rather than a list of changes, so it can be loaded
+/// with direnv's `direnv_load` (or `direnv apply_dump`), which unsets anything it doesn't contain.
+pub fn export(env_vars_state: EnvVarsState, mut env_vars: EnvVars) -> BString {
+    for (key, state) in env_vars_state {
+        if let Some(value) = state {
+            env_vars.insert(key, value);
+        } else {
+            env_vars.shift_remove(&key);
+        }
+    }
+    gzenv_encode(&env_vars.iter().collect::<BTreeMap<_, _>>())
+        .expect("serializing and compressing a string map into memory can't fail")
+        .into()
+}
+
+/// direnv's record of the env vars it changed, from `DIRENV_DIFF`.
+#[derive(Debug, Deserialize)]
+struct DirenvDiff {
+    /// The values before direnv changed them, for env vars it changed or removed.
+    #[serde(rename = "p", default)]
+    prev: Option<BTreeMap<String, String>>,
+    /// The values direnv set, for env vars it added or changed.
+    #[serde(rename = "n", default)]
+    next: Option<BTreeMap<String, String>>,
+}
+
+/// Parses direnv's `DIRENV_DIFF` into the changes that restore the environment from before direnv
+/// loaded, like `get_env_vars_reset` does for envoluntary's own changes.
+///
+/// Env vars direnv changed or removed get their previous values back, and ones it added are unset.
+pub fn parse_direnv_diff(value: &str) -> anyhow::Result<EnvVarsState> {
+    let direnv_diff: DirenvDiff = gzenv_decode(value)?;
+    let prev = direnv_diff.prev.unwrap_or_default();
+    let mut env_vars_state = EnvVarsState::new();
+    for key in direnv_diff.next.unwrap_or_default().into_keys() {
+        if !prev.contains_key(&key) {
+            env_vars_state.insert(key, None);
+        }
+    }
+    for (key, value) in prev {
+        env_vars_state.insert(key, Some(value));
+    }
+    Ok(env_vars_state)
+}
+
+/// Decodes direnv's gzenv format, see [`gzenv_encode`].
+fn gzenv_decode<T: serde::de::DeserializeOwned>(value: &str) -> anyhow::Result<T> {
+    let compressed = BASE64_URL_SAFE.decode(value.trim())?;
+    let mut json = Vec::new();
+    ZlibDecoder::new(compressed.as_slice()).read_to_end(&mut json)?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Encodes like direnv's gzenv: JSON, compressed with zlib, then URL safe base64 encoded.
+fn gzenv_encode(value: &impl serde::Serialize) -> anyhow::Result<String> {
+    let json = serde_json::to_vec(value)?;
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&json)?;
+    Ok(BASE64_URL_SAFE.encode(encoder.finish()?))
+}
@@ -0,0 +1,144 @@
+use std::{iter::Peekable, str::Chars};
+
+use crate::{EnvVars, valid_env_var_key};
+
+/// Renders `env_vars` as a `.env` file of `KEY=value` lines, for tools like docker-compose.
+///
+/// Values made of only safe characters are left bare. Others are single-quoted, which dotenv
+/// loaders read literally, unless they contain a `'` or a line break, in which case they're
+/// double-quoted with `\`-escapes.
+pub fn render_dotenv(env_vars: &EnvVars) -> String {
+    env_vars
+        .iter()
+        .map(|(key, value)| format!("{key}={}\n", dotenv_quote(value)))
+        .collect()
+}
+
+/// Parses a `.env` file, the inverse of [`render_dotenv`].
+///
+/// Supports blank lines, `#` comments, an optional `export ` prefix, and bare, single-quoted
+/// (literal) and double-quoted (with `\n`, `\r`, `\t`, `\"` and `\\` escapes) values.
+pub fn parse_dotenv(content: &str) -> anyhow::Result<EnvVars> {
+    let mut env_vars = EnvVars::new();
+    let mut chars = content.chars().peekable();
+    let mut line_number = 1;
+    loop {
+        skip_while(&mut chars, |c| c.is_whitespace() && c != '\n');
+        match chars.peek() {
+            None => break,
+            Some('\n') => {
+                chars.next();
+                line_number += 1;
+                continue;
+            }
+            Some('#') => {
+                skip_while(&mut chars, |c| c != '\n');
+                continue;
+            }
+            Some(_) => {}
+        }
+
+        let mut key = take_while(&mut chars, |c| c != '=' && c != '\n');
+        if chars.next() != Some('=') {
+            return Err(anyhow::format_err!(
+                "line {line_number} of .env file has no `=`"
+            ));
+        }
+        if let Some(exported_key) = key.strip_prefix("export ") {
+            key = String::from(exported_key);
+        }
+        let key = key.trim();
+        if !valid_env_var_key(key) {
+            return Err(anyhow::format_err!(
+                "line {line_number} of .env file has an invalid key: {key:?}"
+            ));
+        }
+
+        let value = match chars.peek() {
+            Some('\'') => {
+                chars.next();
+                let value = take_while(&mut chars, |c| c != '\'');
+                if chars.next().is_none() {
+                    return Err(anyhow::format_err!(
+                        "line {line_number} of .env file has an unterminated `'`"
+                    ));
+                }
+                line_number += value.matches('\n').count();
+                value
+            }
+            Some('"') => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        None => {
+                            return Err(anyhow::format_err!(
+                                "line {line_number} of .env file has an unterminated `\"`"
+                            ));
+                        }
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => value.push('\n'),
+                            Some('r') => value.push('\r'),
+                            Some('t') => value.push('\t'),
+                            Some(c @ ('"' | '\\')) => value.push(c),
+                            Some(c) => {
+                                value.push('\\');
+                                value.push(c);
+                            }
+                            None => {}
+                        },
+                        Some(c) => value.push(c),
+                    }
+                }
+                line_number += value.matches('\n').count();
+                value
+            }
+            _ => {
+                let value = take_while(&mut chars, |c| c != '\n');
+                // NB: ` #` starts a comment after a bare value, but `#` alone is part of it
+                let value = value.split(" #").next().unwrap_or_default();
+                String::from(value.trim())
+            }
+        };
+        // Anything after a quoted value, e.g. a comment, is ignored
+        skip_while(&mut chars, |c| c != '\n');
+        env_vars.insert(String::from(key), value);
+    }
+    Ok(env_vars)
+}
+
+fn dotenv_quote(value: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-.,/:@%+=".contains(c);
+    if value.chars().all(is_safe) {
+        return String::from(value);
+    }
+    if !value.contains(['\'', '\n', '\r']) {
+        return format!("'{value}'");
+    }
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn take_while(chars: &mut Peekable<Chars<'_>>, predicate: impl Fn(char) -> bool) -> String {
+    let mut taken = String::new();
+    while let Some(c) = chars.next_if(|c| predicate(*c)) {
+        taken.push(c);
+    }
+    taken
+}
+
+fn skip_while(chars: &mut Peekable<Chars<'_>>, predicate: impl Fn(char) -> bool) {
+    while chars.next_if(|c| predicate(*c)).is_some() {}
+}
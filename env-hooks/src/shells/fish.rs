@@ -3,7 +3,7 @@ use std::collections::HashSet;
 use bstr::{B, BString, ByteSlice};
 use shell_quote::Fish;
 
-use crate::EnvVarsState;
+use crate::{EnvVarsState, valid_env_var_key};
 
 const FISH_HOOK: &str = r#"
     function __{{.HookPrefix}}_export_eval --on-event fish_prompt;
@@ -44,6 +44,7 @@ pub fn export(
 ) -> BString {
     let exports = env_vars_state
         .iter()
+        .filter(|(key, _)| valid_env_var_key(key))
         .map(|(key, state)| {
             if let Some(value) = state {
                 export_var(key, value, semicolon_delimited_env_vars)
@@ -3,7 +3,7 @@ use std::collections::HashSet;
 use bstr::{B, BString, ByteSlice};
 use shell_quote::Zsh;
 
-use crate::EnvVarsState;
+use crate::{EnvVarsState, valid_env_var_key};
 
 const ZSH_HOOK: &str = r#"
     _{{.HookPrefix}}_hook() {
@@ -35,6 +35,7 @@ pub fn export(
 ) -> BString {
     let exports = env_vars_state
         .iter()
+        .filter(|(key, _)| valid_env_var_key(key))
         .map(|(key, state)| {
             if let Some(value) = state {
                 export_var(key, value)
@@ -81,6 +81,47 @@ impl CurrentDirState {
     }
 }
 
+/// Walks up from `start` collecting the files named by `filenames` in each directory, innermost
+/// first, e.g. for [`CurrentDirState::match_rcs`].
+///
+/// Stops at the filesystem root, or with `stop_at_git`, at the first directory containing `.git`.
+pub fn find_rcs_upward(start: &Path, filenames: &[&str], stop_at_git: bool) -> Vec<PathBuf> {
+    let mut rcs = vec![];
+    for dir in start.ancestors() {
+        rcs.extend(
+            filenames
+                .iter()
+                .map(|filename| dir.join(filename))
+                .filter(|rc| rc.is_file()),
+        );
+        if stop_at_git && dir.join(".git").exists() {
+            break;
+        }
+    }
+    rcs
+}
+
+/// Lists the rcs that [`find_rcs_upward`] would find for `path` and `matcher` accepts, without
+/// sourcing anything, e.g. for an editor to check whether a file it opened has an environment.
+///
+/// `path` may be a file, in which case the search starts from its parent directory.
+pub fn matching_rcs(
+    path: &Path,
+    filenames: &[&str],
+    stop_at_git: bool,
+    matcher: impl Fn(&Path) -> bool,
+) -> Vec<PathBuf> {
+    let start = if path.is_file() {
+        path.parent().unwrap_or(path)
+    } else {
+        path
+    };
+    find_rcs_upward(start, filenames, stop_at_git)
+        .into_iter()
+        .filter(|rc| matcher(rc))
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub enum MatchRcs<RC> {
     NoRcs(NoRcsState),
@@ -1,10 +1,12 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, io::Read, process};
 
+use base64::{Engine, prelude::BASE64_URL_SAFE};
 use bstr::ByteSlice;
 use env_hooks::{
-    EnvVarsState,
-    shells::{bash, fish, json, nushell, zsh},
+    EnvVars, EnvVarsState,
+    shells::{Shell, bash, direnv, dotenv, fish, json, nushell, quote, zsh},
 };
+use flate2::read::ZlibDecoder;
 use once_cell::sync::Lazy;
 
 static TEST_ENV_VARS: Lazy<EnvVarsState> = Lazy::new(|| {
@@ -53,6 +55,23 @@ fn bash_export_set_unset_and_special_vars() {
     );
 }
 
+#[test]
+fn bash_zsh_and_fish_export_skip_invalid_keys() {
+    let env_vars = EnvVarsState::from_iter(vec![
+        ("VALID".to_string(), Some("value".to_string())),
+        (
+            "BASH_FUNC_foo%%".to_string(),
+            Some("() {  echo foo\n}".to_string()),
+        ),
+        ("1INVALID".to_string(), Some("value".to_string())),
+        ("INVALID-KEY".to_string(), None),
+    ]);
+
+    assert_eq!(bash::export(env_vars.clone(), None), "export VALID=value;");
+    assert_eq!(zsh::export(env_vars.clone(), None), "export VALID=value;");
+    assert_eq!(fish::export(env_vars, None), "set -x -g VALID value;");
+}
+
 #[test]
 fn bash_hook_templated() {
     let result = bash::hook("myapp", "myapp export bash").to_string();
@@ -187,3 +206,160 @@ fn json_export_set_unset_and_special_vars() {
         })
     );
 }
+
+const QUOTE_TEST_VALUES: [&str; 4] = ["value with spaces", "$HOME", "`whoami`", "line1\nline2"];
+
+#[test]
+fn quote_bash_and_zsh_special_values() {
+    for shell in [Shell::Bash, Shell::Zsh] {
+        assert_eq!(
+            QUOTE_TEST_VALUES.map(|value| quote(value, shell)),
+            [
+                "$'value with spaces'",
+                "$'$HOME'",
+                "$'`whoami`'",
+                "$'line1\\nline2'",
+            ]
+        );
+    }
+}
+
+#[test]
+fn quote_sh_special_values() {
+    assert_eq!(
+        QUOTE_TEST_VALUES.map(|value| quote(value, Shell::Sh)),
+        [
+            "value' with spaces'",
+            "'$HOME'",
+            "'`whoami`'",
+            "line1'\nline2'",
+        ]
+    );
+}
+
+#[test]
+fn quote_fish_special_values() {
+    assert_eq!(
+        QUOTE_TEST_VALUES.map(|value| quote(value, Shell::Fish)),
+        [
+            "value' with spaces'",
+            "'$HOME'",
+            "'`whoami`'",
+            "line1\\nline2",
+        ]
+    );
+}
+
+#[test]
+fn quote_round_trips_through_bash_and_sh() {
+    for (program, shell) in [("bash", Shell::Bash), ("sh", Shell::Sh)] {
+        for value in QUOTE_TEST_VALUES {
+            let output = process::Command::new(program)
+                .arg("-c")
+                .arg(format!("printf %s {}", quote(value, shell)))
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8_lossy(&output.stdout), value);
+        }
+    }
+}
+
+fn gzenv_decode(gzenv: &str) -> serde_json::Value {
+    let data = BASE64_URL_SAFE.decode(gzenv.trim()).unwrap();
+    let mut json = Vec::new();
+    ZlibDecoder::new(data.as_slice())
+        .read_to_end(&mut json)
+        .unwrap();
+    serde_json::from_slice(&json).unwrap()
+}
+
+#[test]
+fn direnv_export_matches_direnv_dump_format() {
+    // NB: A dump of `{"HOME":"/home/user","PATH":"/nix/store/abc/bin:/usr/bin","SIMPLE":"value"}`
+    // encoded the way direnv's gzenv does it
+    let direnv_dump_sample = "eJyrVvLw93VVslLSz8jPTdUvLU4tUtJRCnAM8QCJ5WVW6BeX5Bel6icmJesnZeZZAVUUgRhARcGevgE-IK1liTmlqUq1AGeyF1w=";
+
+    let env_vars = EnvVars::from_iter([
+        ("HOME".to_string(), "/home/user".to_string()),
+        ("PATH".to_string(), "/usr/bin".to_string()),
+        ("TO_REMOVE".to_string(), "old".to_string()),
+    ]);
+    let env_vars_state = EnvVarsState::from_iter([
+        (
+            "PATH".to_string(),
+            Some("/nix/store/abc/bin:/usr/bin".to_string()),
+        ),
+        ("SIMPLE".to_string(), Some("value".to_string())),
+        ("TO_REMOVE".to_string(), None),
+    ]);
+
+    let result = direnv::export(env_vars_state, env_vars).to_string();
+
+    assert_eq!(gzenv_decode(&result), gzenv_decode(direnv_dump_sample));
+    assert_eq!(
+        gzenv_decode(&result).to_string(),
+        r#"{"HOME":"/home/user","PATH":"/nix/store/abc/bin:/usr/bin","SIMPLE":"value"}"#
+    );
+}
+
+#[test]
+fn direnv_parse_diff_restores_prior_env() {
+    // NB: A `DIRENV_DIFF` of
+    // `{"p":{"PATH":"/usr/bin","EDITOR":"vi"},"n":{"PATH":"/nix/store/abc/bin:/usr/bin","IN_NIX_SHELL":"impure"}}`
+    // encoded the way direnv's gzenv does it
+    let direnv_diff_sample = "eJyrVipQsqpWCnAM8VCyUtIvLS7ST8rMU9JRcnXxDPEPAoqVZSrV6ijlIavKy6zQLy7JL0rVT0xKBqm3QtLo6Rfv5xkRH-zh6uMDVJyZW1BalKpUWwsAfIEgUA==";
+
+    let env_vars_state = direnv::parse_direnv_diff(direnv_diff_sample).unwrap();
+
+    assert_eq!(
+        env_vars_state,
+        EnvVarsState::from_iter([
+            ("IN_NIX_SHELL".to_string(), None),
+            ("EDITOR".to_string(), Some("vi".to_string())),
+            ("PATH".to_string(), Some("/usr/bin".to_string())),
+        ])
+    );
+    assert!(direnv::parse_direnv_diff("not gzenv").is_err());
+}
+
+#[test]
+fn dotenv_render_round_trips_through_parse() {
+    let env_vars = EnvVars::from_iter(
+        TEST_ENV_VARS
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), value.clone()?)))
+            .chain([
+                ("SINGLE_QUOTE".to_string(), "it's".to_string()),
+                ("DOUBLE_QUOTE".to_string(), "say \"hi\"".to_string()),
+                ("BACKSLASH".to_string(), "C:\\path\\n".to_string()),
+                ("HASH".to_string(), "a #comment".to_string()),
+                ("PADDED".to_string(), "  padded\t".to_string()),
+                ("CRLF".to_string(), "line\r\n'quoted'".to_string()),
+            ]),
+    );
+
+    let dotenv_content = dotenv::render_dotenv(&env_vars);
+    assert!(dotenv_content.contains("SIMPLE=value\n"));
+    assert!(dotenv_content.contains("WITH_SPACES='value with spaces'\n"));
+    assert!(dotenv_content.contains("SINGLE_QUOTE=\"it's\"\n"));
+
+    assert_eq!(dotenv::parse_dotenv(&dotenv_content).unwrap(), env_vars);
+}
+
+#[test]
+fn dotenv_parse_handles_comments_and_export_prefix() {
+    assert_eq!(
+        dotenv::parse_dotenv(
+            "# comment\n\nexport EXPORTED=yes\nBARE = some value # trailing\nQUOTED='a # b' # c\n"
+        )
+        .unwrap(),
+        EnvVars::from_iter([
+            ("EXPORTED".to_string(), "yes".to_string()),
+            ("BARE".to_string(), "some value".to_string()),
+            ("QUOTED".to_string(), "a # b".to_string()),
+        ])
+    );
+    assert!(dotenv::parse_dotenv("NO_EQUALS\n").is_err());
+    assert!(dotenv::parse_dotenv("UNTERMINATED=\"value\n").is_err());
+    assert!(dotenv::parse_dotenv("1INVALID=value\n").is_err());
+}
@@ -1,6 +1,6 @@
 use std::cell::{Cell, RefCell};
-use std::env;
 use std::path::PathBuf;
+use std::{env, fs};
 
 use assert_matches::assert_matches;
 use env_hooks::state::{self, GetEnvStateVar, MatchRcs};
@@ -117,3 +117,65 @@ fn workflow_rcs_found_existing_environment_resets_and_updates_state() {
         std::env::remove_var(ENV_STATE_VAR_KEY);
     }
 }
+
+#[test]
+fn find_rcs_upward_collects_innermost_first() {
+    let root = tempfile::tempdir().unwrap();
+    let repo = root.path().join("repo");
+    let inner = repo.join("a").join("b");
+    fs::create_dir_all(&inner).unwrap();
+    fs::create_dir(repo.join(".git")).unwrap();
+    for dir in [root.path(), &repo, &repo.join("a"), &inner] {
+        fs::write(dir.join(".envrc"), "").unwrap();
+    }
+    fs::write(inner.join(".env"), "").unwrap();
+
+    assert_eq!(
+        state::find_rcs_upward(&inner, &[".envrc", ".env"], true),
+        [
+            inner.join(".envrc"),
+            inner.join(".env"),
+            repo.join("a").join(".envrc"),
+            repo.join(".envrc"),
+        ]
+    );
+
+    let all_rcs = state::find_rcs_upward(&inner, &[".envrc"], false);
+    assert_eq!(all_rcs.len(), 4);
+    assert_eq!(all_rcs.last(), Some(&root.path().join(".envrc")));
+
+    let current_dir_state = state::ShellPromptState::get_current_dir(Some(inner.clone())).unwrap();
+    let match_rcs = current_dir_state
+        .match_rcs(|current_dir| Ok(state::find_rcs_upward(current_dir, &[".env"], true)))
+        .unwrap();
+    let rcs_state = assert_matches!(match_rcs, MatchRcs::Rcs(rcs_state) => rcs_state);
+    assert_matches!(
+        rcs_state.get_env_state_var("FIND_RCS_UPWARD_UNSET_STATE_VAR"),
+        GetEnvStateVar::NoEnvStateVar(_)
+    );
+}
+
+#[test]
+fn matching_rcs_reports_match_and_no_match() {
+    let root = tempfile::tempdir().unwrap();
+    let project = root.path().join("project");
+    let src = project.join("src");
+    let other = root.path().join("other");
+    fs::create_dir_all(&src).unwrap();
+    fs::create_dir(&other).unwrap();
+    fs::create_dir(project.join(".git")).unwrap();
+    fs::write(project.join(".envrc"), "").unwrap();
+    fs::write(src.join("main.rs"), "").unwrap();
+
+    let filenames = [".envrc", ".env"];
+    assert_eq!(
+        state::matching_rcs(&src.join("main.rs"), &filenames, true, |_| true),
+        [project.join(".envrc")]
+    );
+    assert_eq!(
+        state::matching_rcs(&src, &filenames, true, |_| true),
+        [project.join(".envrc")]
+    );
+    assert!(state::matching_rcs(&src, &filenames, true, |_| false).is_empty());
+    assert!(state::matching_rcs(&other, &filenames, true, |_| true).is_empty());
+}
@@ -1,11 +1,20 @@
 use std::env;
+use std::io::{self, BufReader, Cursor};
 use std::os::unix::fs::PermissionsExt;
-use std::{collections::HashSet, fs};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+};
 
 use env_hooks::{
-    BashSource, EnvVars, EnvVarsState, get_env_vars_from_bash, get_env_vars_from_current_process,
-    get_env_vars_reset, get_old_env_vars_to_be_updated, merge_delimited_env_var,
-    remove_ignored_env_vars,
+    BashEnvExtractor, BashSource, DEFAULT_SECRET_PATTERNS, DelimitedEnvVars, EnvBase, EnvDiff,
+    EnvDiffStats, EnvVars, EnvVarsState, IgnoreRules, LineEndings, MergeStrategy,
+    NulDelimitedEnvVars, SourcingEvent, VOLATILE_ENV_VAR_KEYS, bash_env_diff, capture_env_keys,
+    exported_function_value, get_env_vars_from_bash, get_env_vars_from_current_process,
+    get_env_vars_reset, get_env_vars_soft_reset, get_old_env_vars_to_be_updated,
+    merge_delimited_env_var, merge_env_maps, minimal_base, normalize_keys_ci,
+    normalize_line_endings, parse_env0, parse_env0_str, remove_env_vars_with_prefix,
+    remove_ignored_env_vars, remove_invalid_env_var_keys, scrub_secrets, valid_env_var_key,
 };
 
 #[test]
@@ -54,6 +63,57 @@ fn get_old_env_vars_to_be_updated_empty_old_vars() {
     assert!(result.is_empty());
 }
 
+#[test]
+fn merge_env_maps_layers_with_per_key_strategies() {
+    let maps = [
+        EnvVars::from_iter([
+            ("PATH".to_string(), "/usr/bin:/bin".to_string()),
+            ("EDITOR".to_string(), "vi".to_string()),
+            ("FLAGS".to_string(), "-a".to_string()),
+        ]),
+        EnvVars::from_iter([
+            ("PATH".to_string(), "/project/bin:/usr/bin".to_string()),
+            ("EDITOR".to_string(), "nano".to_string()),
+        ]),
+        EnvVars::from_iter([
+            ("PATH".to_string(), "/nix/store/abc/bin".to_string()),
+            ("FLAGS".to_string(), "-b -a".to_string()),
+            ("ONLY_LAST".to_string(), "last".to_string()),
+        ]),
+    ];
+    let strategies = HashMap::from([
+        (
+            "PATH".to_string(),
+            MergeStrategy::Delimited {
+                split: ':',
+                join: ':',
+            },
+        ),
+        (
+            "FLAGS".to_string(),
+            MergeStrategy::Delimited {
+                split: ' ',
+                join: ' ',
+            },
+        ),
+        ("EDITOR".to_string(), MergeStrategy::Replace),
+    ]);
+
+    assert_eq!(
+        merge_env_maps(&maps, &strategies),
+        EnvVars::from_iter([
+            (
+                "PATH".to_string(),
+                "/nix/store/abc/bin:/project/bin:/usr/bin:/bin".to_string()
+            ),
+            ("EDITOR".to_string(), "nano".to_string()),
+            ("FLAGS".to_string(), "-b -a".to_string()),
+            ("ONLY_LAST".to_string(), "last".to_string()),
+        ])
+    );
+    assert_eq!(merge_env_maps(&[], &strategies), EnvVars::new());
+}
+
 #[test]
 fn get_env_vars_reset_returns_state_to_old_env_vars() {
     let old_env_vars = EnvVars::from_iter([
@@ -75,6 +135,27 @@ fn get_env_vars_reset_returns_state_to_old_env_vars() {
     );
 }
 
+#[test]
+fn get_env_vars_soft_reset_only_unsets_added_env_vars() {
+    let old_env_vars = EnvVars::from_iter([
+        ("VAR1".to_string(), "old1".to_string()),
+        ("VAR2".to_string(), "old2".to_string()),
+    ]);
+
+    let new_vars = HashSet::from_iter(["VAR1".to_string(), "VAR3".to_string()]);
+
+    let result = get_env_vars_soft_reset(&old_env_vars, new_vars.clone(), "STATE_VAR".to_string());
+
+    assert_eq!(
+        result,
+        EnvVarsState::from_iter([("VAR3".to_string(), None), ("STATE_VAR".to_string(), None),])
+    );
+    assert_ne!(
+        result,
+        get_env_vars_reset(old_env_vars, new_vars, "STATE_VAR".to_string())
+    );
+}
+
 #[test]
 fn get_env_vars_from_current_process_returns_current_env() {
     let result = get_env_vars_from_current_process();
@@ -110,6 +191,72 @@ fn merge_delimited_values_combines_paths_and_preserves_order_with_new_paths_in_f
     );
 }
 
+#[test]
+fn delimited_env_vars_merges_all_registered_vars_at_once() {
+    let old_env_vars = EnvVars::from_iter([
+        ("PATH".to_string(), "/usr/bin:/bin".to_string()),
+        ("MANPATH".to_string(), "/usr/share/man".to_string()),
+        (
+            "PKG_CONFIG_PATH".to_string(),
+            "/usr/lib/pkgconfig".to_string(),
+        ),
+        ("EDITOR".to_string(), "vi".to_string()),
+    ]);
+    let mut new_env_vars = EnvVars::from_iter([
+        ("PATH".to_string(), "/nix/store/a/bin:/usr/bin".to_string()),
+        ("MANPATH".to_string(), "/nix/store/a/share/man".to_string()),
+        (
+            "PKG_CONFIG_PATH".to_string(),
+            "/nix/store/a/lib/pkgconfig".to_string(),
+        ),
+        (
+            "XDG_DATA_DIRS".to_string(),
+            "/nix/store/a/share".to_string(),
+        ),
+        ("EDITOR".to_string(), "nano".to_string()),
+    ]);
+
+    DelimitedEnvVars::default().merge(&old_env_vars, &mut new_env_vars);
+
+    assert_eq!(
+        new_env_vars,
+        EnvVars::from_iter([
+            (
+                "PATH".to_string(),
+                "/nix/store/a/bin:/usr/bin:/bin".to_string()
+            ),
+            (
+                "MANPATH".to_string(),
+                "/nix/store/a/share/man:/usr/share/man".to_string()
+            ),
+            (
+                "PKG_CONFIG_PATH".to_string(),
+                "/nix/store/a/lib/pkgconfig:/usr/lib/pkgconfig".to_string()
+            ),
+            (
+                "XDG_DATA_DIRS".to_string(),
+                "/nix/store/a/share".to_string()
+            ),
+            ("EDITOR".to_string(), "nano".to_string()),
+        ])
+    );
+}
+
+#[test]
+fn delimited_env_vars_can_be_extended() {
+    let delimited_env_vars = DelimitedEnvVars::new().var("GOPATH", ':').var("PATH", ';');
+    assert_eq!(delimited_env_vars.delimiter("GOPATH"), Some(':'));
+    assert_eq!(delimited_env_vars.delimiter("PATH"), Some(';'));
+    assert_eq!(delimited_env_vars.delimiter("EDITOR"), None);
+
+    let mut new_env_vars = EnvVars::from_iter([("GOPATH".to_string(), "/a".to_string())]);
+    delimited_env_vars.merge(
+        &EnvVars::from_iter([("GOPATH".to_string(), "/b".to_string())]),
+        &mut new_env_vars,
+    );
+    assert_eq!(new_env_vars.get("GOPATH"), Some(&"/a:/b".to_string()));
+}
+
 #[test]
 fn env_vars_into_env_vars_state_conversion() {
     assert_eq!(
@@ -156,3 +303,793 @@ fn test_getting_env_vars_from_bash() {
         EnvVars::from_iter([(String::from("TEST_VAR"), String::from("true"))])
     );
 }
+
+#[test]
+fn env_diff_finds_added_changed_and_removed_vars() {
+    let old = EnvVars::from_iter([
+        (String::from("KEPT"), String::from("same")),
+        (String::from("CHANGED"), String::from("old")),
+        (String::from("REMOVED"), String::from("gone")),
+    ]);
+    let new = EnvVars::from_iter([
+        (String::from("ADDED"), String::from("new")),
+        (String::from("CHANGED"), String::from("new")),
+        (String::from("KEPT"), String::from("same")),
+    ]);
+
+    let env_diff = EnvDiff::new(&old, &new);
+
+    assert_eq!(
+        env_diff.added,
+        EnvVars::from_iter([(String::from("ADDED"), String::from("new"))])
+    );
+    assert_eq!(
+        env_diff.changed.get("CHANGED"),
+        Some(&(String::from("old"), String::from("new")))
+    );
+    assert_eq!(env_diff.changed.len(), 1);
+    assert_eq!(
+        env_diff.removed,
+        EnvVars::from_iter([(String::from("REMOVED"), String::from("gone"))])
+    );
+    assert!(EnvDiff::new(&old, &old).is_empty());
+}
+
+#[test]
+fn env_diff_apply_to_gives_new_env_vars() {
+    let old = EnvVars::from_iter([
+        (String::from("KEPT"), String::from("same")),
+        (String::from("CHANGED"), String::from("old")),
+        (String::from("REMOVED"), String::from("gone")),
+    ]);
+    let new = EnvVars::from_iter([
+        (String::from("ADDED"), String::from("new")),
+        (String::from("CHANGED"), String::from("new")),
+        (String::from("KEPT"), String::from("same")),
+    ]);
+
+    let mut env = old.clone();
+    EnvDiff::new(&old, &new).apply_to(&mut env);
+
+    assert_eq!(env, new);
+}
+
+#[test]
+fn env_diff_stats_count_vars_and_added_bytes() {
+    let old = EnvVars::from_iter([
+        (String::from("CHANGED"), String::from("old")),
+        (String::from("REMOVED"), String::from("gone")),
+    ]);
+    let new = EnvVars::from_iter([
+        (String::from("ADDED"), String::from("12345")),
+        (String::from("ALSO_ADDED"), String::from("678")),
+        (String::from("CHANGED"), String::from("new")),
+    ]);
+
+    let stats = EnvDiff::new(&old, &new).stats();
+
+    assert_eq!(
+        stats,
+        EnvDiffStats {
+            added: 2,
+            changed: 1,
+            removed: 1,
+            added_bytes: 8,
+        }
+    );
+    assert_eq!(stats.to_string(), "2 added (8 bytes), 1 changed, 1 removed");
+}
+
+#[test]
+fn bash_env_diff_finds_set_and_changed_vars() {
+    let base = EnvVars::from_iter([
+        (String::from("PATH"), env::var("PATH").unwrap()),
+        (String::from("CHANGED"), String::from("old")),
+        (String::from("KEPT"), String::from("same")),
+    ]);
+
+    let env_diff = bash_env_diff(
+        BashSource::Script("export ADDED=new CHANGED=new".into()),
+        Some(base),
+    )
+    .unwrap();
+
+    assert_eq!(
+        env_diff.added,
+        EnvVars::from_iter([(String::from("ADDED"), String::from("new"))])
+    );
+    assert_eq!(
+        env_diff.changed.into_iter().collect::<Vec<_>>(),
+        [(
+            String::from("CHANGED"),
+            (String::from("old"), String::from("new"))
+        )]
+    );
+    assert!(env_diff.removed.is_empty());
+}
+
+#[test]
+fn ignore_rules_match_patterns_against_the_full_key() {
+    let ignore_rules = IgnoreRules::new().pattern(regex::Regex::new("FOO_[0-9]+_BAR").unwrap());
+    let mut env_vars = EnvVars::from_iter([
+        (String::from("FOO_12345_BAR"), String::from("1")),
+        (String::from("FOO_9_BAR"), String::from("2")),
+        (String::from("FOO_12345_BARN"), String::from("3")),
+        (String::from("MY_FOO_1_BAR"), String::from("4")),
+        (String::from("PWD"), String::from("/")),
+    ]);
+
+    ignore_rules.remove_ignored_env_vars(&mut env_vars);
+
+    assert_eq!(
+        env_vars,
+        EnvVars::from_iter([
+            (String::from("FOO_12345_BARN"), String::from("3")),
+            (String::from("MY_FOO_1_BAR"), String::from("4")),
+        ])
+    );
+}
+
+#[test]
+fn ignore_rules_match_alternations_against_the_full_key() {
+    let ignore_rules = IgnoreRules::new().pattern(regex::Regex::new("FOO|FOO_BAR").unwrap());
+    let mut env_vars = EnvVars::from_iter([
+        (String::from("FOO"), String::from("1")),
+        (String::from("FOO_BAR"), String::from("2")),
+        (String::from("FOO_BARN"), String::from("3")),
+    ]);
+
+    ignore_rules.remove_ignored_env_vars(&mut env_vars);
+
+    assert_eq!(
+        env_vars,
+        EnvVars::from_iter([(String::from("FOO_BARN"), String::from("3"))])
+    );
+}
+
+#[test]
+fn remove_ignored_env_vars_drops_exported_functions_whatever_their_key() {
+    let mut env_vars = EnvVars::from_iter([
+        (
+            String::from("BASH_FUNC_greet%%"),
+            String::from("() {  echo hi\n}"),
+        ),
+        (
+            String::from("greet"),
+            String::from("() {  echo \"a=b\"\n  echo bye\n}"),
+        ),
+        (String::from("SPACED"), String::from(" ()  { :; }")),
+        (String::from("PARENS"), String::from("(not a function)")),
+        (String::from("EMPTY_PARENS"), String::from("()")),
+    ]);
+
+    remove_ignored_env_vars(&mut env_vars);
+
+    assert_eq!(
+        env_vars,
+        EnvVars::from_iter([
+            (String::from("PARENS"), String::from("(not a function)")),
+            (String::from("EMPTY_PARENS"), String::from("()")),
+        ])
+    );
+    assert!(exported_function_value("() { :; }"));
+    assert!(!exported_function_value("{ () }"));
+}
+
+#[test]
+fn ignore_rules_add_keys_and_prefixes_to_the_defaults() {
+    let ignore_rules = IgnoreRules::new().key("NOISY").prefix("TMUX_");
+
+    assert!(ignore_rules.is_ignored("NOISY"));
+    assert!(ignore_rules.is_ignored("TMUX_PANE"));
+    assert!(ignore_rules.is_ignored("SHLVL"));
+    assert!(ignore_rules.is_ignored("BASH_FUNC_foo%%"));
+    assert!(!ignore_rules.is_ignored("NOISY_NOT"));
+}
+
+#[test]
+fn ignore_rules_keep_volatile_pwd_while_dropping_the_rest() {
+    let ignore_rules = IgnoreRules::new().keep_volatile("PWD");
+    let mut env_vars = VOLATILE_ENV_VAR_KEYS
+        .iter()
+        .map(|key| (String::from(*key), String::from("value")))
+        .collect::<EnvVars>();
+    ignore_rules.remove_ignored_env_vars(&mut env_vars);
+
+    assert_eq!(env_vars.keys().collect::<Vec<_>>(), ["PWD"]);
+    assert!(IgnoreRules::new().is_ignored("PWD"));
+    assert!(ignore_rules.is_ignored("DIRENV_IN_ENVRC"));
+}
+
+#[test]
+fn normalize_line_endings_of_crlf_terminated_values() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let bash_script_path = tempdir.path().join("my_bash_script.sh");
+    fs::write(
+        &bash_script_path,
+        "export CRLF_VAR=$'value\\r'\nexport MULTI_LINE_CRLF_VAR=$'line1\\r\\nline2\\r'",
+    )
+    .unwrap();
+
+    let env_vars = get_env_vars_from_bash(BashSource::File(bash_script_path), None).unwrap();
+    assert_eq!(env_vars.get("CRLF_VAR").unwrap(), "value\r");
+
+    {
+        let mut env_vars = env_vars.clone();
+        normalize_line_endings(&mut env_vars, LineEndings::Keep);
+        assert_eq!(env_vars.get("CRLF_VAR").unwrap(), "value\r");
+    }
+
+    {
+        let mut env_vars = env_vars.clone();
+        normalize_line_endings(&mut env_vars, LineEndings::StripTrailingCr);
+        assert_eq!(env_vars.get("CRLF_VAR").unwrap(), "value");
+        assert_eq!(
+            env_vars.get("MULTI_LINE_CRLF_VAR").unwrap(),
+            "line1\r\nline2"
+        );
+    }
+
+    {
+        let mut env_vars = env_vars.clone();
+        normalize_line_endings(&mut env_vars, LineEndings::NormalizeCrlf);
+        assert_eq!(env_vars.get("CRLF_VAR").unwrap(), "value");
+        assert_eq!(env_vars.get("MULTI_LINE_CRLF_VAR").unwrap(), "line1\nline2");
+    }
+}
+
+#[test]
+fn remove_invalid_env_var_keys_returns_removed_keys() {
+    assert!(valid_env_var_key("_PRIVATE"));
+    assert!(valid_env_var_key("VAR123"));
+    assert!(!valid_env_var_key(""));
+    assert!(!valid_env_var_key("123VAR"));
+    assert!(!valid_env_var_key("BASH_FUNC_foo%%"));
+
+    let mut env_vars = EnvVars::from_iter([
+        ("VALID".to_string(), "value".to_string()),
+        (
+            "BASH_FUNC_foo%%".to_string(),
+            "() {  echo foo\n}".to_string(),
+        ),
+        ("INVALID-KEY".to_string(), "value".to_string()),
+    ]);
+
+    assert_eq!(
+        remove_invalid_env_var_keys(&mut env_vars),
+        vec!["BASH_FUNC_foo%%".to_string(), "INVALID-KEY".to_string()]
+    );
+    assert_eq!(
+        env_vars,
+        EnvVars::from_iter([("VALID".to_string(), "value".to_string())])
+    );
+}
+
+#[test]
+fn bash_env_extractor_with_inherited_env_and_normalized_line_endings() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let bash_script_path = tempdir.path().join("my_bash_script.sh");
+    fs::write(&bash_script_path, "export CRLF_VAR=$'value\\r'").unwrap();
+
+    let env_vars = BashEnvExtractor::new()
+        .env_base(EnvBase::Inherit)
+        .line_endings(LineEndings::StripTrailingCr)
+        .run(BashSource::File(bash_script_path.clone()))
+        .unwrap();
+    assert_eq!(env_vars.get("CRLF_VAR").unwrap(), "value");
+    assert_eq!(env_vars.get("HOME"), env::var("HOME").ok().as_ref());
+
+    let env_vars = BashEnvExtractor::new()
+        .env_base(EnvBase::Vars(EnvVars::from_iter([(
+            String::from("BASE_VAR"),
+            String::from("base"),
+        )])))
+        .run(BashSource::File(bash_script_path))
+        .unwrap();
+    assert_eq!(env_vars.get("CRLF_VAR").unwrap(), "value\r");
+    assert_eq!(env_vars.get("BASE_VAR").unwrap(), "base");
+    assert_eq!(env_vars.get("HOME"), None);
+}
+
+#[test]
+fn remove_env_vars_with_prefix_keeps_other_env_vars() {
+    let mut env_vars = EnvVars::from_iter([
+        (String::from("MYTOOL_STATE"), String::from("control")),
+        (String::from("HOME"), String::from("/home/me")),
+        (String::from("NOT_MYTOOL_VAR"), String::from("kept")),
+    ]);
+
+    remove_env_vars_with_prefix(&mut env_vars, "MYTOOL_");
+
+    assert_eq!(
+        env_vars,
+        EnvVars::from_iter([
+            (String::from("HOME"), String::from("/home/me")),
+            (String::from("NOT_MYTOOL_VAR"), String::from("kept")),
+        ])
+    );
+}
+
+#[test]
+fn bash_env_extractor_keeps_excluded_base_prefix_out_of_inherited_base() {
+    // NB: Cargo sets `CARGO_*` env vars for test processes, so nothing needs setting here
+    let source = BashSource::Script("export CAPTURED=yes".into());
+    let env_vars = BashEnvExtractor::new()
+        .env_base(EnvBase::Inherit)
+        .excluded_base_prefix(Some("CARGO_"))
+        .run(&source)
+        .unwrap();
+    assert_eq!(env_vars.get("CAPTURED").unwrap(), "yes");
+    assert!(!env_vars.keys().any(|key| key.starts_with("CARGO_")));
+
+    let env_vars = BashEnvExtractor::new()
+        .env_base(EnvBase::Inherit)
+        .run(&source)
+        .unwrap();
+    assert_eq!(
+        env_vars.get("CARGO_MANIFEST_DIR").unwrap(),
+        env!("CARGO_MANIFEST_DIR")
+    );
+}
+
+#[test]
+fn bash_env_extractor_errors_clearly_when_interpreter_is_missing() {
+    let result = BashEnvExtractor::new()
+        .interpreter("envoluntary-nonexistent-bash")
+        .run(BashSource::File("/some/project/.envrc".into()));
+
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "envoluntary-nonexistent-bash not found on PATH; cannot source /some/project/.envrc"
+    );
+}
+
+#[test]
+fn bash_env_extractor_sources_from_configured_current_dir() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let project_dir = tempdir.path().join("project");
+    fs::create_dir(&project_dir).unwrap();
+
+    let env_vars = BashEnvExtractor::new()
+        .current_dir(&project_dir)
+        .run(BashSource::Script(r#"export SOURCED_FROM="$PWD""#.into()))
+        .unwrap();
+
+    assert_eq!(
+        fs::canonicalize(env_vars.get("SOURCED_FROM").unwrap()).unwrap(),
+        fs::canonicalize(&project_dir).unwrap()
+    );
+}
+
+#[test]
+fn bash_env_extractor_returns_partial_env_and_exit_code_when_failure_allowed() {
+    let source = BashSource::Script("export PARTIAL_VAR=set_before_failure\nexit 1".into());
+
+    let strict_result = BashEnvExtractor::new().run(&source);
+    assert!(strict_result.is_err());
+
+    let output = BashEnvExtractor::new()
+        .allow_failure(true)
+        .run_with_output(&source)
+        .unwrap();
+    assert_eq!(output.exit_code, Some(1));
+    assert_eq!(
+        output.env_vars.get("PARTIAL_VAR").unwrap(),
+        "set_before_failure"
+    );
+}
+
+#[test]
+fn bash_env_extractor_captures_umask_when_enabled() {
+    let source = BashSource::Script("umask 027; export UMASK_SET=yes".into());
+
+    let output = BashEnvExtractor::new()
+        .capture_umask(true)
+        .run_with_output(&source)
+        .unwrap();
+    assert_eq!(output.umask, Some(0o027));
+    assert_eq!(output.env_vars.get("UMASK_SET").unwrap(), "yes");
+
+    let output = BashEnvExtractor::new()
+        .capture_umask(true)
+        .allow_failure(true)
+        .run_with_output(BashSource::Script("umask 077; exit 1".into()))
+        .unwrap();
+    assert_eq!(output.umask, Some(0o077));
+
+    let output = BashEnvExtractor::new().run_with_output(&source).unwrap();
+    assert_eq!(output.umask, None);
+}
+
+#[test]
+fn bash_env_extractor_errexit_fails_on_mid_script_failure() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let rc_file = tempdir.path().join(".envrc");
+    fs::write(
+        &rc_file,
+        "export BEFORE=set\nfalse | cat\nmissing-command-for-errexit-test\nexport AFTER=set\n",
+    )
+    .unwrap();
+    let source = BashSource::File(rc_file);
+
+    let env_vars = BashEnvExtractor::new().run(&source).unwrap();
+    assert_eq!(env_vars.get("BEFORE").unwrap(), "set");
+    assert_eq!(env_vars.get("AFTER").unwrap(), "set");
+
+    let error = BashEnvExtractor::new()
+        .errexit(true)
+        .run(&source)
+        .unwrap_err();
+    assert!(
+        format!("{error:#}").contains("set -euo pipefail"),
+        "{error:#}"
+    );
+
+    let pipe_failure = BashSource::Script("false | cat; export AFTER=set".into());
+    assert!(BashEnvExtractor::new().run(&pipe_failure).is_ok());
+    assert!(
+        BashEnvExtractor::new()
+            .errexit(true)
+            .run(&pipe_failure)
+            .is_err()
+    );
+}
+
+#[test]
+fn capture_env_keys_returns_keys_without_values() {
+    let keys = capture_env_keys(BashSource::Script(
+        "export API_TOKEN=hunter2 EDITOR=vim; f() { :; }; export -f f".into(),
+    ))
+    .unwrap();
+
+    assert_eq!(
+        keys.iter().map(String::as_str).collect::<Vec<_>>(),
+        ["API_TOKEN", "EDITOR"]
+    );
+    assert!(!keys.iter().any(|key| key.contains("hunter2")));
+}
+
+#[test]
+fn bash_env_extractor_captures_xtrace_when_enabled() {
+    let source = BashSource::Script("export TRACED_VAR=traced".into());
+
+    let output = BashEnvExtractor::new()
+        .xtrace(true)
+        .run_with_output(&source)
+        .unwrap();
+    assert!(
+        output
+            .xtrace
+            .unwrap()
+            .lines()
+            .any(|line| line.ends_with("+ export TRACED_VAR=traced"))
+    );
+    assert_eq!(output.env_vars.get("TRACED_VAR").unwrap(), "traced");
+
+    let output = BashEnvExtractor::new().run_with_output(&source).unwrap();
+    assert_eq!(output.xtrace, None);
+}
+
+#[test]
+fn bash_env_extractor_strips_dangerous_env_vars_only_in_strict_mode() {
+    let source = BashSource::Script(
+        "export LD_PRELOAD=/tmp/evil.so IFS=x SAFE_VAR=kept LD_LIBRARY_PATH=/tmp/lib".into(),
+    );
+    let env_base = EnvBase::Vars(EnvVars::from_iter([
+        ("PATH".to_string(), env::var("PATH").unwrap()),
+        ("LD_LIBRARY_PATH".to_string(), "/usr/lib".to_string()),
+    ]));
+
+    let output = BashEnvExtractor::new()
+        .env_base(env_base.clone())
+        .strict(true)
+        .run_with_output(&source)
+        .unwrap();
+    assert_eq!(output.env_vars.get("LD_PRELOAD"), None);
+    assert_eq!(output.env_vars.get("IFS"), None);
+    assert_eq!(output.env_vars.get("LD_LIBRARY_PATH").unwrap(), "/usr/lib");
+    assert_eq!(output.env_vars.get("SAFE_VAR").unwrap(), "kept");
+    assert_eq!(
+        output.stripped_env_vars,
+        vec!["LD_PRELOAD", "LD_LIBRARY_PATH", "IFS"]
+    );
+
+    let output = BashEnvExtractor::new()
+        .env_base(env_base)
+        .run_with_output(&source)
+        .unwrap();
+    assert_eq!(output.env_vars.get("LD_PRELOAD").unwrap(), "/tmp/evil.so");
+    assert!(output.stripped_env_vars.is_empty());
+}
+
+#[test]
+fn bash_env_extractor_sets_rc_path_var_while_sourcing_a_file() {
+    let rc_dir = tempfile::tempdir().unwrap();
+    let rc_file = rc_dir.path().join("project.rc");
+    fs::write(&rc_file, "export SEEN_RC_PATH=\"${MY_RC-unset}\"\n").unwrap();
+
+    let env_vars = BashEnvExtractor::new()
+        .rc_path_var(Some("MY_RC"))
+        .run(BashSource::File(rc_file.clone()))
+        .unwrap();
+    assert_eq!(
+        env_vars.get("SEEN_RC_PATH").unwrap(),
+        &rc_file.display().to_string()
+    );
+    assert_eq!(env_vars.get("MY_RC"), None);
+
+    let env_vars = BashEnvExtractor::new()
+        .rc_path_var(Some("MY_RC"))
+        .run(BashSource::Script(
+            "export SEEN_RC_PATH=\"${MY_RC-unset}\"".into(),
+        ))
+        .unwrap();
+    assert_eq!(env_vars.get("SEEN_RC_PATH").unwrap(), "unset");
+
+    let env_vars = get_env_vars_from_bash(BashSource::File(rc_file), None).unwrap();
+    assert_eq!(env_vars.get("SEEN_RC_PATH").unwrap(), "unset");
+}
+
+#[test]
+fn nul_delimited_env_vars_parses_large_stream_lazily() {
+    let value = "x".repeat(1024);
+    let mut stream = (0..10_000)
+        .flat_map(|i| format!("VAR_{i}={value}\0").into_bytes())
+        .collect::<Vec<_>>();
+    stream.extend_from_slice(b"NOT_AN_ENV_VAR\0MULTI_LINE=a\nb=c\0NO_TRAILING_NUL=last");
+
+    let mut env_vars = NulDelimitedEnvVars::new(BufReader::new(Cursor::new(stream)));
+    assert_eq!(
+        env_vars.next().unwrap().unwrap(),
+        (String::from("VAR_0"), value.clone())
+    );
+
+    let env_vars = env_vars.collect::<io::Result<EnvVars>>().unwrap();
+    assert_eq!(env_vars.len(), 10_001);
+    assert_eq!(env_vars.get("VAR_9999"), Some(&value));
+    assert!(!env_vars.contains_key("NOT_AN_ENV_VAR"));
+    assert_eq!(
+        env_vars.get("MULTI_LINE").map(String::as_str),
+        Some("a\nb=c")
+    );
+    assert_eq!(
+        env_vars.get("NO_TRAILING_NUL").map(String::as_str),
+        Some("last")
+    );
+}
+
+#[test]
+fn nul_delimited_env_vars_errors_on_invalid_utf8() {
+    let mut env_vars = NulDelimitedEnvVars::new(Cursor::new(b"KEY=\xff\0".to_vec()));
+    assert_eq!(
+        env_vars.next().unwrap().unwrap_err().kind(),
+        io::ErrorKind::InvalidData
+    );
+}
+
+#[test]
+fn parse_env0_handles_empty_single_and_equals_in_values() {
+    assert!(parse_env0(b"").unwrap().is_empty());
+    assert!(parse_env0_str("").is_empty());
+
+    let expected = EnvVars::from_iter([(String::from("KEY"), String::from("value"))]);
+    assert_eq!(parse_env0(b"KEY=value").unwrap(), expected);
+    assert_eq!(parse_env0_str("KEY=value"), expected);
+
+    let expected = EnvVars::from_iter([
+        (String::from("OPTS"), String::from("a=b=c")),
+        (String::from("EMPTY"), String::new()),
+    ]);
+    assert_eq!(parse_env0(b"OPTS=a=b=c\0EMPTY=\0").unwrap(), expected);
+    assert_eq!(parse_env0_str("OPTS=a=b=c\0EMPTY=\0"), expected);
+
+    assert_eq!(
+        parse_env0(b"KEY=\xff").unwrap_err().kind(),
+        io::ErrorKind::InvalidData
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn get_env_vars_from_pid_reads_own_environ() {
+    // NB: cargo sets this for test processes at startup, so it's in the initial environ
+    let env_vars = env_hooks::get_env_vars_from_pid(std::process::id()).unwrap();
+    assert_eq!(
+        env_vars.get("CARGO_MANIFEST_DIR").map(String::as_str),
+        Some(env!("CARGO_MANIFEST_DIR"))
+    );
+
+    assert!(env_hooks::get_env_vars_from_pid(u32::MAX).is_err());
+}
+
+#[cfg(all(windows, feature = "powershell"))]
+#[test]
+fn get_env_vars_from_powershell_returns_set_vars() {
+    let env_vars = env_hooks::get_env_vars_from_powershell(
+        "$env:POWERSHELL_VAR = 'it''s set'; Remove-Item Env:UNSET_VAR",
+        Some(EnvVars::from_iter(
+            get_env_vars_from_current_process()
+                .into_iter()
+                .chain([(String::from("UNSET_VAR"), String::from("gone"))]),
+        )),
+    )
+    .unwrap();
+
+    assert_eq!(
+        env_vars.get("POWERSHELL_VAR").map(String::as_str),
+        Some("it's set")
+    );
+    assert!(!env_vars.contains_key("UNSET_VAR"));
+}
+
+#[test]
+fn bash_env_extractor_run_sources_reports_each_source_in_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let outer_rc = dir.path().join("outer.envrc");
+    let inner_rc = dir.path().join("inner.envrc");
+    let failing_rc = dir.path().join("failing.envrc");
+    fs::write(&outer_rc, "export LAYER=outer\nexport OUTER=1\n").unwrap();
+    fs::write(&inner_rc, "export LAYER=\"$LAYER,inner\"\n").unwrap();
+    fs::write(&failing_rc, "false\n").unwrap();
+
+    let mut events = vec![];
+    let env_vars = BashEnvExtractor::new()
+        .run_sources(
+            [
+                BashSource::File(outer_rc.clone()),
+                BashSource::File(inner_rc.clone()),
+            ],
+            |event| {
+                events.push(match event {
+                    SourcingEvent::Started(source) => format!("started {source}"),
+                    SourcingEvent::Finished(source, result) => {
+                        format!("finished {source}: {}", result.is_ok())
+                    }
+                })
+            },
+        )
+        .unwrap();
+    assert_eq!(
+        env_vars.get("LAYER").map(String::as_str),
+        Some("outer,inner")
+    );
+    assert_eq!(env_vars.get("OUTER").map(String::as_str), Some("1"));
+    assert_eq!(
+        events,
+        [
+            format!("started {}", outer_rc.display()),
+            format!("finished {}: true", outer_rc.display()),
+            format!("started {}", inner_rc.display()),
+            format!("finished {}: true", inner_rc.display()),
+        ]
+    );
+
+    let mut failures = vec![];
+    let error = BashEnvExtractor::new()
+        .run_sources(
+            [
+                BashSource::File(outer_rc.clone()),
+                BashSource::File(failing_rc.clone()),
+                BashSource::File(inner_rc.clone()),
+            ],
+            |event| {
+                if let SourcingEvent::Finished(source, Err(e)) = event {
+                    failures.push((source.to_string(), e.to_string()));
+                }
+            },
+        )
+        .unwrap_err();
+    let expected_error = format!("error sourcing {}", failing_rc.display());
+    assert_eq!(error.to_string(), expected_error);
+    assert_eq!(
+        failures,
+        [(failing_rc.display().to_string(), expected_error)]
+    );
+}
+
+#[test]
+fn scrub_secrets_redacts_matching_keys() {
+    let env_vars = EnvVars::from_iter([
+        ("GITHUB_TOKEN".to_string(), "ghp_abc".to_string()),
+        ("AWS_SECRET".to_string(), "shh".to_string()),
+        ("api_key".to_string(), "123".to_string()),
+        (
+            "DB_PASSWORD_FILE".to_string(),
+            "/run/secrets/db".to_string(),
+        ),
+        ("PATH".to_string(), "/usr/bin".to_string()),
+        ("KEYBOARD".to_string(), "us".to_string()),
+    ]);
+
+    assert_eq!(
+        scrub_secrets(&env_vars, &DEFAULT_SECRET_PATTERNS),
+        EnvVars::from_iter([
+            ("GITHUB_TOKEN".to_string(), "***".to_string()),
+            ("AWS_SECRET".to_string(), "***".to_string()),
+            ("api_key".to_string(), "***".to_string()),
+            ("DB_PASSWORD_FILE".to_string(), "***".to_string()),
+            ("PATH".to_string(), "/usr/bin".to_string()),
+            ("KEYBOARD".to_string(), "us".to_string()),
+        ])
+    );
+    assert_eq!(
+        scrub_secrets(&env_vars, &[regex::Regex::new("^PATH$").unwrap()]).get("PATH"),
+        Some(&"***".to_string())
+    );
+}
+
+#[test]
+fn env_diff_scrub_secrets_redacts_added_changed_and_removed() {
+    let old = EnvVars::from_iter([
+        ("API_TOKEN".to_string(), "old".to_string()),
+        ("OLD_SECRET".to_string(), "gone".to_string()),
+        ("PATH".to_string(), "/usr/bin".to_string()),
+    ]);
+    let new = EnvVars::from_iter([
+        ("API_TOKEN".to_string(), "new".to_string()),
+        ("NEW_PASSWORD".to_string(), "hunter2".to_string()),
+        ("PATH".to_string(), "/bin".to_string()),
+    ]);
+
+    let env_diff = EnvDiff::new(&old, &new).scrub_secrets(&DEFAULT_SECRET_PATTERNS);
+    assert_eq!(env_diff.added.get("NEW_PASSWORD").unwrap(), "***");
+    assert_eq!(
+        env_diff.changed.get("API_TOKEN").unwrap(),
+        &("***".to_string(), "***".to_string())
+    );
+    assert_eq!(
+        env_diff.changed.get("PATH").unwrap(),
+        &("/usr/bin".to_string(), "/bin".to_string())
+    );
+    assert_eq!(env_diff.removed.get("OLD_SECRET").unwrap(), "***");
+}
+
+#[test]
+fn bash_env_extractor_minimal_env_base_only_sees_base_vars() {
+    let bash_path = env::var("NIX_BIN_BASH").unwrap_or_else(|_| String::from("/bin/bash"));
+    let env_vars = BashEnvExtractor::new()
+        .interpreter(bash_path)
+        .env_base(EnvBase::Minimal)
+        .run(BashSource::Script(
+            "export SEEN=\"PATH=$PATH HOME=$HOME CARGO_MANIFEST_DIR=${CARGO_MANIFEST_DIR-unset}\""
+                .into(),
+        ))
+        .unwrap();
+
+    assert_eq!(
+        env_vars.get("SEEN"),
+        Some(&format!(
+            "PATH=/usr/bin:/bin HOME={} CARGO_MANIFEST_DIR=unset",
+            env::var("HOME").unwrap_or_default()
+        ))
+    );
+    assert!(minimal_base().keys().all(|key| {
+        ["HOME", "USER", "LOGNAME", "TERM", "LANG", "TZ", "PATH"].contains(&key.as_str())
+    }));
+}
+
+#[test]
+fn normalize_keys_ci_merges_colliding_keys() {
+    let env_vars = EnvVars::from_iter([
+        ("Path".to_string(), "C:\\Windows".to_string()),
+        ("PATH".to_string(), "C:\\Tools;C:\\Windows".to_string()),
+        ("path".to_string(), "ignored".to_string()),
+        ("TEMP".to_string(), "C:\\Temp".to_string()),
+        ("windir".to_string(), "C:\\Windows".to_string()),
+        ("ComSpec".to_string(), "cmd.exe".to_string()),
+        ("COMSPEC".to_string(), "powershell.exe".to_string()),
+        ("ProgramData".to_string(), "C:\\ProgramData".to_string()),
+        ("programdata".to_string(), "D:\\ProgramData".to_string()),
+    ]);
+
+    assert_eq!(
+        normalize_keys_ci(env_vars).into_iter().collect::<Vec<_>>(),
+        [
+            ("PATH".to_string(), "C:\\Tools;C:\\Windows".to_string()),
+            ("TEMP".to_string(), "C:\\Temp".to_string()),
+            ("WINDIR".to_string(), "C:\\Windows".to_string()),
+            ("COMSPEC".to_string(), "powershell.exe".to_string()),
+            ("PROGRAMDATA".to_string(), "D:\\ProgramData".to_string()),
+        ]
+    );
+}
@@ -1,6 +1,11 @@
 mod nix_command;
+mod nix_flake_check;
 mod nix_profile_cache;
 mod nix_version_check;
+mod profile_rc;
 
+pub use nix_command::NixCommandError;
+pub use nix_flake_check::*;
 pub use nix_profile_cache::*;
 pub use nix_version_check::*;
+pub use profile_rc::*;
@@ -1,6 +1,7 @@
 use std::{
+    error::Error,
     ffi::OsStr,
-    num,
+    fmt, num,
     process::{Command, ExitStatus, Stdio},
 };
 
@@ -25,6 +26,29 @@ impl SimplifiedExitOk for ExitStatus {
     }
 }
 
+/// A `nix` command that couldn't be run or exited unsuccessfully, so callers can tell `nix`
+/// failures apart from other errors, e.g. with [`anyhow::Error::downcast_ref`].
+#[derive(Debug)]
+pub struct NixCommandError {
+    message: String,
+    stderr: Option<String>,
+}
+
+impl NixCommandError {
+    /// What `nix` printed to stderr, if it ran at all.
+    pub fn stderr(&self) -> Option<&str> {
+        self.stderr.as_deref()
+    }
+}
+
+impl fmt::Display for NixCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for NixCommandError {}
+
 pub(crate) fn nix(args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> anyhow::Result<String> {
     nix_program("nix", args)
 }
@@ -39,21 +63,31 @@ pub(crate) fn nix_program(
         .args(args)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped());
-    let output = command.output()?;
-    output.status.simplified_exit_ok().map_err(|err| {
-        anyhow::format_err!(
-            "`{} {}` failed with error:\n{}",
-            BString::new(Sh::quote_vec(command.get_program())),
-            BString::new(bstr::join(
-                " ",
-                command
-                    .get_args()
-                    .map(|arg| { BString::new(Sh::quote_vec(arg)) })
-                    .collect::<Vec<_>>()
-            )),
-            err
-        )
+    let output = command.output().map_err(|err| NixCommandError {
+        message: format!(
+            "`{}` couldn't be run: {err}",
+            BString::new(Sh::quote_vec(command.get_program()))
+        ),
+        stderr: None,
     })?;
+    output
+        .status
+        .simplified_exit_ok()
+        .map_err(|err| NixCommandError {
+            message: format!(
+                "`{} {}` failed with error:\n{}",
+                BString::new(Sh::quote_vec(command.get_program())),
+                BString::new(bstr::join(
+                    " ",
+                    command
+                        .get_args()
+                        .map(|arg| { BString::new(Sh::quote_vec(arg)) })
+                        .collect::<Vec<_>>()
+                )),
+                err
+            ),
+            stderr: Some(String::from_utf8_lossy(&output.stderr).into_owned()),
+        })?;
     let stdout_content = String::from_utf8_lossy(&output.stdout).to_string();
     Ok(stdout_content)
 }
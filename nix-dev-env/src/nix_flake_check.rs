@@ -0,0 +1,7 @@
+use crate::nix_command;
+
+/// Checks that `nix` can evaluate the metadata of a flake, returning the `nix flake metadata`
+/// output.
+pub fn check_flake_metadata(flake_reference: &str) -> anyhow::Result<String> {
+    nix_command::nix(["flake", "metadata", "--no-write-lock-file", flake_reference])
+}
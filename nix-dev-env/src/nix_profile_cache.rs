@@ -1,16 +1,69 @@
 use std::{
-    ffi::OsStr,
-    fs,
-    io::{self, Write},
+    collections::BTreeSet,
+    env,
+    ffi::{OsStr, OsString},
+    fmt, fs,
+    io::{self, Read, Write},
+    num::NonZeroUsize,
+    os::unix::{ffi::OsStrExt, fs::symlink},
     path::{Path, PathBuf},
     process,
-    time::SystemTime,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
+use env_hooks::EnvVars;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use serde_json::Value;
 use sha1::{Digest, Sha1};
 
-use crate::nix_command;
+use crate::nix_command::{self, NixCommandError};
+use crate::profile_rc::{
+    get_env_vars_from_rc, merge_path_into_rc, normalize_rc, remove_env_vars_from_rc,
+};
+
+const NIX_STORE_PREFIX: &str = "/nix/store/";
+
+/// Spawning many `nix` processes at once is counterproductive, so cap how many run in parallel by
+/// default.
+const DEFAULT_MAX_JOBS: usize = 4;
+
+/// Parts of the errors `nix` prints when it can't connect to the Nix daemon.
+const DAEMON_FAILURE_MESSAGES: &[&str] = &[
+    "cannot connect to socket",
+    "cannot open connection to remote store 'daemon'",
+];
+
+/// Parts of the errors `nix` prints when `nix flake archive` can't list a flake's inputs at all,
+/// e.g. on a Nix without the command, rather than failing to fetch them.
+const ARCHIVE_UNSUPPORTED_MESSAGES: &[&str] = &[
+    "is not a recognised command",
+    "unrecognised flag",
+    "is not a flake",
+    "experimental Nix feature 'flakes' is disabled",
+];
+
+/// Nix's per-user gcroots dir, see [`InputGcrootsDir::PerUser`].
+const NIX_PER_USER_GCROOTS_DIR: &str = "/nix/var/nix/gcroots/per-user";
+
+/// Deep enough for any real flake's input tree, while still bounding recursion on malformed
+/// `nix flake archive` output.
+const DEFAULT_MAX_INPUT_DEPTH: usize = 64;
+
+/// A gitignore-style file in a flake's dir, listing watched files that shouldn't invalidate the
+/// cache.
+const WATCH_IGNORE_FILE_NAME: &str = ".envoluntaryignore";
+
+/// The extension of a zstd-compressed profile rc, see [`NixProfileCacheOptions::compress_rc`].
+const COMPRESSED_RC_EXTENSION: &str = "zst";
+
+/// The extension appended to a profile rc's file name for its checksum sidecar, see
+/// [`RebuildReason::ProfileRcCorrupted`].
+const RC_CHECKSUM_EXTENSION: &str = "sha1";
 
 #[derive(Debug, Clone)]
 pub struct NixProfileCache {
@@ -18,9 +71,332 @@ pub struct NixProfileCache {
     flake_inputs_dir: PathBuf,
     flake_reference: FlakeReference,
     evaluation_mode: EvaluationMode,
+    options: NixProfileCacheOptions,
     files_to_watch: Vec<PathBuf>,
+    hash: String,
     profile_symlink: PathBuf,
     profile_rc_file: PathBuf,
+    profile_compressed_rc_file: PathBuf,
+    profile_nix_version_file: PathBuf,
+}
+
+/// Options for how a [`NixProfileCache`] is updated.
+#[derive(Debug, Clone, Default)]
+pub struct NixProfileCacheOptions {
+    /// Skip creating a gcroot for each flake input, only keeping the profile's gcroot.
+    ///
+    /// Useful where Nix garbage collection is disabled or managed externally.
+    pub no_input_gcroots: bool,
+    /// The most `nix` processes to run at once, defaulting to the number of CPUs capped at 4.
+    pub jobs: Option<NonZeroUsize>,
+    /// Called at each phase of [`NixProfileCache::update`], e.g. to report progress.
+    pub on_update_phase: Option<fn(&UpdatePhase)>,
+    /// The longest a cached profile is used for before being updated, even if no watched files
+    /// changed, e.g. for flakes referencing unpinned remote state.
+    pub cache_ttl: Option<Duration>,
+    /// The `nix` program to run, `nix` (looked up on `$PATH`) by default.
+    ///
+    /// Mostly useful for substituting a fake `nix` in tests.
+    pub nix_program: Option<OsString>,
+    /// Mixed into the profile hash, namespacing the cache, e.g. so a new cache format doesn't
+    /// reuse profiles written by an older version.
+    pub cache_salt: Option<String>,
+    /// Write the profile rc zstd-compressed, as `flake-profile-<hash>.rc.zst`.
+    ///
+    /// Profiles cached in the other format are still used until they need an update.
+    pub compress_rc: bool,
+    /// Env vars stripped from the profile rc, so a dev shell can't clobber them, e.g. `TMPDIR`.
+    pub excluded_env_vars: Vec<String>,
+    /// Check that the profile's store path still exists before using a cached profile, updating
+    /// it if not, e.g. after the gcroot was removed and the store path garbage collected.
+    pub verify_gcroots: bool,
+    /// Retry evaluating the flake with `--store local` if it failed to connect to the Nix
+    /// daemon, e.g. when the daemon is restarting.
+    pub local_store_fallback: bool,
+    /// When a watched file changed but the flake's dev env didn't, e.g. after a no-op
+    /// `flake.lock` bump, alias the new profile to the previous one with a symlink instead of
+    /// rooting it and all the flake inputs again.
+    pub alias_unchanged_profiles: bool,
+    /// Where to create the gcroots for flake inputs, `flake-inputs` in the cache dir by default.
+    pub input_gcroots_dir: InputGcrootsDir,
+    /// How deep to follow nested flake inputs when creating gcroots, 64 by default.
+    ///
+    /// Inputs nested deeper are skipped with an [`UpdateWarning::InputDepthLimitReached`].
+    pub max_input_depth: Option<usize>,
+    /// The most flake inputs to create gcroots for, in the sorted order of their store paths,
+    /// since rooting hundreds of inputs is slow and clutters the gcroots dir. All by default.
+    ///
+    /// The rest are skipped with an [`UpdateWarning::InputGcrootsCapped`].
+    pub max_input_gcroots: Option<usize>,
+    /// Called with anything worth warning about during [`NixProfileCache::update`] that doesn't
+    /// fail it.
+    pub on_update_warning: Option<fn(&UpdateWarning)>,
+    /// A file the profile hash is written to after each successful update, e.g. for a language
+    /// server to watch so it restarts when a new environment is loaded.
+    pub ready_marker: Option<PathBuf>,
+    /// A shell command run with `sh -c` after each successful update, with the profile hash as
+    /// `$1`. Its output goes to stderr.
+    pub ready_command: Option<String>,
+    /// Rewrite the profile rc with [`normalize_rc`], sorting its exports by key, so the cached
+    /// rc is stable across builds and diffs between profiles are meaningful.
+    pub normalize_rc: bool,
+    /// What of a local flake's watched files goes into the profile hash, their contents by
+    /// default.
+    pub hash_scope: HashScope,
+    /// Extra args for `nix print-dev-env`, passed just before the flake reference, e.g.
+    /// `--override-input nixpkgs github:NixOS/nixpkgs/nixos-unstable`.
+    ///
+    /// They're mixed into the profile hash, since they can change the dev env.
+    pub print_dev_env_args: Vec<OsString>,
+    /// Rewrite the profile rc's `PATH` assignments with [`merge_path_into_rc`] to keep this
+    /// `PATH` after the dev shell's entries, rather than replacing it.
+    ///
+    /// It's mixed into the profile hash, since it's baked into the cached rc.
+    pub merge_path: Option<String>,
+}
+
+/// What of each watched file [`FilesHasher`] hashes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashScope {
+    /// The file's contents.
+    #[default]
+    Contents,
+    /// The file's path, size and mtime, without reading it, for huge watched files where
+    /// reading them is the bottleneck.
+    ///
+    /// Weaker than hashing contents: a change that keeps the size and mtime (e.g. a restored
+    /// mtime, or a coarse-grained mtime) goes unnoticed, while touching a file without changing
+    /// it updates the profile.
+    MetadataOnly,
+}
+
+/// Where [`NixProfileCache`] creates the gcroots for flake inputs.
+///
+/// Gcroots on a volatile filesystem (e.g. a cache dir on a tmpfs) don't survive a reboot, leaving
+/// flake inputs unprotected from garbage collection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum InputGcrootsDir {
+    /// `flake-inputs` in the cache dir.
+    #[default]
+    CacheDir,
+    /// Nix's per-user gcroots dir, `/nix/var/nix/gcroots/per-user/$USER`.
+    PerUser,
+    /// The given dir.
+    Dir(PathBuf),
+}
+
+impl InputGcrootsDir {
+    /// The dir for the gcroots of the flake inputs cached in `cache_dir`.
+    ///
+    /// Outside the cache dir, each cache gets its own `flake-inputs-<cache dir name>` sub dir,
+    /// since the dir is shared.
+    fn resolve(&self, cache_dir: &Path) -> anyhow::Result<PathBuf> {
+        let gcroots_dir = match self {
+            Self::CacheDir => return Ok(cache_dir.join("flake-inputs")),
+            Self::PerUser => {
+                let user = env::var_os("USER")
+                    .or_else(|| env::var_os("LOGNAME"))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Couldn't find $USER for the per-user gcroots dir.")
+                    })?;
+                Path::new(NIX_PER_USER_GCROOTS_DIR).join(user)
+            }
+            Self::Dir(dir) => dir.clone(),
+        };
+        let mut sub_dir_name = OsString::from("flake-inputs-");
+        sub_dir_name.push(cache_dir.file_name().unwrap_or(cache_dir.as_os_str()));
+        Ok(gcroots_dir.join(sub_dir_name))
+    }
+}
+
+/// Why [`NixProfileCache::rebuild_reason`] found the cache needs to be updated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RebuildReason {
+    /// The profile hasn't been built yet (or was removed).
+    ProfileMissing,
+    /// A watched file changed since the profile was built.
+    FileChanged(PathBuf),
+    /// The profile is older than [`NixProfileCacheOptions::cache_ttl`].
+    CacheTtlExpired,
+    /// The profile was built by a different version of `nix`.
+    NixVersionChanged,
+    /// The profile's store path no longer exists, see [`NixProfileCacheOptions::verify_gcroots`].
+    ProfileStorePathMissing,
+    /// The profile rc doesn't match the checksum recorded when it was cached, e.g. after a
+    /// truncated write, or the checksum is missing.
+    ProfileRcCorrupted,
+}
+
+impl fmt::Display for RebuildReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ProfileMissing => write!(f, "the profile hasn't been built"),
+            Self::FileChanged(file) => write!(f, "{} changed", file.display()),
+            Self::CacheTtlExpired => write!(f, "the profile is older than the cache TTL"),
+            Self::NixVersionChanged => write!(f, "the nix version changed"),
+            Self::ProfileStorePathMissing => write!(f, "the profile's store path is missing"),
+            Self::ProfileRcCorrupted => write!(f, "the profile rc doesn't match its checksum"),
+        }
+    }
+}
+
+/// The phases of [`NixProfileCache::update`], which can take minutes for a cold cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdatePhase {
+    EvaluatingFlake,
+    CreatingProfileGcroot,
+    ArchivingFlakeInputs,
+    CreatingInputGcroots { done: usize, total: usize },
+}
+
+impl fmt::Display for UpdatePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EvaluatingFlake => write!(f, "evaluating flake…"),
+            Self::CreatingProfileGcroot => write!(f, "creating profile gcroot…"),
+            Self::ArchivingFlakeInputs => write!(f, "archiving flake inputs…"),
+            Self::CreatingInputGcroots { done, total } => {
+                write!(f, "creating flake input gcroots {done}/{total}…")
+            }
+        }
+    }
+}
+
+/// What [`NixProfileCache::update`] built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateSummary {
+    /// The hash in the profile's `flake-profile-<hash>` name.
+    pub hash: String,
+    /// How many flake inputs got a gcroot, or `None` if `nix flake archive` couldn't list them,
+    /// e.g. on a restricted Nix without it, as opposed to the flake having no inputs.
+    pub inputs_rooted: Option<usize>,
+    /// The hash of the unchanged profile this one was aliased to, see
+    /// [`NixProfileCacheOptions::alias_unchanged_profiles`].
+    pub aliased_to: Option<String>,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for UpdateSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(aliased_to) = &self.aliased_to {
+            return write!(
+                f,
+                "aliased profile {} to unchanged profile {aliased_to} in {:.1}s",
+                self.hash,
+                self.elapsed.as_secs_f64()
+            );
+        }
+        match self.inputs_rooted {
+            Some(inputs_rooted) => write!(
+                f,
+                "built profile {}, rooted {inputs_rooted} flake inputs in {:.1}s",
+                self.hash,
+                self.elapsed.as_secs_f64()
+            ),
+            None => write!(
+                f,
+                "built profile {}, without flake input gcroots since nix couldn't list them, in \
+                 {:.1}s",
+                self.hash,
+                self.elapsed.as_secs_f64()
+            ),
+        }
+    }
+}
+
+/// Problems during [`NixProfileCache::update`] that don't fail it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateWarning {
+    /// Flake inputs nested deeper than [`NixProfileCacheOptions::max_input_depth`] were skipped,
+    /// so they don't get gcroots.
+    InputDepthLimitReached { max_depth: usize },
+    /// Evaluating the flake failed to connect to the Nix daemon, so it's retried with
+    /// `--store local`, see [`NixProfileCacheOptions::local_store_fallback`].
+    LocalStoreFallback,
+    /// The flake has more inputs than [`NixProfileCacheOptions::max_input_gcroots`], so the rest
+    /// don't get gcroots.
+    InputGcrootsCapped {
+        max_input_gcroots: usize,
+        total: usize,
+    },
+    /// Writing [`NixProfileCacheOptions::ready_marker`] or running
+    /// [`NixProfileCacheOptions::ready_command`] failed.
+    ReadySignalFailed { reason: String },
+}
+
+impl fmt::Display for UpdateWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InputDepthLimitReached { max_depth } => write!(
+                f,
+                "flake inputs are nested deeper than {max_depth} levels, skipping gcroots for the \
+                 deeper ones"
+            ),
+            Self::LocalStoreFallback => write!(
+                f,
+                "couldn't connect to the nix daemon, retrying with `--store local`"
+            ),
+            Self::InputGcrootsCapped {
+                max_input_gcroots,
+                total,
+            } => write!(
+                f,
+                "only rooting {max_input_gcroots} of {total} flake inputs, skipping gcroots for \
+                 the rest"
+            ),
+            Self::ReadySignalFailed { reason } => {
+                write!(f, "couldn't signal the new profile is ready: {reason}")
+            }
+        }
+    }
+}
+
+impl NixProfileCacheOptions {
+    fn nix_program(&self) -> &OsStr {
+        self.nix_program.as_deref().unwrap_or(OsStr::new("nix"))
+    }
+
+    fn jobs(&self) -> usize {
+        self.jobs.map(NonZeroUsize::get).unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map_or(1, NonZeroUsize::get)
+                .min(DEFAULT_MAX_JOBS)
+        })
+    }
+
+    fn max_input_depth(&self) -> usize {
+        self.max_input_depth.unwrap_or(DEFAULT_MAX_INPUT_DEPTH)
+    }
+
+    /// The salt for the profile hash, which also covers options changing the profile rc.
+    fn hash_salt(&self) -> Option<String> {
+        if self.excluded_env_vars.is_empty()
+            && !self.normalize_rc
+            && self.print_dev_env_args.is_empty()
+            && self.merge_path.is_none()
+        {
+            return self.cache_salt.clone();
+        }
+        let mut salt = self.cache_salt.clone().unwrap_or_default();
+        if !self.excluded_env_vars.is_empty() {
+            salt.push_str(&format!(
+                "\0excluded-env-vars={}",
+                self.excluded_env_vars.join(",")
+            ));
+        }
+        if self.normalize_rc {
+            salt.push_str("\0normalize-rc");
+        }
+        for print_dev_env_arg in &self.print_dev_env_args {
+            salt.push_str("\0print-dev-env-arg=");
+            salt.push_str(&print_dev_env_arg.to_string_lossy());
+        }
+        if let Some(merge_path) = &self.merge_path {
+            salt.push_str("\0merge-path=");
+            salt.push_str(merge_path);
+        }
+        Some(salt)
+    }
 }
 
 impl NixProfileCache {
@@ -29,89 +405,430 @@ impl NixProfileCache {
         flake_reference: &str,
         evaluation_mode: EvaluationMode,
     ) -> anyhow::Result<Self> {
-        let flake_inputs_dir = cache_dir.join("flake-inputs");
+        Self::with_options(
+            cache_dir,
+            flake_reference,
+            evaluation_mode,
+            NixProfileCacheOptions::default(),
+        )
+    }
+
+    pub fn with_options(
+        cache_dir: PathBuf,
+        flake_reference: &str,
+        evaluation_mode: EvaluationMode,
+        options: NixProfileCacheOptions,
+    ) -> anyhow::Result<Self> {
+        let flake_inputs_dir = options.input_gcroots_dir.resolve(&cache_dir)?;
 
         let flake_reference = FlakeReference::parse(flake_reference)?;
 
+        // NB: Impure evaluation can give a different dev env, so it's cached separately
+        let hash_salt = match evaluation_mode {
+            EvaluationMode::Impure => Some(format!(
+                "{}\0impure",
+                options.hash_salt().unwrap_or_default()
+            )),
+            EvaluationMode::Pure => options.hash_salt(),
+        };
         let mut files_to_watch = vec![];
         let hash = if let Some(flake_dir) = &flake_reference.flake_dir {
-            files_to_watch.extend_from_slice(&[
-                flake_dir.join("flake.nix"),
-                flake_dir.join("flake.lock"),
-                flake_dir.join("devshell.toml"),
-            ]);
-            hash_files(&files_to_watch)?
+            let mut hasher = FilesHasher::with_scope(hash_salt.as_deref(), options.hash_scope);
+            let ignore = watch_ignore(flake_dir)?;
+            for filename in ["flake.nix", "flake.lock", "devshell.toml"] {
+                let file_to_watch = flake_dir.join(filename);
+                if ignore.matched(&file_to_watch, false).is_ignore() {
+                    continue;
+                }
+                hasher.update_file(&file_to_watch)?;
+                files_to_watch.push(file_to_watch);
+            }
+            hasher.finalize()?
         } else {
-            hash_flake_reference(&flake_reference.flake_reference_string)?
+            hash_flake_reference(
+                &flake_reference.flake_reference_string,
+                hash_salt.as_deref(),
+            )?
         };
 
         let profile_symlink = cache_dir.join(format!("flake-profile-{}", hash));
         let profile_rc_file = profile_symlink.with_extension("rc");
+        let profile_compressed_rc_file =
+            profile_symlink.with_extension(format!("rc.{COMPRESSED_RC_EXTENSION}"));
+        let profile_nix_version_file = profile_symlink.with_extension("nix-version");
         Ok(Self {
             cache_dir,
             flake_inputs_dir,
             flake_reference,
             evaluation_mode,
+            options,
             files_to_watch,
+            hash,
             profile_symlink,
             profile_rc_file,
+            profile_compressed_rc_file,
+            profile_nix_version_file,
         })
     }
 
     pub fn needs_update(&self) -> anyhow::Result<bool> {
-        let mut need_update = true;
+        Ok(self.rebuild_reason()?.is_some())
+    }
+
+    /// Why the cache needs to be updated, or `None` if it's fresh.
+    pub fn rebuild_reason(&self) -> anyhow::Result<Option<RebuildReason>> {
+        let profile_rc = self.profile_rc();
+        if !profile_rc.is_file() || !self.profile_symlink.is_symlink() {
+            return Ok(Some(RebuildReason::ProfileMissing));
+        }
+        // NB: Unlike `is_symlink`, `exists` follows the symlink into the store
+        if self.options.verify_gcroots && !self.profile_symlink.exists() {
+            return Ok(Some(RebuildReason::ProfileStorePathMissing));
+        }
 
-        if self.profile_rc_file.is_file() && self.profile_symlink.is_symlink() {
-            let profile_rc_mtime = fs::metadata(&self.profile_rc_file)?.modified()?;
+        // NB: An unchanged rc keeps its old mtime on update, unlike the nix version file, so the
+        // latter marks when the profile was last updated
+        let Ok(updated_at) =
+            fs::metadata(&self.profile_nix_version_file).and_then(|meta| meta.modified())
+        else {
+            return Ok(Some(RebuildReason::NixVersionChanged));
+        };
 
-            need_update = self.files_to_watch.iter().any(|file| {
-                fs::metadata(file)
-                    .and_then(|meta| meta.modified())
-                    .unwrap_or(SystemTime::UNIX_EPOCH)
-                    > profile_rc_mtime
-            });
+        let changed_file = self.files_to_watch.iter().find(|file| {
+            fs::metadata(file)
+                .and_then(|meta| meta.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+                > updated_at
+        });
+        if let Some(changed_file) = changed_file {
+            return Ok(Some(RebuildReason::FileChanged(changed_file.clone())));
+        }
+
+        if let Some(cache_ttl) = self.options.cache_ttl
+            && updated_at.elapsed().unwrap_or_default() > cache_ttl
+        {
+            return Ok(Some(RebuildReason::CacheTtlExpired));
+        }
+
+        // NB: A corrupted rc would otherwise be eval'd by the shell
+        let profile_rc_checksum = fs::read_to_string(rc_checksum_file(profile_rc)).ok();
+        if profile_rc_checksum != Some(rc_checksum(&fs::read(profile_rc)?)) {
+            return Ok(Some(RebuildReason::ProfileRcCorrupted));
+        }
+
+        // NB: A different version of nix can print a different dev env for the same flake
+        if fs::read_to_string(&self.profile_nix_version_file).ok() != Some(self.nix_version()?) {
+            return Ok(Some(RebuildReason::NixVersionChanged));
         }
 
-        Ok(need_update)
+        Ok(None)
     }
 
-    pub fn update(&self) -> anyhow::Result<()> {
-        clean_old_gcroots(&self.cache_dir, &self.flake_inputs_dir)?;
+    pub fn update(&self) -> anyhow::Result<UpdateSummary> {
+        let update_summary = self.update_profile()?;
+        self.signal_ready(&update_summary.hash);
+        Ok(update_summary)
+    }
+
+    fn update_profile(&self) -> anyhow::Result<UpdateSummary> {
+        let started_at = Instant::now();
+        ensure_writable_dir(&self.cache_dir)?;
+        let (profile_rc, other_profile_rc) = self.profile_rc_files();
+        let old_profile_rc = fs::read(profile_rc).ok().zip(
+            fs::metadata(profile_rc)
+                .and_then(|meta| meta.modified())
+                .ok(),
+        );
+        let nix_version = self.nix_version()?;
 
         let tmp_profile = self
             .cache_dir
             .join(format!("flake-tmp-profile.{}", process::id()));
 
-        let mut args = vec![OsStr::new("print-dev-env")];
-        if let Some(impure_arg) = self.impure_arg() {
-            args.push(OsStr::new(impure_arg));
+        self.report_update_phase(UpdatePhase::EvaluatingFlake);
+        let stdout_content = self.print_dev_env(Some(&tmp_profile))?;
+
+        let unchanged_profile = if self.options.alias_unchanged_profiles {
+            self.unchanged_profile(stdout_content.as_bytes())?
+        } else {
+            None
+        };
+        // NB: The unchanged profile's gcroots are kept for the alias
+        if unchanged_profile.is_none() {
+            clean_old_gcroots(&self.cache_dir, &self.flake_inputs_dir, &tmp_profile)?;
         }
-        args.extend_from_slice(&[
-            OsStr::new("--no-write-lock-file"),
-            OsStr::new("--profile"),
-            tmp_profile.as_os_str(),
-            OsStr::new(&self.flake_reference.flake_reference_string),
-        ]);
-        let stdout_content = nix_command::nix(args)?;
 
-        fs::File::create(&self.profile_rc_file)?.write_all(stdout_content.as_bytes())?;
+        let profile_rc_content = if self.options.compress_rc {
+            ruzstd::encoding::compress_to_vec(
+                stdout_content.as_bytes(),
+                ruzstd::encoding::CompressionLevel::Fastest,
+            )
+        } else {
+            stdout_content.into_bytes()
+        };
+        // NB: An identical rc keeps its mtime, which would otherwise be bumped needlessly for other
+        // tools watching it
+        let profile_rc_mtime = old_profile_rc
+            .filter(|(old_profile_rc_content, _)| *old_profile_rc_content == profile_rc_content)
+            .map(|(_, old_profile_rc_mtime)| old_profile_rc_mtime);
+        write_file_atomically(profile_rc, &profile_rc_content, profile_rc_mtime)?;
+        write_file_atomically(
+            &rc_checksum_file(profile_rc),
+            rc_checksum(&profile_rc_content).as_bytes(),
+            None,
+        )?;
+        // NB: Otherwise a stale rc in the other format could be used if the option is toggled
+        for other_file in [other_profile_rc, &rc_checksum_file(other_profile_rc)] {
+            if let Err(e) = fs::remove_file(other_file)
+                && e.kind() != io::ErrorKind::NotFound
+            {
+                return Err(e.into());
+            }
+        }
+        fs::write(&self.profile_nix_version_file, nix_version)?;
 
+        if let Some((unchanged_hash, unchanged_symlink_target)) = unchanged_profile {
+            fs::remove_file(&tmp_profile)?;
+            if let Err(e) = fs::remove_file(&self.profile_symlink)
+                && e.kind() != io::ErrorKind::NotFound
+            {
+                return Err(e.into());
+            }
+            symlink(unchanged_symlink_target, &self.profile_symlink)?;
+            return Ok(UpdateSummary {
+                hash: self.hash.clone(),
+                inputs_rooted: Some(0),
+                aliased_to: Some(unchanged_hash),
+                elapsed: started_at.elapsed(),
+            });
+        }
+
+        self.report_update_phase(UpdatePhase::CreatingProfileGcroot);
         self.add_gcroot(&tmp_profile, &self.profile_symlink)?;
         fs::remove_file(&tmp_profile)?;
 
-        if self.flake_reference.flake_dir.is_some() {
-            for input in self.get_flake_input_paths()? {
-                let store_path = PathBuf::from("/nix/store").join(&input);
-                let symlink_path = self.flake_inputs_dir.join(&input);
-                self.add_gcroot(&store_path, &symlink_path)?;
-            }
+        let mut inputs_rooted = Some(0);
+        if self.flake_reference.flake_dir.is_some() && !self.options.no_input_gcroots {
+            self.report_update_phase(UpdatePhase::ArchivingFlakeInputs);
+            // NB: The profile's own gcroot still protects the dev env, so a Nix that can't list
+            // flake inputs only loses their gcroots
+            inputs_rooted = match self.get_flake_input_paths() {
+                Ok(mut input_paths) => {
+                    if let Some(max_input_gcroots) = self.options.max_input_gcroots
+                        && input_paths.len() > max_input_gcroots
+                    {
+                        self.report_update_warning(UpdateWarning::InputGcrootsCapped {
+                            max_input_gcroots,
+                            total: input_paths.len(),
+                        });
+                        input_paths.truncate(max_input_gcroots);
+                    }
+                    self.add_input_gcroots(&input_paths)?;
+                    Some(input_paths.len())
+                }
+                Err(e) if nix_failed_with(&e, ARCHIVE_UNSUPPORTED_MESSAGES) => None,
+                Err(e) => return Err(e),
+            };
         }
 
-        Ok(())
+        Ok(UpdateSummary {
+            hash: self.hash.clone(),
+            inputs_rooted,
+            aliased_to: None,
+            elapsed: started_at.elapsed(),
+        })
     }
 
+    /// Evaluates the flake's dev env rc without caching it, so nothing is written to the cache
+    /// dir and no gcroots are created.
+    ///
+    /// For one-off runs, e.g. in CI, where a persistent cache isn't wanted.
+    pub fn evaluate(&self) -> anyhow::Result<String> {
+        self.report_update_phase(UpdatePhase::EvaluatingFlake);
+        self.print_dev_env(None)
+    }
+
+    /// The profile rc, which may be compressed, see [`NixProfileCache::read_profile_rc`].
+    ///
+    /// Prefers the format chosen by [`NixProfileCacheOptions::compress_rc`], falling back to a
+    /// profile rc cached in the other format.
     pub fn profile_rc(&self) -> &Path {
-        &self.profile_rc_file
+        let (profile_rc, other_profile_rc) = self.profile_rc_files();
+        if !profile_rc.is_file() && other_profile_rc.is_file() {
+            other_profile_rc
+        } else {
+            profile_rc
+        }
+    }
+
+    /// Whether [`NixProfileCache::profile_rc`] is zstd-compressed, so can't be sourced directly.
+    pub fn profile_rc_is_compressed(&self) -> bool {
+        self.profile_rc().extension() == Some(OsStr::new(COMPRESSED_RC_EXTENSION))
+    }
+
+    /// Reads the profile rc, decompressing it if needed.
+    pub fn read_profile_rc(&self) -> anyhow::Result<Vec<u8>> {
+        read_rc_file(self.profile_rc())
+    }
+
+    /// The env vars the profile rc would set, see [`get_env_vars_from_rc`].
+    pub fn profile_rc_env_vars(&self) -> anyhow::Result<EnvVars> {
+        Ok(get_env_vars_from_rc(&String::from_utf8_lossy(
+            &self.read_profile_rc()?,
+        )))
+    }
+
+    /// The profile rc in the configured format, and in the other format.
+    fn profile_rc_files(&self) -> (&Path, &Path) {
+        if self.options.compress_rc {
+            (&self.profile_compressed_rc_file, &self.profile_rc_file)
+        } else {
+            (&self.profile_rc_file, &self.profile_compressed_rc_file)
+        }
+    }
+
+    /// The most recently updated profile with another hash, if its rc is `profile_rc_content`,
+    /// along with the target for a symlink aliasing it.
+    ///
+    /// The target is the gcroot symlink itself, relative to the cache dir, so aliases of aliases
+    /// don't chain.
+    fn unchanged_profile(
+        &self,
+        profile_rc_content: &[u8],
+    ) -> anyhow::Result<Option<(String, PathBuf)>> {
+        let Ok(entries) = fs::read_dir(&self.cache_dir) else {
+            return Ok(None);
+        };
+        let Some((_, hash)) = entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let hash = entry
+                    .file_name()
+                    .to_str()?
+                    .strip_prefix("flake-profile-")?
+                    .strip_suffix(".nix-version")?
+                    .to_string();
+                let updated_at = entry.metadata().and_then(|meta| meta.modified()).ok()?;
+                (hash != self.hash).then_some((updated_at, hash))
+            })
+            .max()
+        else {
+            return Ok(None);
+        };
+
+        let symlink_name = format!("flake-profile-{hash}");
+        let symlink = self.cache_dir.join(&symlink_name);
+        let Ok(symlink_target) = fs::read_link(&symlink) else {
+            return Ok(None);
+        };
+        let Some(rc_file) = cached_rc_file(&self.cache_dir, &hash) else {
+            return Ok(None);
+        };
+        if read_rc_file(&rc_file)? != profile_rc_content {
+            return Ok(None);
+        }
+
+        let symlink_target = if symlink_target.is_relative() {
+            symlink_target
+        } else {
+            PathBuf::from(symlink_name)
+        };
+        Ok(Some((hash, symlink_target)))
+    }
+
+    /// Runs `nix print-dev-env`, recording the dev env in `profile` if given, and returns the
+    /// rc without the excluded env vars.
+    fn print_dev_env(&self, profile: Option<&Path>) -> anyhow::Result<String> {
+        let mut args = vec![OsStr::new("print-dev-env")];
+        if let Some(impure_arg) = self.impure_arg() {
+            args.push(OsStr::new(impure_arg));
+        }
+        args.push(OsStr::new("--no-write-lock-file"));
+        if let Some(profile) = profile {
+            args.extend_from_slice(&[OsStr::new("--profile"), profile.as_os_str()]);
+        }
+        args.extend(
+            self.options
+                .print_dev_env_args
+                .iter()
+                .map(OsString::as_os_str),
+        );
+        args.push(OsStr::new(&self.flake_reference.flake_reference_string));
+        let mut stdout_content = match nix_command::nix_program(self.options.nix_program(), &args) {
+            Err(e)
+                if self.options.local_store_fallback
+                    && nix_failed_with(&e, DAEMON_FAILURE_MESSAGES) =>
+            {
+                self.report_update_warning(UpdateWarning::LocalStoreFallback);
+                let mut local_store_args = vec![OsStr::new("--store"), OsStr::new("local")];
+                local_store_args.extend(args);
+                nix_command::nix_program(self.options.nix_program(), local_store_args)?
+            }
+            res => res?,
+        };
+        if !self.options.excluded_env_vars.is_empty() {
+            stdout_content =
+                remove_env_vars_from_rc(&stdout_content, &self.options.excluded_env_vars);
+        }
+        if self.options.normalize_rc {
+            stdout_content = normalize_rc(&stdout_content);
+        }
+        if let Some(merge_path) = &self.options.merge_path {
+            stdout_content = merge_path_into_rc(&stdout_content, merge_path);
+        }
+        Ok(stdout_content)
+    }
+
+    /// The `flake-profile-<hash>` gcroot symlink, which only exists once the cache is updated.
+    pub fn profile_path(&self) -> &Path {
+        &self.profile_symlink
+    }
+
+    /// The files whose changes invalidate the cached profile, e.g. `flake.nix`, or none for a
+    /// flake that isn't in a local dir.
+    pub fn watched_files(&self) -> &[PathBuf] {
+        &self.files_to_watch
+    }
+
+    fn nix_version(&self) -> anyhow::Result<String> {
+        nix_command::nix_program(self.options.nix_program(), ["--version"])
+    }
+
+    /// Signals a successful update with [`NixProfileCacheOptions::ready_marker`] and
+    /// [`NixProfileCacheOptions::ready_command`], warning rather than failing since the profile
+    /// is already built.
+    fn signal_ready(&self, hash: &str) {
+        if let Some(ready_marker) = &self.options.ready_marker
+            && let Err(e) = fs::write(ready_marker, hash)
+        {
+            self.report_update_warning(UpdateWarning::ReadySignalFailed {
+                reason: format!("couldn't write {}: {e}", ready_marker.display()),
+            });
+        }
+        if let Some(ready_command) = &self.options.ready_command {
+            let status = process::Command::new("sh")
+                .args(["-c", ready_command, "sh", hash])
+                .stdout(io::stderr())
+                .status();
+            let reason = match status {
+                Ok(status) if status.success() => return,
+                Ok(status) => format!("`{ready_command}` failed with {status}"),
+                Err(e) => format!("couldn't run `{ready_command}`: {e}"),
+            };
+            self.report_update_warning(UpdateWarning::ReadySignalFailed { reason });
+        }
+    }
+
+    fn report_update_phase(&self, update_phase: UpdatePhase) {
+        if let Some(on_update_phase) = self.options.on_update_phase {
+            on_update_phase(&update_phase);
+        }
+    }
+
+    fn report_update_warning(&self, update_warning: UpdateWarning) {
+        if let Some(on_update_warning) = self.options.on_update_warning {
+            on_update_warning(&update_warning);
+        }
     }
 
     fn impure_arg(&self) -> Option<&str> {
@@ -122,17 +839,61 @@ impl NixProfileCache {
     }
 
     fn add_gcroot(&self, store_path: &Path, symlink: &Path) -> anyhow::Result<()> {
-        let mut args = vec![OsStr::new("build")];
-        if let Some(impure_arg) = self.impure_arg() {
-            args.push(OsStr::new(impure_arg));
+        add_gcroot_with(
+            self.options.nix_program(),
+            self.impure_arg(),
+            store_path,
+            symlink,
+        )
+    }
+
+    /// Creates a gcroot for each flake input, running a bounded number of `nix build`s at once.
+    fn add_input_gcroots(&self, inputs: &[PathBuf]) -> anyhow::Result<()> {
+        let inputs_iter = Mutex::new(inputs.iter());
+        let errors = Mutex::new(Vec::new());
+        let done = AtomicUsize::new(0);
+        self.report_update_phase(UpdatePhase::CreatingInputGcroots {
+            done: 0,
+            total: inputs.len(),
+        });
+
+        thread::scope(|scope| {
+            for _ in 0..self.options.jobs().min(inputs.len()) {
+                scope.spawn(|| {
+                    loop {
+                        let Some(input) = inputs_iter.lock().unwrap().next() else {
+                            break;
+                        };
+                        let store_path = PathBuf::from(NIX_STORE_PREFIX).join(input);
+                        let symlink_path = self
+                            .flake_inputs_dir
+                            .join(input.file_name().unwrap_or(input.as_os_str()));
+                        if let Err(e) = self.add_gcroot(&store_path, &symlink_path) {
+                            errors.lock().unwrap().push(e);
+                        }
+                        self.report_update_phase(UpdatePhase::CreatingInputGcroots {
+                            done: done.fetch_add(1, Ordering::Relaxed) + 1,
+                            total: inputs.len(),
+                        });
+                    }
+                });
+            }
+        });
+
+        let errors = errors.into_inner().unwrap();
+        if errors.is_empty() {
+            return Ok(());
         }
-        args.extend_from_slice(&[
-            OsStr::new("--out-link"),
-            symlink.as_os_str(),
-            store_path.as_os_str(),
-        ]);
-        nix_command::nix(args)?;
-        Ok(())
+        Err(anyhow::format_err!(
+            "Failed to create {} of {} flake input gcroots:\n{}",
+            errors.len(),
+            inputs.len(),
+            errors
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        ))
     }
 
     fn get_flake_input_paths(&self) -> anyhow::Result<Vec<PathBuf>> {
@@ -145,9 +906,14 @@ impl NixProfileCache {
             "--no-write-lock-file",
             &self.flake_reference.flake_reference_string,
         ]);
-        let stdout_content = nix_command::nix(args)?;
+        let stdout_content = nix_command::nix_program(self.options.nix_program(), args)?;
         let json = serde_json::from_str::<Value>(&stdout_content)?;
-        Ok(get_paths_from_doc(&json))
+        let max_depth = self.options.max_input_depth();
+        let (paths, depth_limit_reached) = get_paths_from_doc(&json, max_depth);
+        if depth_limit_reached {
+            self.report_update_warning(UpdateWarning::InputDepthLimitReached { max_depth });
+        }
+        Ok(paths)
     }
 }
 
@@ -205,81 +971,336 @@ impl FlakeReference {
     }
 }
 
-fn hash_files(filenames: impl AsRef<[PathBuf]>) -> anyhow::Result<String> {
-    let (hasher, no_files) = filenames
-        .as_ref()
-        .iter()
-        .filter(|f| {
-            // TODO: figure out what to do if the file doesn't exist
-            f.exists()
-        })
-        .try_fold((Sha1::new(), true), |(mut acc, ..), f| {
-            acc.update(fs::read(f)?);
-            anyhow::Result::<(Sha1, bool)>::Ok((acc, false))
-        })?;
+/// The checksum sidecar of `profile_rc`, e.g. `flake-profile-<hash>.rc.sha1`.
+fn rc_checksum_file(profile_rc: &Path) -> PathBuf {
+    let mut checksum_file = OsString::from(profile_rc);
+    checksum_file.push(".");
+    checksum_file.push(RC_CHECKSUM_EXTENSION);
+    PathBuf::from(checksum_file)
+}
+
+/// The checksum of a profile rc's raw (possibly compressed) content.
+fn rc_checksum(profile_rc_content: &[u8]) -> String {
+    format!("{:x}", Sha1::digest(profile_rc_content))
+}
 
-    if no_files {
-        return Err(anyhow::anyhow!("No files found to hash"));
+fn salted_hasher(salt: Option<&str>) -> Sha1 {
+    let mut hasher = Sha1::new();
+    if let Some(salt) = salt {
+        hasher.update(salt);
     }
+    hasher
+}
 
-    Ok(format!("{:x}", hasher.finalize()))
+/// Hashes the contents of the files that exist of `filenames`, see [`FilesHasher`].
+pub fn hash_files(filenames: impl AsRef<[PathBuf]>, salt: Option<&str>) -> anyhow::Result<String> {
+    let mut hasher = FilesHasher::new(salt);
+    for filename in filenames.as_ref() {
+        hasher.update_file(filename)?;
+    }
+    hasher.finalize()
 }
 
-fn hash_flake_reference(flake_reference: &str) -> anyhow::Result<String> {
-    let mut hasher = Sha1::new();
+/// Hashes files one at a time as they're found, giving the same hash as hashing them all at once
+/// in the same order.
+#[derive(Debug, Clone)]
+pub struct FilesHasher {
+    hasher: Sha1,
+    scope: HashScope,
+    no_files: bool,
+}
+
+impl FilesHasher {
+    pub fn new(salt: Option<&str>) -> Self {
+        Self::with_scope(salt, HashScope::Contents)
+    }
+
+    pub fn with_scope(salt: Option<&str>, scope: HashScope) -> Self {
+        Self {
+            hasher: salted_hasher(salt),
+            scope,
+            no_files: true,
+        }
+    }
+
+    /// Adds a file's contents (or metadata, see [`HashScope`]) to the hash, skipping it if it
+    /// doesn't exist.
+    pub fn update_file(&mut self, filename: &Path) -> anyhow::Result<()> {
+        // TODO: figure out what to do if the file doesn't exist
+        if filename.exists() {
+            match self.scope {
+                HashScope::Contents => self.hasher.update(fs::read(filename)?),
+                HashScope::MetadataOnly => {
+                    let metadata = fs::metadata(filename)?;
+                    let mtime = metadata
+                        .modified()?
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default();
+                    self.hasher.update(filename.as_os_str().as_bytes());
+                    self.hasher.update(b"\0");
+                    self.hasher.update(metadata.len().to_le_bytes());
+                    self.hasher.update(mtime.as_nanos().to_le_bytes());
+                }
+            }
+            self.no_files = false;
+        }
+        Ok(())
+    }
+
+    pub fn finalize(self) -> anyhow::Result<String> {
+        if self.no_files {
+            return Err(anyhow::anyhow!("No files found to hash"));
+        }
+
+        Ok(format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+/// Whether `error` is a failed `nix` command whose stderr contains any of `messages`.
+fn nix_failed_with(error: &anyhow::Error, messages: &[&str]) -> bool {
+    error
+        .downcast_ref::<NixCommandError>()
+        .and_then(NixCommandError::stderr)
+        .is_some_and(|stderr| messages.iter().any(|message| stderr.contains(message)))
+}
+
+/// Reads the [`WATCH_IGNORE_FILE_NAME`] in `flake_dir`, which ignores nothing if it doesn't exist.
+fn watch_ignore(flake_dir: &Path) -> anyhow::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(flake_dir);
+    let ignore_file = flake_dir.join(WATCH_IGNORE_FILE_NAME);
+    if ignore_file.is_file()
+        && let Some(e) = builder.add(&ignore_file)
+    {
+        return Err(anyhow::format_err!(
+            "Couldn't read {}: {e}",
+            ignore_file.display()
+        ));
+    }
+    Ok(builder.build()?)
+}
+
+fn hash_flake_reference(flake_reference: &str, salt: Option<&str>) -> anyhow::Result<String> {
+    let mut hasher = salted_hasher(salt);
     hasher.update(flake_reference);
     Ok(format!("{:x}", hasher.finalize()))
 }
 
-fn clean_old_gcroots(cache_dir: &Path, flake_inputs_dir: &Path) -> anyhow::Result<()> {
-    let res = fs::remove_dir_all(cache_dir);
+/// Checks up front that `dir` can be written to, rather than failing with a cryptic IO error
+/// partway through an update.
+pub fn ensure_writable_dir(dir: &Path) -> anyhow::Result<()> {
+    let probe_file = dir.join(format!(".write-probe.{}", process::id()));
+    fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&probe_file, ""))
+        .and_then(|_| fs::remove_file(&probe_file))
+        .map_err(|e| {
+            anyhow::format_err!(
+                "Cache directory {} isn't writable: {e}\nFix its permissions or choose another cache directory.",
+                dir.display()
+            )
+        })
+}
+
+/// Writes `content` to a temporary file next to `path`, with the modification time `mtime` if
+/// given, then renames it into place, so readers (e.g. a shell sourcing a profile rc) never see a
+/// partially written file, even after a crash.
+fn write_file_atomically(path: &Path, content: &[u8], mtime: Option<SystemTime>) -> io::Result<()> {
+    let mut tmp_path = OsString::from(path);
+    tmp_path.push(format!(".tmp.{}", process::id()));
+    let tmp_path = PathBuf::from(tmp_path);
+    let result = fs::File::create(&tmp_path)
+        .and_then(|mut tmp_file| {
+            tmp_file.write_all(content)?;
+            if let Some(mtime) = mtime {
+                tmp_file.set_modified(mtime)?;
+            }
+            tmp_file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Removes everything in the cache dir and the flake inputs dir, except the temporary profile
+/// being built.
+fn clean_old_gcroots(
+    cache_dir: &Path,
+    flake_inputs_dir: &Path,
+    tmp_profile: &Path,
+) -> anyhow::Result<()> {
+    let res = fs::remove_dir_all(flake_inputs_dir);
     if let Err(e) = &res
         && e.kind() != io::ErrorKind::NotFound
     {
         res?;
     }
+    let tmp_profile_name = tmp_profile.file_name().unwrap_or_default();
+    for entry in fs::read_dir(cache_dir)? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .as_encoded_bytes()
+            .starts_with(tmp_profile_name.as_encoded_bytes())
+        {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
     fs::create_dir_all(flake_inputs_dir)?;
     Ok(())
 }
 
-fn get_paths_from_doc(doc: &Value) -> Vec<PathBuf> {
-    let mut result = Vec::new();
+/// Reads the rc cached in `cache_dir` for the profile with `hash`, e.g. one built before a
+/// `flake.lock` bump, decompressing it if needed.
+///
+/// Only the cache dir is needed, so the flake doesn't have to still exist or be resolved.
+pub fn read_cached_profile_rc(cache_dir: &Path, hash: &str) -> anyhow::Result<Vec<u8>> {
+    let rc_file = cached_rc_file(cache_dir, hash).ok_or_else(|| {
+        anyhow::format_err!("No cached profile rc for {hash} in {}", cache_dir.display())
+    })?;
+    read_rc_file(&rc_file)
+}
+
+/// The rc file, in either format, cached in `cache_dir` for the profile with `hash`.
+fn cached_rc_file(cache_dir: &Path, hash: &str) -> Option<PathBuf> {
+    let symlink = cache_dir.join(format!("flake-profile-{hash}"));
+    [
+        symlink.with_extension("rc"),
+        symlink.with_extension(format!("rc.{COMPRESSED_RC_EXTENSION}")),
+    ]
+    .into_iter()
+    .find(|rc_file| rc_file.is_file())
+}
 
-    if let Some(p) = get_path(doc) {
-        result.push(p);
+/// Reads a profile rc, decompressing it if it's zstd-compressed.
+fn read_rc_file(rc_file: &Path) -> anyhow::Result<Vec<u8>> {
+    let content = fs::read(rc_file)?;
+    if rc_file.extension() != Some(OsStr::new(COMPRESSED_RC_EXTENSION)) {
+        return Ok(content);
     }
+    let mut content_slice = content.as_slice();
+    let mut decoder = ruzstd::decoding::StreamingDecoder::new(&mut content_slice)?;
+    let mut decompressed_content = vec![];
+    decoder.read_to_end(&mut decompressed_content)?;
+    Ok(decompressed_content)
+}
 
-    if let Some(inputs) = doc.get("inputs").and_then(|i| i.as_object()) {
-        for (_k, v) in inputs {
-            let sub_paths = get_paths_from_doc(v);
-            result.extend(sub_paths);
-        }
+/// Protects `store_path` from garbage collection with a gcroot at `symlink`, the way
+/// [`NixProfileCache`] roots profiles and flake inputs.
+pub fn add_gcroot(store_path: &Path, symlink: &Path) -> anyhow::Result<()> {
+    add_gcroot_with(OsStr::new("nix"), None, store_path, symlink)
+}
+
+fn add_gcroot_with(
+    nix_program: &OsStr,
+    impure_arg: Option<&str>,
+    store_path: &Path,
+    symlink: &Path,
+) -> anyhow::Result<()> {
+    let mut args = vec![OsStr::new("build")];
+    if let Some(impure_arg) = impure_arg {
+        args.push(OsStr::new(impure_arg));
     }
+    args.extend_from_slice(&[
+        OsStr::new("--out-link"),
+        symlink.as_os_str(),
+        store_path.as_os_str(),
+    ]);
+    nix_command::nix_program(nix_program, args)?;
+    verify_gcroot(symlink)
+}
 
-    result
+/// Checks that `nix build --out-link` actually left a symlink into the Nix store, since a missing
+/// or misdirected symlink won't protect anything from garbage collection.
+fn verify_gcroot(symlink: &Path) -> anyhow::Result<()> {
+    let target = fs::read_link(symlink).map_err(|e| {
+        anyhow::format_err!(
+            "`nix build` didn't create a gcroot at {}: {e}",
+            symlink.display()
+        )
+    })?;
+    if !target.starts_with(NIX_STORE_PREFIX) {
+        return Err(anyhow::format_err!(
+            "gcroot {} points to {}, which isn't in the Nix store",
+            symlink.display(),
+            target.display()
+        ));
+    }
+    Ok(())
 }
 
+/// Gets every input path from a `nix flake archive --json` doc, sorted and deduplicated so gcroots
+/// are created in a stable order.
+///
+/// Inputs nested more than `max_depth` levels deep are skipped, returning `true` alongside the
+/// paths found if any were.
+fn get_paths_from_doc(doc: &Value, max_depth: usize) -> (Vec<PathBuf>, bool) {
+    let mut visited = BTreeSet::new();
+    let depth_limit_reached = collect_paths_from_doc(doc, 0, max_depth, &mut visited);
+    (visited.into_iter().collect(), depth_limit_reached)
+}
+
+/// Collects the paths of `doc` and its inputs, skipping the inputs of any path already visited,
+/// so an input graph that refers back to an ancestor doesn't get walked again.
+fn collect_paths_from_doc(
+    doc: &Value,
+    depth: usize,
+    max_depth: usize,
+    visited: &mut BTreeSet<PathBuf>,
+) -> bool {
+    if let Some(p) = get_path(doc)
+        && !visited.insert(p)
+    {
+        return false;
+    }
+
+    let Some(inputs) = doc
+        .get("inputs")
+        .and_then(|i| i.as_object())
+        .filter(|inputs| !inputs.is_empty())
+    else {
+        return false;
+    };
+    if depth == max_depth {
+        return true;
+    }
+    let mut depth_limit_reached = false;
+    for (_k, v) in inputs {
+        depth_limit_reached |= collect_paths_from_doc(v, depth + 1, max_depth, visited);
+    }
+    depth_limit_reached
+}
+
+/// Gets the store path name from a `nix flake archive --json` entry, keeping the full path if
+/// it isn't in the store.
 fn get_path(doc: &Value) -> Option<PathBuf> {
     doc.get("path")
         .and_then(|value| value.as_str())
-        .map(|path| {
-            if path.len() > 11 {
-                PathBuf::from(&path[11..])
-            } else {
-                PathBuf::from(path)
-            }
-        })
+        .map(|path| PathBuf::from(path.strip_prefix(NIX_STORE_PREFIX).unwrap_or(path)))
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{io::Write, path::PathBuf};
+    use std::{
+        fs::{self, File},
+        io::Write,
+        path::{Path, PathBuf},
+        process::Command,
+        time::{Duration, SystemTime},
+    };
 
     use once_cell::sync::Lazy;
     use serde_json::json;
     use tempfile::NamedTempFile;
 
-    use super::{get_path, get_paths_from_doc, hash_files};
+    use super::{
+        FilesHasher, HashScope, get_path, get_paths_from_doc, hash_files, write_file_atomically,
+    };
 
     static TEST_FILE: Lazy<NamedTempFile> = Lazy::new(|| {
         let mut test_file = tempfile::NamedTempFile::new().unwrap();
@@ -290,7 +1311,7 @@ mod tests {
     #[test]
     fn test_hash_one() {
         assert_eq!(
-            hash_files([TEST_FILE.path().to_path_buf()]).unwrap(),
+            hash_files([TEST_FILE.path().to_path_buf()], None).unwrap(),
             "6ead949bf4bcae230b9ed9cd11e578e34ce9f9ea"
         );
     }
@@ -298,10 +1319,13 @@ mod tests {
     #[test]
     fn test_hash_multiple() {
         assert_eq!(
-            hash_files([
-                TEST_FILE.path().to_path_buf(),
-                TEST_FILE.path().to_path_buf(),
-            ])
+            hash_files(
+                [
+                    TEST_FILE.path().to_path_buf(),
+                    TEST_FILE.path().to_path_buf(),
+                ],
+                None
+            )
             .unwrap(),
             "f109b7892a541ed1e3cf39314cd25d21042b984f"
         );
@@ -310,37 +1334,246 @@ mod tests {
     #[test]
     fn test_hash_filters_nonexistent() {
         assert_eq!(
-            hash_files([TEST_FILE.path().to_path_buf(), PathBuf::from("FOOBARBAZ"),]).unwrap(),
+            hash_files(
+                [TEST_FILE.path().to_path_buf(), PathBuf::from("FOOBARBAZ"),],
+                None
+            )
+            .unwrap(),
             "6ead949bf4bcae230b9ed9cd11e578e34ce9f9ea"
         );
     }
 
+    #[test]
+    fn test_files_hasher_matches_hash_files() {
+        let files = [
+            TEST_FILE.path().to_path_buf(),
+            PathBuf::from("FOOBARBAZ"),
+            TEST_FILE.path().to_path_buf(),
+        ];
+        for salt in [None, Some("salt")] {
+            let mut hasher = FilesHasher::new(salt);
+            for file in &files {
+                hasher.update_file(file).unwrap();
+            }
+            assert_eq!(
+                hasher.finalize().unwrap(),
+                hash_files(&files, salt).unwrap()
+            );
+        }
+
+        let mut hasher = FilesHasher::new(None);
+        hasher.update_file(&PathBuf::from("FOOBARBAZ")).unwrap();
+        assert!(hasher.finalize().is_err());
+    }
+
+    #[test]
+    fn test_files_hasher_metadata_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash_metadata = |file: &Path| {
+            let mut hasher = FilesHasher::with_scope(None, HashScope::MetadataOnly);
+            hasher.update_file(file).unwrap();
+            hasher.finalize().unwrap()
+        };
+
+        // NB: Reading a FIFO without a writer would block, so this only passes without reading
+        let fifo = dir.path().join("fifo");
+        assert!(
+            Command::new("mkfifo")
+                .arg(&fifo)
+                .status()
+                .unwrap()
+                .success()
+        );
+        assert_eq!(hash_metadata(&fifo), hash_metadata(&fifo));
+
+        let file = dir.path().join("flake.nix");
+        fs::write(&file, "{}").unwrap();
+        let file_mtime = |mtime: SystemTime| {
+            File::options()
+                .write(true)
+                .open(&file)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        };
+        file_mtime(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let hash = hash_metadata(&file);
+        assert_eq!(hash_metadata(&file), hash);
+        assert_ne!(hash, hash_files([file.clone()], None).unwrap());
+
+        file_mtime(SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000));
+        assert_ne!(hash_metadata(&file), hash);
+    }
+
+    #[test]
+    fn test_hash_salt() {
+        let unsalted = hash_files([TEST_FILE.path().to_path_buf()], None).unwrap();
+        let salted_a = hash_files([TEST_FILE.path().to_path_buf()], Some("a")).unwrap();
+        let salted_b = hash_files([TEST_FILE.path().to_path_buf()], Some("b")).unwrap();
+        assert_ne!(salted_a, unsalted);
+        assert_ne!(salted_a, salted_b);
+        assert_eq!(
+            salted_a,
+            hash_files([TEST_FILE.path().to_path_buf()], Some("a")).unwrap()
+        );
+    }
+
     #[test]
     fn test_get_path_removes_prefix() {
         let input = json!({
-            "path": "aaaaaaaaaaabbbbb"
+            "path": "/nix/store/bbbbb"
         });
         let result = get_path(&input);
         assert_eq!(result, Some(PathBuf::from("bbbbb")));
     }
 
+    #[test]
+    fn test_get_path_keeps_long_non_store_path() {
+        let input = json!({
+            "path": "/home/user/projects/flake"
+        });
+        let result = get_path(&input);
+        assert_eq!(result, Some(PathBuf::from("/home/user/projects/flake")));
+    }
+
+    #[test]
+    fn test_get_path_with_multibyte_characters() {
+        let input = json!({
+            "path": "/nix/stör/ééééé"
+        });
+        assert_eq!(get_path(&input), Some(PathBuf::from("/nix/stör/ééééé")));
+
+        let input = json!({
+            "path": "/nix/store/ééééé-source"
+        });
+        assert_eq!(get_path(&input), Some(PathBuf::from("ééééé-source")));
+    }
+
     #[test]
     fn test_get_paths_from_doc() {
         let input = json!({
-            "path": "aaaaaaaaaaabbbbb",
+            "path": "/nix/store/bbbbb",
             "inputs": {
                 "foo": {
-                    "path": "aaaaaaaaaaaccccc",
+                    "path": "/nix/store/ccccc",
                     "inputs": {
                         "bar": {
-                            "path": "aaaaaaaaaaaddddd",
+                            "path": "/nix/store/ddddd",
+                            "inputs": {}
+                        }
+                    }
+                }
+            }
+        });
+        let (result, depth_limit_reached) = get_paths_from_doc(&input, 64);
+        assert!(!depth_limit_reached);
+        assert_eq!(
+            result,
+            vec![
+                "bbbbb".to_string(),
+                "ccccc".to_string(),
+                "ddddd".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_paths_from_doc_skips_inputs_of_visited_paths() {
+        let input = json!({
+            "path": "/nix/store/bbbbb",
+            "inputs": {
+                "foo": {
+                    "path": "/nix/store/ccccc",
+                    "inputs": {
+                        "parent": {
+                            "path": "/nix/store/bbbbb",
+                            "inputs": {
+                                "foo": {
+                                    "path": "/nix/store/ccccc",
+                                    "inputs": {
+                                        "unreachable": {
+                                            "path": "/nix/store/eeeee",
+                                            "inputs": {}
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                },
+                "bar": {
+                    "path": "/nix/store/ddddd",
+                    "inputs": {
+                        "foo": {
+                            "path": "/nix/store/ccccc",
+                            "inputs": {}
+                        }
+                    }
+                }
+            }
+        });
+        let (result, depth_limit_reached) = get_paths_from_doc(&input, 64);
+        assert!(!depth_limit_reached);
+        assert_eq!(
+            result,
+            vec![
+                "bbbbb".to_string(),
+                "ccccc".to_string(),
+                "ddddd".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_paths_from_doc_stops_at_max_depth() {
+        let input = (1..=10)
+            .rev()
+            .fold(json!({"path": "/nix/store/0"}), |doc, depth| {
+                json!({
+                    "path": format!("/nix/store/{depth}"),
+                    "inputs": { "nested": doc }
+                })
+            });
+
+        let (result, depth_limit_reached) = get_paths_from_doc(&input, 3);
+        assert!(depth_limit_reached);
+        assert_eq!(
+            result,
+            vec![
+                "1".to_string(),
+                "2".to_string(),
+                "3".to_string(),
+                "4".to_string()
+            ]
+        );
+
+        let (result, depth_limit_reached) = get_paths_from_doc(&input, 10);
+        assert!(!depth_limit_reached);
+        assert_eq!(result.len(), 11);
+    }
+
+    #[test]
+    fn test_get_paths_from_doc_sorts_and_dedups() {
+        let input = json!({
+            "path": "/nix/store/ddddd",
+            "inputs": {
+                "foo": {
+                    "path": "/nix/store/ccccc",
+                    "inputs": {
+                        "nixpkgs": {
+                            "path": "/nix/store/bbbbb",
                             "inputs": {}
                         }
                     }
+                },
+                "nixpkgs": {
+                    "path": "/nix/store/bbbbb",
+                    "inputs": {}
                 }
             }
         });
-        let result = get_paths_from_doc(&input);
+        let (result, depth_limit_reached) = get_paths_from_doc(&input, 64);
+        assert!(!depth_limit_reached);
         assert_eq!(
             result,
             vec![
@@ -350,4 +1583,25 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_write_file_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("flake-profile-abc.rc");
+        std::fs::write(&path, "export OLD=1;").unwrap();
+
+        write_file_atomically(&path, b"export NEW=1;", None).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "export NEW=1;");
+
+        // A failed write leaves the old file in place, and no temporary file behind
+        let dir_path = dir.path().join("a-dir");
+        std::fs::create_dir(&dir_path).unwrap();
+        assert!(write_file_atomically(&dir_path, b"export NEW=1;", None).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "export NEW=1;");
+        let entries = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2, "{entries:?}");
+    }
 }
@@ -16,23 +16,31 @@ static REQUIRED_NIX_VERSION: Lazy<VersionReq> = Lazy::new(|| VersionReq {
     }],
 });
 
-static SEMVER_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"([0-9]+\.[0-9]+\.[0-9]+)").unwrap());
+static SEMVER_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"([0-9]+)\.([0-9]+)(?:\.([0-9]+))?").unwrap());
 
 pub fn check_nix_version() -> anyhow::Result<()> {
-    check_nix_program_version(OsStr::new("nix"))
+    check_nix_version_with(&VersionExtractor::new())
 }
 
-fn check_nix_program_version(nix_executable_path: impl AsRef<OsStr>) -> anyhow::Result<()> {
+/// Like [`check_nix_version`], but finding the version in `nix --version` with `version_extractor`.
+pub fn check_nix_version_with(version_extractor: &VersionExtractor) -> anyhow::Result<()> {
+    check_nix_program_version(OsStr::new("nix"), version_extractor)
+}
+
+fn check_nix_program_version(
+    nix_executable_path: impl AsRef<OsStr>,
+    version_extractor: &VersionExtractor,
+) -> anyhow::Result<()> {
     let stdout_content = nix_command::nix_program(nix_executable_path.as_ref(), ["--version"])?;
 
     if stdout_content.is_empty() {
         return Err(anyhow::format_err!("`nix --version` failed to execute."));
     }
 
-    let nix_version_match = SEMVER_RE
-        .find(&stdout_content)
+    let nix_version = version_extractor
+        .extract(&stdout_content)
         .ok_or_else(|| anyhow::format_err!("SemVer from `nix --version` could not be found."))?;
-    let nix_version = Version::parse(nix_version_match.as_str())?;
 
     if REQUIRED_NIX_VERSION.matches(&nix_version) {
         Ok(())
@@ -41,11 +49,71 @@ fn check_nix_program_version(nix_executable_path: impl AsRef<OsStr>) -> anyhow::
     }
 }
 
+/// Finds a tool's version in its `--version` output.
+///
+/// By default this is the first `major.minor[.patch]` version, defaulting a missing patch to 0.
+#[derive(Debug, Clone, Default)]
+pub struct VersionExtractor {
+    prefix: Option<String>,
+    version_re: Option<Regex>,
+}
+
+impl VersionExtractor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A custom regex locating the version, for output where the first version-like number isn't
+    /// the tool's version (e.g. a build date like `2023.01.02`).
+    ///
+    /// The version is read from the regex's first capture group, or the whole match if it has
+    /// none.
+    pub fn version_regex(mut self, version_re: Regex) -> Self {
+        self.version_re = Some(version_re);
+        self
+    }
+
+    /// Only look for the version after the first occurrence of `prefix`, typically the tool's
+    /// name, e.g. `Nix` in `nix (Nix) 2.18.1`, so versions in earlier lines (e.g. a copyright
+    /// notice) are skipped.
+    ///
+    /// No version is found if the output doesn't contain `prefix`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn extract(&self, text: &str) -> Option<Version> {
+        let text = match &self.prefix {
+            Some(prefix) => text.split_once(prefix.as_str())?.1,
+            None => text,
+        };
+        let text = match &self.version_re {
+            Some(version_re) => {
+                let captures = version_re.captures(text)?;
+                captures.get(1).or_else(|| captures.get(0))?.as_str()
+            }
+            None => text,
+        };
+
+        let captures = SEMVER_RE.captures(text)?;
+        let component = |i| {
+            captures
+                .get(i)
+                .map_or(Some(0), |component| component.as_str().parse().ok())
+        };
+        Some(Version::new(component(1)?, component(2)?, component(3)?))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{env, fs, os::unix::fs::PermissionsExt, path::PathBuf};
 
-    use super::check_nix_program_version;
+    use regex::Regex;
+    use semver::Version;
+
+    use super::{VersionExtractor, check_nix_program_version};
 
     #[derive(Debug)]
     struct NixExecutable {
@@ -74,7 +142,7 @@ mod tests {
     fn test_error_on_exit_failure() {
         let nix_executable = NixExecutable::new(r#"exit 1;"#);
         assert_eq!(
-            check_nix_program_version(&nix_executable.file_path)
+            check_nix_program_version(&nix_executable.file_path, &VersionExtractor::new())
                 .unwrap_err()
                 .to_string(),
             format!(
@@ -88,7 +156,7 @@ mod tests {
     fn test_error_on_empty_stdout() {
         let nix_executable = NixExecutable::new(r#"printf "";"#);
         assert_eq!(
-            check_nix_program_version(nix_executable.file_path)
+            check_nix_program_version(nix_executable.file_path, &VersionExtractor::new())
                 .unwrap_err()
                 .to_string(),
             "`nix --version` failed to execute."
@@ -99,7 +167,7 @@ mod tests {
     fn test_error_on_missing_semver() {
         let nix_executable = NixExecutable::new(r#"echo "hello";"#);
         assert_eq!(
-            check_nix_program_version(nix_executable.file_path)
+            check_nix_program_version(nix_executable.file_path, &VersionExtractor::new())
                 .unwrap_err()
                 .to_string(),
             "SemVer from `nix --version` could not be found."
@@ -110,7 +178,7 @@ mod tests {
     fn test_error_on_too_old_version() {
         let nix_executable = NixExecutable::new(r#"echo "nix (Nix) 0.0.0";"#);
         assert_eq!(
-            check_nix_program_version(nix_executable.file_path)
+            check_nix_program_version(nix_executable.file_path, &VersionExtractor::new())
                 .unwrap_err()
                 .to_string(),
             "`nix` version too old for flakes."
@@ -120,12 +188,78 @@ mod tests {
     #[test]
     fn test_version_matches_minimum() {
         let nix_executable = NixExecutable::new(r#"echo "nix (Nix) 2.10.0";"#);
-        check_nix_program_version(nix_executable.file_path).unwrap();
+        check_nix_program_version(nix_executable.file_path, &VersionExtractor::new()).unwrap();
     }
 
     #[test]
     fn test_version_matches_newer() {
         let nix_executable = NixExecutable::new(r#"echo "nix (Nix) 2.30.0";"#);
-        check_nix_program_version(nix_executable.file_path).unwrap();
+        check_nix_program_version(nix_executable.file_path, &VersionExtractor::new()).unwrap();
+    }
+
+    #[test]
+    fn test_extract_version_without_patch() {
+        assert_eq!(
+            VersionExtractor::new().extract("1.2"),
+            Some(Version::new(1, 2, 0))
+        );
+        assert_eq!(
+            VersionExtractor::new().extract("nix (Nix) 2.18"),
+            Some(Version::new(2, 18, 0))
+        );
+    }
+
+    #[test]
+    fn test_extract_version_with_patch() {
+        assert_eq!(
+            VersionExtractor::new().extract("nix (Nix) 2.30.1"),
+            Some(Version::new(2, 30, 1))
+        );
+    }
+
+    #[test]
+    fn test_version_without_patch_matches_minimum() {
+        let nix_executable = NixExecutable::new(r#"echo "nix (Nix) 2.18";"#);
+        check_nix_program_version(nix_executable.file_path, &VersionExtractor::new()).unwrap();
+    }
+
+    #[test]
+    fn test_extract_version_with_custom_regex() {
+        let output = "nix-build-2023.01.02 (Nix) 2.18.1";
+        assert_eq!(
+            VersionExtractor::new().extract(output),
+            Some(Version::new(2023, 1, 2))
+        );
+        assert_eq!(
+            VersionExtractor::new()
+                .version_regex(Regex::new(r"\(Nix\) ([0-9.]+)").unwrap())
+                .extract(output),
+            Some(Version::new(2, 18, 1))
+        );
+    }
+
+    #[test]
+    fn test_extract_version_after_prefix() {
+        let output = "Copyright 2003.2.1 Eelco Dolstra\nnix (Nix) 2.18.1\n";
+        assert_eq!(
+            VersionExtractor::new().extract(output),
+            Some(Version::new(2003, 2, 1))
+        );
+        assert_eq!(
+            VersionExtractor::new().prefix("Nix").extract(output),
+            Some(Version::new(2, 18, 1))
+        );
+        assert_eq!(VersionExtractor::new().prefix("Lix").extract(output), None);
+    }
+
+    #[test]
+    fn test_version_with_custom_regex_matches_minimum() {
+        let nix_executable = NixExecutable::new(r#"echo "nix 0.1.0 (Nix) 2.18.1";"#);
+        check_nix_program_version(&nix_executable.file_path, &VersionExtractor::new()).unwrap_err();
+        check_nix_program_version(
+            &nix_executable.file_path,
+            &VersionExtractor::new().version_regex(Regex::new(r"\(Nix\) (\S+)").unwrap()),
+        )
+        .unwrap();
     }
 }
@@ -0,0 +1,585 @@
+use env_hooks::{EnvDiff, EnvVars, merge_delimited_values, valid_env_var_key};
+use indexmap::IndexSet;
+use shell_quote::Bash;
+
+/// A top level statement of a profile rc, e.g. `export KEY=value` or a function definition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RcStatement<'a> {
+    /// The statement's source, including its trailing separator, so joining every statement
+    /// reproduces the rc.
+    pub(crate) text: &'a str,
+    /// The variables assigned or exported by the statement, if any.
+    pub(crate) assignments: Vec<RcAssignment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RcAssignment {
+    pub(crate) key: String,
+    /// `None` for a bare `export KEY`.
+    pub(crate) value: Option<String>,
+    pub(crate) exported: bool,
+}
+
+/// Lists the env vars a profile rc (the shell code written by `nix print-dev-env`) would set,
+/// without evaluating it.
+///
+/// This is best-effort: only the `KEY=value`, `export KEY[=value]` and `declare -x KEY[=value]`
+/// forms `nix print-dev-env` emits are understood, and values aren't expanded.
+pub fn get_env_vars_from_rc(rc: &str) -> EnvVars {
+    let mut values = EnvVars::new();
+    let mut exported = IndexSet::new();
+    for assignment in parse_rc_statements(rc)
+        .into_iter()
+        .flat_map(|statement| statement.assignments)
+    {
+        if assignment.exported {
+            exported.insert(assignment.key.clone());
+        }
+        if let Some(value) = assignment.value {
+            values.insert(assignment.key, value);
+        }
+    }
+
+    let mut env_vars = EnvVars::new();
+    for key in exported {
+        if let Some(value) = values.shift_remove(&key) {
+            env_vars.insert(key, value);
+        }
+    }
+    env_vars
+}
+
+/// The env vars a profile rc would add or change on top of `base`, e.g. the env of the shell
+/// it's loaded into, to audit what entering a dev shell does.
+///
+/// Like [`get_env_vars_from_rc`], this doesn't evaluate the rc, so it never has any
+/// [`EnvDiff::removed`] env vars.
+pub fn nix_env_delta(profile_rc: &str, base: &EnvVars) -> EnvDiff {
+    let mut env_vars = base.clone();
+    env_vars.extend(get_env_vars_from_rc(profile_rc));
+    EnvDiff::new(base, &env_vars)
+}
+
+/// How the env vars of two profile rcs differ, e.g. to see why switching branches changed a dev
+/// env.
+///
+/// Like [`get_env_vars_from_rc`], this doesn't evaluate either rc.
+pub fn diff_profiles(rc_a: &str, rc_b: &str) -> EnvDiff {
+    EnvDiff::new(&get_env_vars_from_rc(rc_a), &get_env_vars_from_rc(rc_b))
+}
+
+/// Removes the statements assigning or exporting any of `keys` from a profile rc.
+///
+/// A statement assigning several variables (e.g. `export A=1 B=2`) is removed as a whole.
+pub(crate) fn remove_env_vars_from_rc(rc: &str, keys: &[String]) -> String {
+    parse_rc_statements(rc)
+        .into_iter()
+        .filter(|statement| {
+            !statement
+                .assignments
+                .iter()
+                .any(|assignment| keys.contains(&assignment.key))
+        })
+        .map(|statement| statement.text)
+        .collect()
+}
+
+/// Rewrites a profile rc into a canonical form that's stable across builds, so diffs between
+/// cached rcs are meaningful: one statement per line, without blank lines, and each run of
+/// assignments and exports sorted by key.
+///
+/// Other shell code (e.g. function definitions) stays in place between the sorted runs, as do
+/// assignments expanding other variables, so the rc still sets the same environment.
+pub fn normalize_rc(rc: &str) -> String {
+    let mut normalized_rc = String::new();
+    let mut assignments: Vec<(String, &str)> = vec![];
+    let flush_assignments = |normalized_rc: &mut String, assignments: &mut Vec<_>| {
+        assignments.sort_by(|(a, _): &(String, &str), (b, _)| a.cmp(b));
+        for (_, text) in assignments.drain(..) {
+            normalized_rc.push_str(text);
+            normalized_rc.push('\n');
+        }
+    };
+    for statement in parse_rc_statements(rc) {
+        let text = statement.text.trim();
+        let text = text.strip_suffix(';').unwrap_or(text).trim_end();
+        if text.is_empty() {
+            continue;
+        }
+        match statement.assignments.first() {
+            Some(assignment) if !expands_variables(text) => {
+                assignments.push((assignment.key.clone(), text));
+            }
+            _ => {
+                flush_assignments(&mut normalized_rc, &mut assignments);
+                normalized_rc.push_str(text);
+                normalized_rc.push('\n');
+            }
+        }
+    }
+    flush_assignments(&mut normalized_rc, &mut assignments);
+    normalized_rc
+}
+
+/// Whether a statement expands a variable (e.g. `$HOME`), outside single and ANSI-C quotes, so
+/// can't be reordered.
+fn expands_variables(statement: &str) -> bool {
+    let mut chars = statement.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '\'' => {
+                for c in chars.by_ref() {
+                    if c == '\'' {
+                        break;
+                    }
+                }
+            }
+            '$' if chars.next_if_eq(&'\'').is_some() => {
+                skip_ansi_c_quoted(&mut chars.by_ref().map(|c| (0, c)))
+            }
+            '$' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Rewrites a profile rc's `PATH` assignments to keep `old_path` after the dev shell's entries,
+/// rather than replacing it.
+pub fn merge_path_into_rc(rc: &str, old_path: &str) -> String {
+    parse_rc_statements(rc)
+        .into_iter()
+        .map(|statement| match statement.assignments.as_slice() {
+            [
+                RcAssignment {
+                    key,
+                    value: Some(value),
+                    exported,
+                },
+            ] if key == "PATH" => {
+                let merged_path = merge_delimited_values(':', ':', old_path, value);
+                let separator = statement
+                    .text
+                    .strip_suffix(['\n', ';'])
+                    .map_or("", |rest| &statement.text[rest.len()..]);
+                format!(
+                    "{}PATH={}{separator}",
+                    if *exported { "export " } else { "" },
+                    String::from_utf8_lossy(&Bash::quote_vec(&merged_path))
+                )
+            }
+            _ => statement.text.to_string(),
+        })
+        .collect()
+}
+
+/// Splits a profile rc into its top level statements.
+pub(crate) fn parse_rc_statements(rc: &str) -> Vec<RcStatement<'_>> {
+    split_statements(rc)
+        .into_iter()
+        .map(|text| RcStatement {
+            text,
+            assignments: parse_assignments(text),
+        })
+        .collect()
+}
+
+fn split_statements(rc: &str) -> Vec<&str> {
+    let mut statements = vec![];
+    let mut start = 0;
+    let mut brace_depth = 0usize;
+    let mut chars = rc.char_indices().peekable();
+    let mut previous = None;
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '\'' if previous == Some('$') => skip_ansi_c_quoted(&mut chars),
+            '\'' => {
+                for (_, c) in chars.by_ref() {
+                    if c == '\'' {
+                        break;
+                    }
+                }
+            }
+            '"' => skip_double_quoted(&mut chars),
+            '#' if previous.is_none_or(char::is_whitespace) => {
+                while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+            }
+            '{' | '}'
+                if previous.is_none_or(|c| c.is_whitespace() || c == ';')
+                    && chars
+                        .peek()
+                        .is_none_or(|(_, c)| c.is_whitespace() || *c == ';') =>
+            {
+                if c == '{' {
+                    brace_depth += 1;
+                } else {
+                    brace_depth = brace_depth.saturating_sub(1);
+                }
+            }
+            '\n' | ';' if brace_depth == 0 => {
+                statements.push(&rc[start..i + c.len_utf8()]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+        previous = Some(c);
+    }
+    if start < rc.len() {
+        statements.push(&rc[start..]);
+    }
+
+    statements
+}
+
+fn skip_ansi_c_quoted(chars: &mut impl Iterator<Item = (usize, char)>) {
+    while let Some((_, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '\'' => break,
+            _ => {}
+        }
+    }
+}
+
+fn skip_double_quoted(chars: &mut impl Iterator<Item = (usize, char)>) {
+    while let Some((_, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => break,
+            _ => {}
+        }
+    }
+}
+
+fn parse_assignments(statement: &str) -> Vec<RcAssignment> {
+    let Some(words) = split_words(statement) else {
+        return vec![];
+    };
+    let Some((command, args)) = words.split_first() else {
+        return vec![];
+    };
+
+    match command.as_str() {
+        "export" => parse_declaration_args(args, true),
+        "declare" | "typeset" => {
+            let (options, args): (Vec<_>, Vec<_>) =
+                args.iter().cloned().partition(|arg| arg.starts_with('-'));
+            // NB: Arrays and functions aren't env vars
+            if options
+                .iter()
+                .any(|option| option.contains(['a', 'A', 'f', 'F']))
+            {
+                return vec![];
+            }
+            let exported = options.iter().any(|option| option.contains('x'));
+            parse_declaration_args(&args, exported)
+        }
+        _ if args.is_empty() => parse_assignment(command, false).into_iter().collect(),
+        _ => vec![],
+    }
+}
+
+fn parse_declaration_args(args: &[String], exported: bool) -> Vec<RcAssignment> {
+    args.iter()
+        .filter(|arg| !arg.starts_with('-'))
+        .filter_map(|arg| {
+            parse_assignment(arg, exported).or_else(|| {
+                valid_env_var_key(arg).then(|| RcAssignment {
+                    key: arg.clone(),
+                    value: None,
+                    exported,
+                })
+            })
+        })
+        .collect()
+}
+
+fn parse_assignment(word: &str, exported: bool) -> Option<RcAssignment> {
+    let (key, value) = word.split_once('=')?;
+    valid_env_var_key(key).then(|| RcAssignment {
+        key: key.to_string(),
+        value: Some(value.to_string()),
+        exported,
+    })
+}
+
+/// Splits a simple command into its words, removing quotes, or `None` if it's anything more
+/// complicated (e.g. a function definition or a command substitution).
+fn split_words(statement: &str) -> Option<Vec<String>> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = statement.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\n') | None => {}
+                Some(c) => word.get_or_insert_default().push(c),
+            },
+            '\'' => {
+                let word = word.get_or_insert_default();
+                for c in chars.by_ref() {
+                    if c == '\'' {
+                        break;
+                    }
+                    word.push(c);
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_default();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some(c @ ('$' | '`' | '"' | '\\')) => word.push(c),
+                            Some('\n') | None => {}
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                        },
+                        _ => word.push(c),
+                    }
+                }
+            }
+            '$' if chars.next_if_eq(&'\'').is_some() => {
+                let word = word.get_or_insert_default();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\'' => break,
+                        '\\' => word.extend(unescape_ansi_c(chars.next())),
+                        _ => word.push(c),
+                    }
+                }
+            }
+            '$' if chars.peek() == Some(&'(') => return None,
+            '`' | '(' | ')' | '{' | '}' | '|' | '&' | '<' | '>' => return None,
+            '#' if word.is_none() => break,
+            ';' | '\n' => {}
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => word.get_or_insert_default().push(c),
+        }
+    }
+    words.extend(word);
+
+    Some(words)
+}
+
+fn unescape_ansi_c(c: Option<char>) -> Option<char> {
+    Some(match c? {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        'e' | 'E' => '\x1b',
+        'a' => '\x07',
+        'b' => '\x08',
+        'f' => '\x0c',
+        'v' => '\x0b',
+        c => c,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use env_hooks::{BashSource, EnvVars, get_env_vars_from_bash};
+    use indexmap::IndexMap;
+
+    use super::{
+        diff_profiles, get_env_vars_from_rc, merge_path_into_rc, nix_env_delta, normalize_rc,
+        parse_rc_statements, remove_env_vars_from_rc,
+    };
+
+    const RC: &str = r#"BASH='/nix/store/abc-bash/bin/bash'
+HOSTTYPE='x86_64'
+IN_NIX_SHELL='impure'
+export IN_NIX_SHELL
+NIX_BUILD_CORES='8'
+export NIX_BUILD_CORES
+declare -x QUOTED="a \"double\" quoted \$value"
+export SINGLE='it'\''s quoted'
+export ANSI=$'line one\nline two'
+declare -a envHostHostPhases=()
+PATH='/nix/store/abc-bash/bin:/nix/store/def-coreutils/bin'
+export PATH
+genericBuild ()
+{
+    export NOT_TOP_LEVEL=true;
+    runPhase "$curPhase"
+}
+export MULTI_LINE='first
+second'; export AFTER_SEMICOLON=yes
+"#;
+
+    #[test]
+    fn test_get_env_vars_from_rc() {
+        let env_vars = get_env_vars_from_rc(RC);
+        assert_eq!(
+            env_vars
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("IN_NIX_SHELL", "impure"),
+                ("NIX_BUILD_CORES", "8"),
+                ("QUOTED", r#"a "double" quoted $value"#),
+                ("SINGLE", "it's quoted"),
+                ("ANSI", "line one\nline two"),
+                (
+                    "PATH",
+                    "/nix/store/abc-bash/bin:/nix/store/def-coreutils/bin"
+                ),
+                ("MULTI_LINE", "first\nsecond"),
+                ("AFTER_SEMICOLON", "yes"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_rc_statements_covers_whole_rc() {
+        assert_eq!(
+            parse_rc_statements(RC)
+                .into_iter()
+                .map(|statement| statement.text)
+                .collect::<String>(),
+            RC
+        );
+    }
+
+    #[test]
+    fn test_remove_env_vars_from_rc() {
+        let rc = remove_env_vars_from_rc(RC, &[String::from("PATH"), String::from("MULTI_LINE")]);
+        assert!(!rc.contains("PATH="));
+        assert!(!rc.contains("export PATH"));
+        assert!(!rc.contains("first\nsecond"));
+        // NB: `NOT_TOP_LEVEL` is inside a function, so untouched
+        assert!(rc.contains("export NOT_TOP_LEVEL=true;"));
+        let env_vars = get_env_vars_from_rc(&rc);
+        assert!(!env_vars.contains_key("PATH"));
+        assert!(!env_vars.contains_key("MULTI_LINE"));
+        assert_eq!(
+            env_vars.get("AFTER_SEMICOLON").map(String::as_str),
+            Some("yes")
+        );
+        assert_eq!(
+            env_vars.get("NIX_BUILD_CORES").map(String::as_str),
+            Some("8")
+        );
+    }
+
+    #[test]
+    fn test_merge_path_into_rc() {
+        let rc = merge_path_into_rc(RC, "/usr/bin:/nix/store/def-coreutils/bin:/bin");
+        assert!(rc.contains(
+            "PATH=$'/nix/store/abc-bash/bin:/nix/store/def-coreutils/bin:/usr/bin:/bin'\nexport PATH\n"
+        ));
+        assert_eq!(
+            get_env_vars_from_rc(&rc).get("PATH").map(String::as_str),
+            Some("/nix/store/abc-bash/bin:/nix/store/def-coreutils/bin:/usr/bin:/bin")
+        );
+        assert_eq!(
+            merge_path_into_rc("export PATH='/nix/bin'; echo hi\n", "/home/my user/bin"),
+            "export PATH=$'/nix/bin:/home/my user/bin'; echo hi\n"
+        );
+    }
+
+    #[test]
+    fn test_nix_env_delta() {
+        let base = EnvVars::from_iter([
+            (String::from("HOME"), String::from("/home/me")),
+            (String::from("NIX_BUILD_CORES"), String::from("8")),
+            (String::from("PATH"), String::from("/usr/bin:/bin")),
+        ]);
+
+        let env_diff = nix_env_delta(RC, &base);
+
+        assert_eq!(
+            env_diff
+                .added
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>(),
+            [
+                "IN_NIX_SHELL",
+                "QUOTED",
+                "SINGLE",
+                "ANSI",
+                "MULTI_LINE",
+                "AFTER_SEMICOLON"
+            ]
+        );
+        assert_eq!(
+            env_diff.changed,
+            IndexMap::from([(
+                String::from("PATH"),
+                (
+                    String::from("/usr/bin:/bin"),
+                    String::from("/nix/store/abc-bash/bin:/nix/store/def-coreutils/bin")
+                )
+            )])
+        );
+        assert!(env_diff.removed.is_empty());
+    }
+
+    #[test]
+    fn test_diff_profiles() {
+        let rc_a = "export SHARED='same'\nexport VERSION='1.0'\nexport OLD_ONLY='gone'\n";
+        let rc_b = "export SHARED='same'\nexport VERSION='2.0'\nexport NEW_ONLY='here'\n";
+
+        let env_diff = diff_profiles(rc_a, rc_b);
+
+        assert_eq!(
+            env_diff.added,
+            EnvVars::from_iter([(String::from("NEW_ONLY"), String::from("here"))])
+        );
+        assert_eq!(
+            env_diff.changed,
+            IndexMap::from([(
+                String::from("VERSION"),
+                (String::from("1.0"), String::from("2.0"))
+            )])
+        );
+        assert_eq!(
+            env_diff.removed,
+            EnvVars::from_iter([(String::from("OLD_ONLY"), String::from("gone"))])
+        );
+        assert!(diff_profiles(rc_a, rc_a).is_empty());
+    }
+
+    #[test]
+    fn test_normalize_rc() {
+        let rc = "ZED='last'\nexport ZED\nALPHA='first'; export ALPHA\n\nMIDDLE=$'two\\nlines'\n\
+                  f ()\n{\n    export IN_FUNCTION=1\n}\nREFERENCES=\"$ALPHA\"\nBETA='b'\n";
+
+        let normalized_rc = normalize_rc(rc);
+
+        assert_eq!(
+            normalized_rc,
+            "ALPHA='first'\nexport ALPHA\nMIDDLE=$'two\\nlines'\nZED='last'\nexport ZED\n\
+             f ()\n{\n    export IN_FUNCTION=1\n}\nREFERENCES=\"$ALPHA\"\nBETA='b'\n"
+        );
+        assert_eq!(normalize_rc(&normalized_rc), normalized_rc);
+
+        // Keep the caller's PATH so bash can still find `env` to dump the environment.
+        let rc = remove_env_vars_from_rc(RC, &[String::from("PATH")]);
+        let normalized_rc = normalize_rc(&rc);
+        let env_vars = |rc: &str| {
+            get_env_vars_from_bash(BashSource::Script(rc.into()), Some(EnvVars::new())).unwrap()
+        };
+        let mut expected_env_vars = env_vars(&rc);
+        expected_env_vars.sort_keys();
+        let mut normalized_env_vars = env_vars(&normalized_rc);
+        normalized_env_vars.sort_keys();
+        assert_eq!(normalized_env_vars, expected_env_vars);
+    }
+}
@@ -1,22 +1,126 @@
 use std::{
     env, fs,
+    io::Write,
     os::unix::fs::PermissionsExt,
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{self, Command},
+    sync::Mutex,
+    time::{Duration, SystemTime},
 };
 
-use nix_dev_env::NixProfileCache;
+use nix_dev_env::{
+    InputGcrootsDir, NixCommandError, NixProfileCache, NixProfileCacheOptions, RebuildReason,
+    UpdateWarning,
+};
 use tempfile::{tempdir, tempdir_in};
 
+/// How the fake `nix` written by [`write_fake_nix`] behaves.
+struct FakeNix<'a> {
+    /// Printed by `nix --version`.
+    version: &'a str,
+    /// Bash printing the profile rc, with the flake reference in `$installable`, the `--profile`
+    /// path in `$profile_path` and the `--store` (if any) in `$store`.
+    print_dev_env: &'a str,
+    /// Bash run by `nix build` before it creates the gcroot.
+    on_build: &'a str,
+    /// Bash run by `nix flake archive` before it prints [`FakeNix::flake_archive_json`].
+    on_flake_archive: &'a str,
+    /// Printed by `nix flake archive --json`.
+    flake_archive_json: &'a str,
+    /// A file each invocation's args (after `--extra-experimental-features`) are appended to.
+    log_file: Option<&'a Path>,
+}
+
+impl Default for FakeNix<'_> {
+    fn default() -> Self {
+        Self {
+            version: "nix (Nix) 2.30.0",
+            print_dev_env: r#"echo "export FAKE_VAR=true;""#,
+            on_build: "",
+            on_flake_archive: "",
+            flake_archive_json: r#"{ "inputs": {} }"#,
+            log_file: None,
+        }
+    }
+}
+
+/// Writes a fake `nix` to `dir` and returns its path, for [`NixProfileCacheOptions::nix_program`].
+///
+/// Args like `--profile` and `--out-link` are found by name, and the fake fails rather than
+/// write anywhere outside `dir`.
+fn write_fake_nix(dir: &Path, fake_nix: FakeNix) -> PathBuf {
+    let FakeNix {
+        version,
+        print_dev_env,
+        on_build,
+        on_flake_archive,
+        flake_archive_json,
+        log_file,
+    } = fake_nix;
+    let log = log_file
+        .map(|log_file| format!(r#"echo "${{*:3}}" >> "{}""#, log_file.display()))
+        .unwrap_or_default();
+
+    let bash_path = env::var("NIX_BIN_BASH").unwrap_or_else(|_| String::from("/bin/bash"));
+    let nix_file_content = format!(
+        r#"#! {bash_path}
+
+{log}
+work_dir="$(dirname "$0")"
+
+arg_value() {{
+    local args=("${{@:2}}")
+    for ((i=0; i+1<${{#args[@]}}; i++)); do
+        if [[ "${{args[$i]}}" == "$1" ]]; then
+            case "${{args[$((i+1))]}}" in
+                "$work_dir"/*) echo "${{args[$((i+1))]}}"; return 0 ;;
+                *) return 1 ;;
+            esac
+        fi
+    done
+}}
+
+command=("${{@:3}}")
+store=""
+if [[ "${{command[0]}}" == "--store" ]]; then
+    store="${{command[1]}}"
+    command=("${{command[@]:2}}")
+fi
+installable="${{@: -1}}"
+if [[ "${{command[0]}}" == "--version" ]]; then
+    echo "{version}"
+elif [[ "${{command[0]}}" == "print-dev-env" ]]; then
+    profile_path="$(arg_value --profile "$@")" || exit 1
+    rc="$({print_dev_env})" || exit 1
+    if [[ -n "$profile_path" ]]; then
+        printf '%s\n' "$rc" > "$profile_path"
+    fi
+    printf '%s\n' "$rc"
+elif [[ "${{command[0]}}" == "build" ]]; then
+    link_path="$(arg_value --out-link "$@")" || exit 1
+    [[ -n "$link_path" ]] || exit 1
+    {on_build}
+    mkdir -p "$(dirname "$link_path")"
+    ln -sf "/nix/store/$(basename "$installable")" "$link_path"
+elif [[ "${{command[0]}} ${{command[1]}}" == "flake archive" ]]; then
+    {on_flake_archive}
+    echo '{flake_archive_json}'
+fi
+
+exit 0
+"#
+    );
+    let nix_file = dir.join("fake-nix");
+    fs::write(&nix_file, nix_file_content).unwrap();
+    fs::set_permissions(&nix_file, fs::Permissions::from_mode(0o755)).unwrap();
+    nix_file
+}
+
 #[test]
 fn test_nix_profile_cache() {
     let work_dir = tempdir().unwrap();
     let cache_dir = tempdir_in(work_dir.path()).unwrap();
-    let bin_dir = work_dir.path().join("bin");
-    fs::create_dir(&bin_dir).unwrap();
-    let nix_file = bin_dir.join("nix");
     let log_file = work_dir.path().join("nix_commands.log");
-    let bash_path = env::var("NIX_BIN_BASH").unwrap_or_else(|_| String::from("/bin/bash"));
     let flake_dir = tempdir_in(work_dir.path()).unwrap();
     let flake_file = flake_dir.path().join("flake.nix");
     fs::write(flake_file, "{}").unwrap();
@@ -25,60 +129,34 @@ fn test_nix_profile_cache() {
         .join(format!("flake-tmp-profile.{}", process::id()));
     let profile_symlink = cache_dir
         .path()
-        .join("flake-profile-bf21a9e8fbc5a3846fb05b4fa0859e0917b2202f");
+        .join("flake-profile-cf849c970d5edb531d1e5b566aacb10b0f3a42c3");
     let mut profile_rc = profile_symlink.clone();
     profile_rc.set_extension("rc");
+    let profile_nix_version = profile_symlink.with_extension("nix-version");
     let flake_inputs_path = cache_dir.path().join("flake-inputs");
 
     let profile_rc_content = "export FAKE_VAR=true;";
     let nixpkgs_dir_name = "yfzmnk75f009yb7b542kf4r7qaqq9kid-source";
     let nixpkgs_path = format!("/nix/store/{nixpkgs_dir_name}");
-    let nix_file_content = format!(
-        r#"#! {bash_path}
-
-echo "$@" >> "{log_file}"
-
-if [[ "$@" == "--extra-experimental-features nix-command flakes print-dev-env --impure --no-write-lock-file --profile "* ]]; then
-    rc="{profile_rc_content}"
-    for ((i=0; i<$#; i++)); do
-        if [[ "${{@:$i:1}}" == "--profile" ]]; then
-            profile_path="${{@:$((i+1)):1}}"
-            echo "$rc" > "$profile_path"
-            break
-        fi
-    done
-    echo "$rc"
-elif [[ "$@" == "--extra-experimental-features nix-command flakes build --impure --out-link "* ]]; then
-    for ((i=0; i<$#; i++)); do
-        if [[ "${{@:$i:1}}" == "--out-link" ]]; then
-            link_path="${{@:$((i+1)):1}}"
-            installable="${{@:$((i+2)):1}}"
-            mkdir -p "$(dirname "$link_path")"
-            ln -sf "$installable" "$link_path"
-            break
-        fi
-    done
-elif [[ "$@" == "--extra-experimental-features nix-command flakes flake archive --impure --json --no-write-lock-file "* ]]; then
-    echo '{{ "inputs": {{ "nixpkgs": {{ "inputs": {{}}, "path": "{nixpkgs_path}" }} }} }}'
-fi
-
-exit 0
-"#,
-        log_file = log_file.display()
+    let nix_file = write_fake_nix(
+        work_dir.path(),
+        FakeNix {
+            flake_archive_json: &format!(
+                r#"{{ "inputs": {{ "nixpkgs": {{ "inputs": {{}}, "path": "{nixpkgs_path}" }} }} }}"#
+            ),
+            log_file: Some(&log_file),
+            ..FakeNix::default()
+        },
     );
-    fs::write(&nix_file, nix_file_content).unwrap();
-    fs::set_permissions(&nix_file, fs::Permissions::from_mode(0o755)).unwrap();
 
-    let original_path = env::var("PATH").unwrap_or_default();
-    let new_path = format!("{}:{}", bin_dir.display(), original_path);
-    unsafe {
-        env::set_var("PATH", &new_path);
-    }
-
-    let nix_profile_cache = NixProfileCache::new(
+    let nix_profile_cache = NixProfileCache::with_options(
         PathBuf::from(cache_dir.path()),
         &format!("path:{}", flake_dir.path().to_string_lossy()),
         nix_dev_env::EvaluationMode::Impure,
+        NixProfileCacheOptions {
+            nix_program: Some(nix_file.into_os_string()),
+            ..NixProfileCacheOptions::default()
+        },
     )
     .unwrap();
 
@@ -111,7 +189,9 @@ exit 0
         [
             flake_inputs_path.clone(),
             profile_symlink.clone(),
+            profile_nix_version.clone(),
             profile_rc.clone(),
+            profile_symlink.with_extension("rc.sha1"),
         ]
     );
     assert_eq!(
@@ -127,28 +207,904 @@ exit 0
     assert_eq!(
         log_lines,
         [
+            String::from("--version"),
             format!(
-                "--extra-experimental-features nix-command flakes print-dev-env --impure --no-write-lock-file --profile {tmp_profile} {flake_dir}",
+                "print-dev-env --impure --no-write-lock-file --profile {tmp_profile} {flake_dir}",
                 tmp_profile = tmp_profile.to_string_lossy(),
                 flake_dir = flake_dir.path().to_string_lossy()
             ),
             format!(
-                "--extra-experimental-features nix-command flakes build --impure --out-link {profile_symlink} {tmp_profile}",
+                "build --impure --out-link {profile_symlink} {tmp_profile}",
                 profile_symlink = profile_symlink.to_string_lossy(),
                 tmp_profile = tmp_profile.to_string_lossy()
             ),
             format!(
-                "--extra-experimental-features nix-command flakes flake archive --impure --json --no-write-lock-file {flake_dir}",
+                "flake archive --impure --json --no-write-lock-file {flake_dir}",
                 flake_dir = flake_dir.path().to_string_lossy()
             ),
             format!(
-                "--extra-experimental-features nix-command flakes build --impure --out-link {flake_inputs_symlink} {nixpkgs_path}",
+                "build --impure --out-link {flake_inputs_symlink} {nixpkgs_path}",
                 flake_inputs_symlink = flake_inputs_path.join(nixpkgs_dir_name).to_string_lossy()
             ),
+            String::from("--version"),
         ]
     );
+}
 
-    unsafe {
-        env::set_var("PATH", original_path);
-    }
+#[test]
+fn test_nix_profile_cache_update_reports_nix_failure() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let nix_file = write_fake_nix(
+        work_dir.path(),
+        FakeNix {
+            print_dev_env: "echo 'error: flake does not provide a devShell' >&2; exit 1",
+            ..FakeNix::default()
+        },
+    );
+
+    let nix_profile_cache = NixProfileCache::with_options(
+        PathBuf::from(cache_dir.path()),
+        "github:owner/repo",
+        nix_dev_env::EvaluationMode::Pure,
+        NixProfileCacheOptions {
+            nix_program: Some(nix_file.clone().into_os_string()),
+            ..NixProfileCacheOptions::default()
+        },
+    )
+    .unwrap();
+
+    let error = nix_profile_cache.update().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "`{} --extra-experimental-features nix-command' flakes' print-dev-env --no-write-lock-file --profile {} github':owner/repo'` failed with error:\nprocess exited unsuccessfully: exit status: 1",
+            nix_file.display(),
+            cache_dir
+                .path()
+                .join(format!("flake-tmp-profile.{}", process::id()))
+                .display()
+        )
+    );
+    assert!(nix_profile_cache.needs_update().unwrap());
+}
+
+#[test]
+fn test_nix_profile_cache_needs_update_after_nix_version_bump() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let nix_file = write_fake_nix(work_dir.path(), FakeNix::default());
+
+    let nix_profile_cache = NixProfileCache::with_options(
+        PathBuf::from(cache_dir.path()),
+        "github:owner/repo",
+        nix_dev_env::EvaluationMode::Pure,
+        NixProfileCacheOptions {
+            nix_program: Some(nix_file.into_os_string()),
+            ..NixProfileCacheOptions::default()
+        },
+    )
+    .unwrap();
+
+    assert!(nix_profile_cache.needs_update().unwrap());
+    nix_profile_cache.update().unwrap();
+    assert!(!nix_profile_cache.needs_update().unwrap());
+
+    write_fake_nix(
+        work_dir.path(),
+        FakeNix {
+            version: "nix (Nix) 2.31.0",
+            ..FakeNix::default()
+        },
+    );
+    assert_eq!(
+        nix_profile_cache.rebuild_reason().unwrap(),
+        Some(RebuildReason::NixVersionChanged)
+    );
+    nix_profile_cache.update().unwrap();
+    assert!(!nix_profile_cache.needs_update().unwrap());
+}
+
+#[test]
+fn test_nix_profile_cache_update_errors_early_on_unwritable_cache_dir() {
+    let work_dir = tempdir().unwrap();
+    // NB: A directory can't be created under a file, which stays unwritable even when running as
+    // root, unlike permission bits
+    let not_a_dir = work_dir.path().join("not-a-dir");
+    fs::write(&not_a_dir, "").unwrap();
+    let cache_dir = not_a_dir.join("cache");
+    let log_file = work_dir.path().join("nix_commands.log");
+    let nix_file = write_fake_nix(
+        work_dir.path(),
+        FakeNix {
+            log_file: Some(&log_file),
+            ..FakeNix::default()
+        },
+    );
+
+    let nix_profile_cache = NixProfileCache::with_options(
+        cache_dir.clone(),
+        "github:owner/repo",
+        nix_dev_env::EvaluationMode::Pure,
+        NixProfileCacheOptions {
+            nix_program: Some(nix_file.into_os_string()),
+            ..NixProfileCacheOptions::default()
+        },
+    )
+    .unwrap();
+
+    let error = nix_profile_cache.update().unwrap_err().to_string();
+    assert!(error.starts_with(&format!(
+        "Cache directory {} isn't writable: ",
+        cache_dir.display()
+    )));
+    assert!(!log_file.exists());
+}
+
+#[test]
+fn test_nix_profile_cache_needs_update_after_cache_ttl() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let nix_file = write_fake_nix(work_dir.path(), FakeNix::default());
+
+    let nix_profile_cache = |cache_ttl| {
+        NixProfileCache::with_options(
+            PathBuf::from(cache_dir.path()),
+            "github:owner/repo",
+            nix_dev_env::EvaluationMode::Pure,
+            NixProfileCacheOptions {
+                nix_program: Some(nix_file.clone().into_os_string()),
+                cache_ttl,
+                ..NixProfileCacheOptions::default()
+            },
+        )
+        .unwrap()
+    };
+    let cache_ttl = Some(Duration::from_secs(60 * 60));
+
+    nix_profile_cache(cache_ttl).update().unwrap();
+    assert!(!nix_profile_cache(cache_ttl).needs_update().unwrap());
+
+    fs::File::options()
+        .write(true)
+        .open(
+            nix_profile_cache(cache_ttl)
+                .profile_path()
+                .with_extension("nix-version"),
+        )
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(2 * 60 * 60))
+        .unwrap();
+    assert!(!nix_profile_cache(None).needs_update().unwrap());
+    assert_eq!(
+        nix_profile_cache(cache_ttl).rebuild_reason().unwrap(),
+        Some(RebuildReason::CacheTtlExpired)
+    );
+}
+
+#[test]
+fn test_nix_profile_cache_rebuild_reason_after_watched_file_change() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let flake_dir = tempdir_in(work_dir.path()).unwrap();
+    let flake_file = flake_dir.path().join("flake.nix");
+    fs::write(&flake_file, "{}").unwrap();
+    let nix_file = write_fake_nix(work_dir.path(), FakeNix::default());
+
+    let nix_profile_cache = NixProfileCache::with_options(
+        PathBuf::from(cache_dir.path()),
+        &format!("path:{}", flake_dir.path().display()),
+        nix_dev_env::EvaluationMode::Pure,
+        NixProfileCacheOptions {
+            nix_program: Some(nix_file.into_os_string()),
+            no_input_gcroots: true,
+            ..NixProfileCacheOptions::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(
+        nix_profile_cache.rebuild_reason().unwrap(),
+        Some(RebuildReason::ProfileMissing)
+    );
+    nix_profile_cache.update().unwrap();
+    assert_eq!(nix_profile_cache.rebuild_reason().unwrap(), None);
+
+    // NB: Only the mtime changes, so the profile hash (based on contents) stays the same
+    fs::File::options()
+        .write(true)
+        .open(&flake_file)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60 * 60))
+        .unwrap();
+    assert_eq!(
+        nix_profile_cache.rebuild_reason().unwrap(),
+        Some(RebuildReason::FileChanged(flake_file))
+    );
+}
+
+#[test]
+fn test_nix_profile_cache_compressed_rc_round_trip() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let nix_file = write_fake_nix(work_dir.path(), FakeNix::default());
+
+    let nix_profile_cache = |compress_rc| {
+        NixProfileCache::with_options(
+            PathBuf::from(cache_dir.path()),
+            "github:owner/repo",
+            nix_dev_env::EvaluationMode::Pure,
+            NixProfileCacheOptions {
+                nix_program: Some(nix_file.clone().into_os_string()),
+                compress_rc,
+                ..NixProfileCacheOptions::default()
+            },
+        )
+        .unwrap()
+    };
+
+    let compressed = nix_profile_cache(true);
+    compressed.update().unwrap();
+    assert!(compressed.profile_rc_is_compressed());
+    assert!(
+        compressed
+            .profile_rc()
+            .to_string_lossy()
+            .ends_with(".rc.zst")
+    );
+    assert_ne!(
+        fs::read(compressed.profile_rc()).unwrap(),
+        b"export FAKE_VAR=true;\n"
+    );
+    assert_eq!(
+        compressed.read_profile_rc().unwrap(),
+        b"export FAKE_VAR=true;\n"
+    );
+    assert_eq!(
+        compressed
+            .profile_rc_env_vars()
+            .unwrap()
+            .get("FAKE_VAR")
+            .map(String::as_str),
+        Some("true")
+    );
+
+    // A profile cached in the other format is still used until it needs an update
+    let uncompressed = nix_profile_cache(false);
+    assert_eq!(uncompressed.rebuild_reason().unwrap(), None);
+    assert!(uncompressed.profile_rc_is_compressed());
+    assert_eq!(
+        uncompressed.read_profile_rc().unwrap(),
+        b"export FAKE_VAR=true;\n"
+    );
+
+    uncompressed.update().unwrap();
+    assert!(!uncompressed.profile_rc_is_compressed());
+    assert!(uncompressed.profile_rc().to_string_lossy().ends_with(".rc"));
+    assert_eq!(
+        uncompressed.read_profile_rc().unwrap(),
+        b"export FAKE_VAR=true;\n"
+    );
+    assert!(!compressed.profile_rc_is_compressed());
+}
+
+#[test]
+fn test_nix_profile_cache_excluded_env_vars_are_stripped_from_rc() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let nix_file = write_fake_nix(
+        work_dir.path(),
+        FakeNix {
+            print_dev_env: r#"printf '%s\n' "FAKE_VAR='true'" "export FAKE_VAR" \
+        "TMPDIR='/tmp/nix-shell'" "export TMPDIR" "declare -x TERM=dumb""#,
+            ..FakeNix::default()
+        },
+    );
+
+    let nix_profile_cache = |excluded_env_vars| {
+        NixProfileCache::with_options(
+            PathBuf::from(cache_dir.path()),
+            "github:owner/repo",
+            nix_dev_env::EvaluationMode::Pure,
+            NixProfileCacheOptions {
+                nix_program: Some(nix_file.clone().into_os_string()),
+                excluded_env_vars,
+                ..NixProfileCacheOptions::default()
+            },
+        )
+        .unwrap()
+    };
+
+    let excluding = nix_profile_cache(vec![String::from("TMPDIR"), String::from("TERM")]);
+    excluding.update().unwrap();
+    assert_eq!(
+        fs::read_to_string(excluding.profile_rc()).unwrap(),
+        "FAKE_VAR='true'\nexport FAKE_VAR\n"
+    );
+
+    // NB: Excluding env vars changes the rc, so it's cached separately
+    let not_excluding = nix_profile_cache(vec![]);
+    assert_ne!(not_excluding.profile_rc(), excluding.profile_rc());
+    not_excluding.update().unwrap();
+    assert_eq!(
+        not_excluding
+            .profile_rc_env_vars()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>(),
+        ["FAKE_VAR", "TMPDIR", "TERM"]
+    );
+}
+
+#[test]
+fn test_nix_profile_cache_merge_path_keeps_existing_path_in_rc() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let nix_file = write_fake_nix(
+        work_dir.path(),
+        FakeNix {
+            print_dev_env: r#"printf '%s\n' "PATH='/nix/store/aaa-tool/bin:/usr/bin'" "export PATH""#,
+            ..FakeNix::default()
+        },
+    );
+
+    let nix_profile_cache = |merge_path: Option<&str>| {
+        NixProfileCache::with_options(
+            PathBuf::from(cache_dir.path()),
+            "github:owner/repo",
+            nix_dev_env::EvaluationMode::Pure,
+            NixProfileCacheOptions {
+                nix_program: Some(nix_file.clone().into_os_string()),
+                merge_path: merge_path.map(String::from),
+                ..NixProfileCacheOptions::default()
+            },
+        )
+        .unwrap()
+    };
+
+    let merging = nix_profile_cache(Some("/usr/bin:/home/user/bin"));
+    merging.update().unwrap();
+    assert_eq!(
+        merging
+            .profile_rc_env_vars()
+            .unwrap()
+            .get("PATH")
+            .map(String::as_str),
+        Some("/nix/store/aaa-tool/bin:/usr/bin:/home/user/bin")
+    );
+
+    // NB: The merged PATH is baked into the rc, so it's cached separately
+    let not_merging = nix_profile_cache(None);
+    assert_ne!(not_merging.profile_rc(), merging.profile_rc());
+    not_merging.update().unwrap();
+    assert_eq!(
+        not_merging
+            .profile_rc_env_vars()
+            .unwrap()
+            .get("PATH")
+            .map(String::as_str),
+        Some("/nix/store/aaa-tool/bin:/usr/bin")
+    );
+}
+
+#[test]
+fn test_nix_profile_cache_identical_update_keeps_rc_mtime() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let nix_file = write_fake_nix(work_dir.path(), FakeNix::default());
+
+    let nix_profile_cache = NixProfileCache::with_options(
+        PathBuf::from(cache_dir.path()),
+        "github:owner/repo",
+        nix_dev_env::EvaluationMode::Pure,
+        NixProfileCacheOptions {
+            nix_program: Some(nix_file.into_os_string()),
+            ..NixProfileCacheOptions::default()
+        },
+    )
+    .unwrap();
+
+    nix_profile_cache.update().unwrap();
+    let profile_rc_mtime = SystemTime::now() - Duration::from_secs(60 * 60);
+    fs::File::options()
+        .write(true)
+        .open(nix_profile_cache.profile_rc())
+        .unwrap()
+        .set_modified(profile_rc_mtime)
+        .unwrap();
+
+    nix_profile_cache.update().unwrap();
+    assert_eq!(
+        fs::metadata(nix_profile_cache.profile_rc())
+            .unwrap()
+            .modified()
+            .unwrap(),
+        profile_rc_mtime
+    );
+    assert!(nix_profile_cache.profile_path().is_symlink());
+    assert_eq!(nix_profile_cache.rebuild_reason().unwrap(), None);
+}
+
+#[test]
+fn test_nix_profile_cache_input_gcroots_in_configured_dir() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let gcroots_dir = work_dir.path().join("gcroots");
+    let flake_dir = tempdir_in(work_dir.path()).unwrap();
+    fs::write(flake_dir.path().join("flake.nix"), "{}").unwrap();
+    let nixpkgs_dir_name = "yfzmnk75f009yb7b542kf4r7qaqq9kid-source";
+    let nix_file = write_fake_nix(
+        work_dir.path(),
+        FakeNix {
+            flake_archive_json: &format!(
+                r#"{{ "inputs": {{ "nixpkgs": {{ "inputs": {{}}, "path": "/nix/store/{nixpkgs_dir_name}" }} }} }}"#
+            ),
+            ..FakeNix::default()
+        },
+    );
+
+    let nix_profile_cache = NixProfileCache::with_options(
+        PathBuf::from(cache_dir.path()),
+        &format!("path:{}", flake_dir.path().to_string_lossy()),
+        nix_dev_env::EvaluationMode::Pure,
+        NixProfileCacheOptions {
+            nix_program: Some(nix_file.into_os_string()),
+            input_gcroots_dir: InputGcrootsDir::Dir(gcroots_dir.clone()),
+            ..NixProfileCacheOptions::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(nix_profile_cache.update().unwrap().inputs_rooted, Some(1));
+
+    let flake_inputs_dir = gcroots_dir.join(format!(
+        "flake-inputs-{}",
+        cache_dir.path().file_name().unwrap().to_string_lossy()
+    ));
+    assert_eq!(
+        fs::read_link(flake_inputs_dir.join(nixpkgs_dir_name)).unwrap(),
+        PathBuf::from(format!("/nix/store/{nixpkgs_dir_name}"))
+    );
+    assert!(!cache_dir.path().join("flake-inputs").exists());
+}
+
+#[test]
+fn test_nix_profile_cache_ignored_watched_file_change_doesnt_need_update() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let flake_dir = tempdir_in(work_dir.path()).unwrap();
+    fs::write(flake_dir.path().join("flake.nix"), "{}").unwrap();
+    let devshell_file = flake_dir.path().join("devshell.toml");
+    fs::write(&devshell_file, "[devshell]").unwrap();
+    fs::write(
+        flake_dir.path().join(".envoluntaryignore"),
+        "devshell.toml\n",
+    )
+    .unwrap();
+    let nix_file = write_fake_nix(work_dir.path(), FakeNix::default());
+
+    let nix_profile_cache = || {
+        NixProfileCache::with_options(
+            PathBuf::from(cache_dir.path()),
+            &format!("path:{}", flake_dir.path().display()),
+            nix_dev_env::EvaluationMode::Pure,
+            NixProfileCacheOptions {
+                nix_program: Some(nix_file.clone().into_os_string()),
+                no_input_gcroots: true,
+                ..NixProfileCacheOptions::default()
+            },
+        )
+        .unwrap()
+    };
+
+    let before_change = nix_profile_cache();
+    before_change.update().unwrap();
+    assert_eq!(before_change.rebuild_reason().unwrap(), None);
+
+    let mut devshell = fs::File::options()
+        .append(true)
+        .open(&devshell_file)
+        .unwrap();
+    devshell.write_all(b"\nname = \"changed\"").unwrap();
+    devshell
+        .set_modified(SystemTime::now() + Duration::from_secs(60 * 60))
+        .unwrap();
+
+    let after_change = nix_profile_cache();
+    assert_eq!(after_change.profile_path(), before_change.profile_path());
+    assert_eq!(after_change.rebuild_reason().unwrap(), None);
+}
+
+#[test]
+fn test_nix_profile_cache_verify_gcroots_rebuilds_missing_store_path() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    // NB: The gcroot points into the Nix store, where the fake profile never actually exists
+    let nix_file = write_fake_nix(work_dir.path(), FakeNix::default());
+
+    let nix_profile_cache = |verify_gcroots| {
+        NixProfileCache::with_options(
+            PathBuf::from(cache_dir.path()),
+            "github:owner/repo",
+            nix_dev_env::EvaluationMode::Pure,
+            NixProfileCacheOptions {
+                nix_program: Some(nix_file.clone().into_os_string()),
+                verify_gcroots,
+                ..NixProfileCacheOptions::default()
+            },
+        )
+        .unwrap()
+    };
+
+    nix_profile_cache(false).update().unwrap();
+    assert!(!nix_profile_cache(false).profile_path().exists());
+    assert_eq!(nix_profile_cache(false).rebuild_reason().unwrap(), None);
+    assert_eq!(
+        nix_profile_cache(true).rebuild_reason().unwrap(),
+        Some(RebuildReason::ProfileStorePathMissing)
+    );
+}
+
+#[test]
+fn test_nix_profile_cache_rebuilds_corrupted_rc() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let nix_file = write_fake_nix(work_dir.path(), FakeNix::default());
+
+    let nix_profile_cache = NixProfileCache::with_options(
+        PathBuf::from(cache_dir.path()),
+        "github:owner/repo",
+        nix_dev_env::EvaluationMode::Pure,
+        NixProfileCacheOptions {
+            nix_program: Some(nix_file.into_os_string()),
+            ..NixProfileCacheOptions::default()
+        },
+    )
+    .unwrap();
+
+    nix_profile_cache.update().unwrap();
+    assert_eq!(nix_profile_cache.rebuild_reason().unwrap(), None);
+
+    // A truncated write
+    fs::write(nix_profile_cache.profile_rc(), "export FAKE_V").unwrap();
+    assert_eq!(
+        nix_profile_cache.rebuild_reason().unwrap(),
+        Some(RebuildReason::ProfileRcCorrupted)
+    );
+
+    nix_profile_cache.update().unwrap();
+    assert_eq!(nix_profile_cache.rebuild_reason().unwrap(), None);
+    assert_eq!(
+        fs::read_to_string(nix_profile_cache.profile_rc()).unwrap(),
+        "export FAKE_VAR=true;\n"
+    );
+}
+
+#[test]
+fn test_nix_profile_cache_signals_ready_after_update() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let ready_marker = work_dir.path().join("ready");
+    let ready_command_output = work_dir.path().join("ready-command-output");
+    let nix_file = write_fake_nix(work_dir.path(), FakeNix::default());
+
+    let nix_profile_cache = NixProfileCache::with_options(
+        PathBuf::from(cache_dir.path()),
+        "github:owner/repo",
+        nix_dev_env::EvaluationMode::Pure,
+        NixProfileCacheOptions {
+            nix_program: Some(nix_file.into_os_string()),
+            ready_marker: Some(ready_marker.clone()),
+            ready_command: Some(format!(
+                "echo \"$1\" > '{}'",
+                ready_command_output.display()
+            )),
+            ..NixProfileCacheOptions::default()
+        },
+    )
+    .unwrap();
+
+    assert!(!ready_marker.exists());
+    let update_summary = nix_profile_cache.update().unwrap();
+    assert_eq!(
+        fs::read_to_string(&ready_marker).unwrap(),
+        update_summary.hash
+    );
+    assert_eq!(
+        fs::read_to_string(&ready_command_output).unwrap(),
+        format!("{}\n", update_summary.hash)
+    );
+}
+
+#[test]
+fn test_nix_profile_cache_local_store_fallback_after_daemon_failure() {
+    static WARNINGS: Mutex<Vec<UpdateWarning>> = Mutex::new(Vec::new());
+
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let nix_file = write_fake_nix(
+        work_dir.path(),
+        FakeNix {
+            print_dev_env: r#"if [[ "$store" != "local" ]]; then
+        echo "error: cannot connect to socket at '/nix/var/nix/daemon-socket/socket': Connection refused" >&2
+        exit 1
+    fi
+    echo "export FAKE_VAR=true;""#,
+            ..FakeNix::default()
+        },
+    );
+
+    let nix_profile_cache = |local_store_fallback| {
+        NixProfileCache::with_options(
+            PathBuf::from(cache_dir.path()),
+            "github:owner/repo",
+            nix_dev_env::EvaluationMode::Pure,
+            NixProfileCacheOptions {
+                nix_program: Some(nix_file.clone().into_os_string()),
+                local_store_fallback,
+                on_update_warning: Some(|update_warning| {
+                    WARNINGS.lock().unwrap().push(update_warning.clone())
+                }),
+                ..NixProfileCacheOptions::default()
+            },
+        )
+        .unwrap()
+    };
+
+    let err = nix_profile_cache(false).update().unwrap_err();
+    assert!(
+        err.downcast_ref::<NixCommandError>()
+            .and_then(NixCommandError::stderr)
+            .unwrap()
+            .contains("cannot connect to socket")
+    );
+    assert!(WARNINGS.lock().unwrap().is_empty());
+
+    let nix_profile_cache = nix_profile_cache(true);
+    nix_profile_cache.update().unwrap();
+    assert_eq!(
+        fs::read_to_string(nix_profile_cache.profile_rc()).unwrap(),
+        "export FAKE_VAR=true;\n"
+    );
+    assert_eq!(
+        *WARNINGS.lock().unwrap(),
+        [UpdateWarning::LocalStoreFallback]
+    );
+}
+
+#[test]
+fn test_nix_profile_cache_aliases_unchanged_profile_after_lock_bump() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let dev_env_file = work_dir.path().join("dev-env");
+    fs::write(&dev_env_file, "one").unwrap();
+    let flake_dir = tempdir_in(work_dir.path()).unwrap();
+    fs::write(flake_dir.path().join("flake.nix"), "{}").unwrap();
+    let flake_lock_file = flake_dir.path().join("flake.lock");
+    fs::write(&flake_lock_file, "{\"version\": 1}").unwrap();
+    let print_dev_env = format!(
+        r#"echo "export FAKE_VAR=$(cat {dev_env_file});""#,
+        dev_env_file = dev_env_file.display()
+    );
+    let nix_file = write_fake_nix(
+        work_dir.path(),
+        FakeNix {
+            print_dev_env: &print_dev_env,
+            ..FakeNix::default()
+        },
+    );
+
+    let nix_profile_cache = || {
+        NixProfileCache::with_options(
+            PathBuf::from(cache_dir.path()),
+            &format!("path:{}", flake_dir.path().display()),
+            nix_dev_env::EvaluationMode::Pure,
+            NixProfileCacheOptions {
+                nix_program: Some(nix_file.clone().into_os_string()),
+                no_input_gcroots: true,
+                alias_unchanged_profiles: true,
+                ..NixProfileCacheOptions::default()
+            },
+        )
+        .unwrap()
+    };
+
+    let original = nix_profile_cache();
+    let original_summary = original.update().unwrap();
+    assert_eq!(original_summary.aliased_to, None);
+
+    fs::write(&flake_lock_file, "{\"version\": 2}").unwrap();
+    let lock_bumped = nix_profile_cache();
+    assert_ne!(lock_bumped.profile_path(), original.profile_path());
+    let lock_bumped_summary = lock_bumped.update().unwrap();
+    assert_eq!(
+        lock_bumped_summary.aliased_to.as_ref(),
+        Some(&original_summary.hash)
+    );
+    assert_eq!(
+        fs::read_link(lock_bumped.profile_path()).unwrap(),
+        original.profile_path().file_name().unwrap()
+    );
+    assert!(original.profile_path().is_symlink());
+    assert_eq!(lock_bumped.rebuild_reason().unwrap(), None);
+    assert_eq!(
+        fs::read_to_string(lock_bumped.profile_rc()).unwrap(),
+        "export FAKE_VAR=one;\n"
+    );
+
+    // NB: An alias of an alias points at the original gcroot
+    fs::write(&flake_lock_file, "{\"version\": 3}").unwrap();
+    let lock_bumped_again = nix_profile_cache();
+    lock_bumped_again.update().unwrap();
+    assert_eq!(
+        fs::read_link(lock_bumped_again.profile_path()).unwrap(),
+        original.profile_path().file_name().unwrap()
+    );
+
+    fs::write(&dev_env_file, "two").unwrap();
+    fs::write(&flake_lock_file, "{\"version\": 4}").unwrap();
+    let changed = nix_profile_cache();
+    assert_eq!(changed.update().unwrap().aliased_to, None);
+    assert!(
+        fs::read_link(changed.profile_path())
+            .unwrap()
+            .starts_with("/nix/store/")
+    );
+    assert!(!original.profile_path().is_symlink());
+    assert!(!lock_bumped.profile_path().is_symlink());
+}
+
+#[test]
+fn test_nix_profile_cache_skips_input_gcroots_when_flake_archive_is_unsupported() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let flake_dir = tempdir_in(work_dir.path()).unwrap();
+    fs::write(flake_dir.path().join("flake.nix"), "{}").unwrap();
+    let archive_error_file = work_dir.path().join("archive-error");
+    let on_flake_archive = format!(
+        "cat {archive_error_file} >&2; exit 1",
+        archive_error_file = archive_error_file.display()
+    );
+    let nix_file = write_fake_nix(
+        work_dir.path(),
+        FakeNix {
+            on_flake_archive: &on_flake_archive,
+            ..FakeNix::default()
+        },
+    );
+
+    let nix_profile_cache = NixProfileCache::with_options(
+        PathBuf::from(cache_dir.path()),
+        &format!("path:{}", flake_dir.path().display()),
+        nix_dev_env::EvaluationMode::Pure,
+        NixProfileCacheOptions {
+            nix_program: Some(nix_file.into_os_string()),
+            ..NixProfileCacheOptions::default()
+        },
+    )
+    .unwrap();
+
+    fs::write(
+        &archive_error_file,
+        "error: 'archive' is not a recognised command",
+    )
+    .unwrap();
+    let update_summary = nix_profile_cache.update().unwrap();
+    assert_eq!(update_summary.inputs_rooted, None);
+    assert!(
+        update_summary
+            .to_string()
+            .contains("without flake input gcroots")
+    );
+    assert!(nix_profile_cache.profile_path().is_symlink());
+    assert_eq!(nix_profile_cache.rebuild_reason().unwrap(), None);
+
+    fs::write(
+        &archive_error_file,
+        "error: unable to download 'https://example.com': Couldn't resolve host name",
+    )
+    .unwrap();
+    let err = nix_profile_cache.update().unwrap_err();
+    assert!(err.downcast_ref::<NixCommandError>().is_some());
+}
+
+#[test]
+fn test_nix_profile_cache_roots_at_most_max_input_gcroots() {
+    static WARNINGS: Mutex<Vec<UpdateWarning>> = Mutex::new(Vec::new());
+
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let flake_dir = tempdir_in(work_dir.path()).unwrap();
+    fs::write(flake_dir.path().join("flake.nix"), "{}").unwrap();
+    let nix_file = write_fake_nix(
+        work_dir.path(),
+        FakeNix {
+            flake_archive_json: r#"{ "inputs": {
+        "c": { "inputs": {}, "path": "/nix/store/ccc-source" },
+        "a": { "inputs": {}, "path": "/nix/store/aaa-source" },
+        "b": { "inputs": {}, "path": "/nix/store/bbb-source" }
+    } }"#,
+            ..FakeNix::default()
+        },
+    );
+
+    let nix_profile_cache = NixProfileCache::with_options(
+        PathBuf::from(cache_dir.path()),
+        &format!("path:{}", flake_dir.path().display()),
+        nix_dev_env::EvaluationMode::Pure,
+        NixProfileCacheOptions {
+            nix_program: Some(nix_file.into_os_string()),
+            max_input_gcroots: Some(2),
+            on_update_warning: Some(|update_warning| {
+                WARNINGS.lock().unwrap().push(update_warning.clone())
+            }),
+            ..NixProfileCacheOptions::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(nix_profile_cache.update().unwrap().inputs_rooted, Some(2));
+
+    let mut rooted_inputs = fs::read_dir(cache_dir.path().join("flake-inputs"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    rooted_inputs.sort();
+    assert_eq!(rooted_inputs, ["aaa-source", "bbb-source"]);
+    assert_eq!(
+        *WARNINGS.lock().unwrap(),
+        [UpdateWarning::InputGcrootsCapped {
+            max_input_gcroots: 2,
+            total: 3
+        }]
+    );
+}
+
+#[test]
+fn test_nix_profile_cache_passes_print_dev_env_args_before_flake_reference() {
+    let work_dir = tempdir().unwrap();
+    let cache_dir = tempdir_in(work_dir.path()).unwrap();
+    let args_file = work_dir.path().join("print-dev-env-args");
+    // NB: Skips the temporary profile path
+    let print_dev_env = format!(
+        r#"echo "${{@:3:3}} ${{@:7}}" > "{}"; echo "export FAKE_VAR=true;""#,
+        args_file.display()
+    );
+    let nix_file = write_fake_nix(
+        work_dir.path(),
+        FakeNix {
+            print_dev_env: &print_dev_env,
+            ..FakeNix::default()
+        },
+    );
+
+    let nix_profile_cache = |print_dev_env_args: &[&str]| {
+        NixProfileCache::with_options(
+            PathBuf::from(cache_dir.path()),
+            "github:owner/repo",
+            nix_dev_env::EvaluationMode::Pure,
+            NixProfileCacheOptions {
+                nix_program: Some(nix_file.clone().into_os_string()),
+                print_dev_env_args: print_dev_env_args.iter().map(Into::into).collect(),
+                ..NixProfileCacheOptions::default()
+            },
+        )
+        .unwrap()
+    };
+
+    let with_args = nix_profile_cache(&["--impure", "--override-input", "nixpkgs", "path:/tmp"]);
+    with_args.update().unwrap();
+    assert_eq!(
+        fs::read_to_string(&args_file).unwrap(),
+        "print-dev-env --no-write-lock-file --profile --impure --override-input nixpkgs path:/tmp \
+         github:owner/repo\n"
+    );
+
+    // NB: Extra args can change the dev env, so the profile is cached separately
+    let without_args = nix_profile_cache(&[]);
+    assert_ne!(without_args.profile_path(), with_args.profile_path());
+    without_args.update().unwrap();
+    assert_eq!(
+        fs::read_to_string(&args_file).unwrap(),
+        "print-dev-env --no-write-lock-file --profile github:owner/repo\n"
+    );
 }